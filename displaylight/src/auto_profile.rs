@@ -0,0 +1,268 @@
+//! Switching the profile automatically based on the detected content.
+//!
+//! Rules are evaluated in order, the first rule whose conditions match selects its profile. A rule
+//! can match on the aspect ratio of the detected borders, like a letterboxed movie, and on the
+//! resolution of the screen:
+//! ```yaml
+//! profile_rules:
+//!   - {profile: cinema, letterbox: {min_ratio: 2.2}, stable_for: 5.0}
+//!   - {profile: game, resolution: {width: 2560, height: 1440}}
+//! ```
+//! To keep it from flapping between profiles, a rule must match for `stable_for` seconds before
+//! its profile is switched to, and once active the ratio bounds are widened by `ratio_margin`. If
+//! no rule matches for `profile_rules_release` seconds the configured profile is used again.
+use crate::clock::{Clock, SystemClock};
+use crate::events::Event;
+use serde::{Deserialize, Serialize};
+
+use std::time::{Duration, Instant};
+
+fn default_stable_for() -> f32 {
+    5.0
+}
+
+fn default_ratio_margin() -> f32 {
+    0.05
+}
+
+/// Matches the aspect ratio (width / height) of the detected borders.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Copy, Clone)]
+pub struct LetterboxCondition {
+    /// The minimum aspect ratio, like 2.2 for movies in a 2.39:1 format.
+    #[serde(default)]
+    pub min_ratio: Option<f32>,
+
+    /// The maximum aspect ratio.
+    #[serde(default)]
+    pub max_ratio: Option<f32>,
+
+    /// Once the rule is active, the bounds are widened by this much.
+    #[serde(default = "default_ratio_margin")]
+    pub ratio_margin: f32,
+}
+
+impl LetterboxCondition {
+    fn matches(&self, ratio: f32, active: bool) -> bool {
+        let margin = if active { self.ratio_margin } else { 0.0 };
+        self.min_ratio.map_or(true, |min| ratio >= min - margin)
+            && self.max_ratio.map_or(true, |max| ratio <= max + margin)
+    }
+}
+
+/// Matches the resolution of the screen, unset fields match anything.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Copy, Clone)]
+pub struct ResolutionCondition {
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
+/// Selects a profile if all of its conditions match.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ProfileRule {
+    /// The profile to switch to.
+    pub profile: String,
+
+    /// Condition on the aspect ratio of the detected borders.
+    #[serde(default)]
+    pub letterbox: Option<LetterboxCondition>,
+
+    /// Condition on the resolution of the screen.
+    #[serde(default)]
+    pub resolution: Option<ResolutionCondition>,
+
+    /// Seconds the conditions must match before switching to the profile.
+    #[serde(default = "default_stable_for")]
+    pub stable_for: f32,
+}
+
+impl ProfileRule {
+    fn matches(&self, ratio: Option<f32>, resolution: Option<(u32, u32)>, active: bool) -> bool {
+        let letterbox = match (self.letterbox.as_ref(), ratio) {
+            (None, _) => true,
+            (Some(c), Some(ratio)) => c.matches(ratio, active),
+            (Some(_), None) => false,
+        };
+        let resolution = match (self.resolution.as_ref(), resolution) {
+            (None, _) => true,
+            (Some(c), Some((width, height))) => {
+                c.width.map_or(true, |w| w == width) && c.height.map_or(true, |h| h == height)
+            }
+            (Some(_), None) => false,
+        };
+        letterbox && resolution
+    }
+}
+
+/// Tracks the detected content and decides when to switch profiles.
+pub struct AutoProfile {
+    rules: Vec<ProfileRule>,
+    default: Option<String>,
+    release: Duration,
+    clock: Box<dyn Clock>,
+
+    ratio: Option<f32>,
+    resolution: Option<(u32, u32)>,
+
+    /// The rule that matches now and since when, None if no rule matches.
+    candidate: Option<usize>,
+    candidate_since: Instant,
+    /// The rule whose profile is active, None if the default profile is.
+    active: Option<usize>,
+}
+
+impl AutoProfile {
+    /// Create the tracker for these rules, the default profile is used if no rule matches for the
+    /// release duration.
+    pub fn new(rules: &[ProfileRule], default: Option<&str>, release: Duration) -> Self {
+        AutoProfile::with_clock(rules, default, release, Box::new(SystemClock))
+    }
+
+    /// Create the tracker using the provided clock.
+    pub fn with_clock(
+        rules: &[ProfileRule],
+        default: Option<&str>,
+        release: Duration,
+        clock: Box<dyn Clock>,
+    ) -> Self {
+        AutoProfile {
+            rules: rules.to_vec(),
+            default: default.map(str::to_owned),
+            release,
+            candidate_since: clock.now(),
+            clock,
+            ratio: None,
+            resolution: None,
+            candidate: None,
+            active: None,
+        }
+    }
+
+    /// Use new rules, default profile and release duration. The tracked state is only reset if
+    /// they differ from the current ones.
+    pub fn configure(&mut self, rules: &[ProfileRule], default: Option<&str>, release: Duration) {
+        if self.rules == rules && self.default.as_deref() == default && self.release == release {
+            return;
+        }
+        self.rules = rules.to_vec();
+        self.default = default.map(str::to_owned);
+        self.release = release;
+        self.candidate = None;
+        self.candidate_since = self.clock.now();
+        self.active = None;
+    }
+
+    /// Update the detected content from an event, returns the profile to switch to if the rules
+    /// decide so. The outer None means no switch, the inner None means no profile.
+    pub fn observe(&mut self, event: &Event) -> Option<Option<String>> {
+        match event {
+            Event::BordersChanged(r) => {
+                let height = r.y_max.saturating_sub(r.y_min);
+                self.ratio =
+                    (height > 0).then(|| r.x_max.saturating_sub(r.x_min) as f32 / height as f32);
+            }
+            Event::ResolutionChanged { width, height, .. } => {
+                self.resolution = Some((*width, *height));
+            }
+            // Not an input, but arrives every frame, such that the time is checked regularly.
+            Event::FrameProcessed(_) => {}
+            _ => return None,
+        }
+        self.update()
+    }
+
+    fn update(&mut self) -> Option<Option<String>> {
+        let now = self.clock.now();
+        let candidate = self.rules.iter().enumerate().position(|(i, rule)| {
+            rule.matches(self.ratio, self.resolution, self.active == Some(i))
+        });
+        if candidate != self.candidate {
+            self.candidate = candidate;
+            self.candidate_since = now;
+        }
+        if self.candidate == self.active {
+            return None;
+        }
+
+        let hold = match self.candidate {
+            Some(i) => Duration::from_secs_f32(self.rules[i].stable_for.max(0.0)),
+            None => self.release,
+        };
+        if now.duration_since(self.candidate_since) < hold {
+            return None;
+        }
+        self.active = self.candidate;
+        Some(match self.active {
+            Some(i) => Some(self.rules[i].profile.clone()),
+            None => self.default.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::rectangle::Rectangle;
+
+    #[test]
+    fn test_auto_profile() {
+        let rules: Vec<ProfileRule> = serde_yaml::from_str(
+            "[{profile: cinema, letterbox: {min_ratio: 2.2}, stable_for: 5.0},
+              {profile: game, resolution: {width: 2560}, stable_for: 0.0}]",
+        )
+        .unwrap();
+        let clock = ManualClock::default();
+        let mut auto = AutoProfile::with_clock(
+            &rules,
+            Some("desktop"),
+            Duration::from_secs(3),
+            Box::new(clock.clone()),
+        );
+        let borders = |height: u32| {
+            let y_min = (1080 - height) / 2;
+            Event::BordersChanged(Rectangle {
+                x_min: 0,
+                x_max: 1920,
+                y_min,
+                y_max: y_min + height,
+            })
+        };
+        let frame = Event::FrameProcessed(&[]);
+        let step = |auto: &mut AutoProfile, secs: u64| {
+            clock.advance(Duration::from_secs(secs));
+            auto.observe(&frame)
+        };
+
+        // Full screen content matches no rule, the default is already active.
+        assert_eq!(auto.observe(&borders(1080)), None);
+
+        // Letterboxed at 2.4, switches after it was stable for 5 seconds.
+        assert_eq!(auto.observe(&borders(800)), None);
+        assert_eq!(step(&mut auto, 4), None);
+        assert_eq!(step(&mut auto, 1), Some(Some("cinema".to_owned())));
+
+        // Slightly below the ratio stays within the margin.
+        assert_eq!(auto.observe(&borders(876)), None);
+        assert_eq!(step(&mut auto, 10), None);
+
+        // Briefly full screen, like a bright scene, doesn't release it.
+        assert_eq!(auto.observe(&borders(1080)), None);
+        assert_eq!(step(&mut auto, 2), None);
+        assert_eq!(auto.observe(&borders(800)), None);
+        assert_eq!(step(&mut auto, 10), None);
+
+        // Full screen for longer returns to the default.
+        assert_eq!(auto.observe(&borders(1080)), None);
+        assert_eq!(step(&mut auto, 3), Some(Some("desktop".to_owned())));
+
+        // A matching resolution switches right away.
+        let resolution = Event::ResolutionChanged {
+            width: 2560,
+            height: 1440,
+            specification: Default::default(),
+        };
+        assert_eq!(auto.observe(&resolution), Some(Some("game".to_owned())));
+    }
+}
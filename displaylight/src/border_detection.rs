@@ -162,6 +162,12 @@ impl RectangleChangeLimiter {
         self.previous_time = *current;
     }
 
+    /// Restart the rate limiting from the provided time, keeping the current rectangle. Used after
+    /// a gap in time, such that it doesn't result in a large jump.
+    pub fn reset_time(&mut self, current: &std::time::Instant) {
+        self.previous_time = *current;
+    }
+
     /// Update the rectangle with rate limiting.
     pub fn update(&mut self, rectangle: &Rectangle, current: &std::time::Instant) {
         let dt = (*current - self.previous_time).as_secs_f32();
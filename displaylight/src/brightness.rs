@@ -0,0 +1,159 @@
+//! Adaptive brightness, scales the leds based on the average picture level of the sampled zones.
+//!
+//! Dark scenes result in a softer glow, bright scenes get the full output. The brightness factor
+//! is smoothed with separate time constants for increasing and decreasing brightness.
+use lights::RGB;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// Configuration for the adaptive brightness.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Copy, Clone)]
+pub struct AdaptiveBrightnessConfig {
+    /// The brightness factor used when the frame is completely black.
+    pub min: f32,
+
+    /// The brightness factor used when the frame is completely white.
+    pub max: f32,
+
+    /// Time constant in seconds used when the brightness increases.
+    pub rise_time_constant: f32,
+
+    /// Time constant in seconds used when the brightness decreases.
+    pub fall_time_constant: f32,
+}
+
+impl Default for AdaptiveBrightnessConfig {
+    fn default() -> Self {
+        AdaptiveBrightnessConfig {
+            min: 0.3,
+            max: 1.0,
+            rise_time_constant: 0.5,
+            fall_time_constant: 2.0,
+        }
+    }
+}
+
+/// Calculate the average picture level of the leds, this is the mean luma between 0.0 and 1.0.
+pub fn average_picture_level(leds: &[RGB]) -> f32 {
+    if leds.is_empty() {
+        return 0.0;
+    }
+    let total: f32 = leds
+        .iter()
+        .map(|c| 0.2126 * c.r as f32 + 0.7152 * c.g as f32 + 0.0722 * c.b as f32)
+        .sum();
+    total / (leds.len() as f32 * 255.0)
+}
+
+/// Struct to track the smoothed brightness factor derived from the average picture level.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveBrightness {
+    config: AdaptiveBrightnessConfig,
+    factor: f32,
+    previous_time: Option<Instant>,
+}
+
+impl AdaptiveBrightness {
+    /// Create a new adaptive brightness tracker, the first update sets the factor directly.
+    pub fn new(config: AdaptiveBrightnessConfig) -> Self {
+        AdaptiveBrightness {
+            config,
+            factor: config.max,
+            previous_time: None,
+        }
+    }
+
+    /// Update the brightness factor with the provided led values, returns the new factor.
+    pub fn update(&mut self, leds: &[RGB], current: &Instant) -> f32 {
+        let apl = average_picture_level(leds);
+        let target = self.config.min + (self.config.max - self.config.min) * apl;
+
+        if let Some(previous_time) = self.previous_time {
+            let dt = (*current - previous_time).as_secs_f32();
+            let tau = if target > self.factor {
+                self.config.rise_time_constant
+            } else {
+                self.config.fall_time_constant
+            };
+            // Exponential smoothing, a time constant of zero jumps to the target immediately.
+            let alpha = if tau > 0.0 {
+                1.0 - (-dt / tau).exp()
+            } else {
+                1.0
+            };
+            self.factor += (target - self.factor) * alpha;
+        } else {
+            self.factor = target;
+        }
+        self.previous_time = Some(*current);
+        self.factor
+    }
+
+    /// Forget the time of the previous update, such that the next update sets the factor directly.
+    pub fn reset(&mut self) {
+        self.previous_time = None;
+    }
+
+    /// Return the current brightness factor.
+    pub fn factor(&self) -> f32 {
+        self.factor
+    }
+
+    /// Scale the provided leds by the current brightness factor.
+    pub fn apply(&self, leds: &mut [RGB]) {
+        crate::color::scale(leds, self.factor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const WHITE: RGB = RGB {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+
+    #[test]
+    fn test_average_picture_level() {
+        assert_eq!(average_picture_level(&[]), 0.0);
+        assert_eq!(average_picture_level(&[RGB::default(); 10]), 0.0);
+        assert!((average_picture_level(&[WHITE; 10]) - 1.0).abs() < 0.001);
+        let half = [RGB::default(), WHITE];
+        assert!((average_picture_level(&half) - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_adaptive_brightness() {
+        let config = AdaptiveBrightnessConfig {
+            min: 0.2,
+            max: 1.0,
+            rise_time_constant: 1.0,
+            fall_time_constant: 2.0,
+        };
+        let mut z = AdaptiveBrightness::new(config);
+        let dark = [RGB::default(); 10];
+        let bright = [WHITE; 10];
+
+        // First update jumps directly to the target.
+        let t0 = Instant::now();
+        assert!((z.update(&dark, &t0) - 0.2).abs() < 0.001);
+
+        // After one rise time constant, we should be at 63% of the step.
+        let t1 = t0 + Duration::from_secs_f32(1.0);
+        let expected = 0.2 + 0.8 * (1.0 - (-1.0f32).exp());
+        assert!((z.update(&bright, &t1) - expected).abs() < 0.001);
+
+        // Going down uses the fall time constant.
+        let t2 = t1 + Duration::from_secs_f32(2.0);
+        let expected = expected + (0.2 - expected) * (1.0 - (-1.0f32).exp());
+        assert!((z.update(&dark, &t2) - expected).abs() < 0.001);
+
+        // Applying scales the channels.
+        let mut leds = [WHITE; 2];
+        z.apply(&mut leds);
+        assert_eq!(leds[0].r, (255.0 * z.factor()) as u8);
+    }
+}
@@ -0,0 +1,227 @@
+//! Matching the capture specifications against the screen, see [`CaptureSpecification`].
+//!
+//! The specifications are tried in order and the first one whose criteria all match is used. The
+//! criteria are an exact resolution, a range of resolutions, the aspect ratio and the display:
+//! ```yaml
+//! capture:
+//!   - {min_width: 3000, match_aspect_ratio: 3.556, region: {x: 0.5}} # Right half of two monitors.
+//!   - {match_display: 1, display: 1}
+//!   - {}
+//! ```
+//! The captured region is either given in pixels or, with `region`, as fractions of the
+//! resolution. [`explain`] returns why each specification did or did not match.
+use crate::CaptureSpecification;
+use serde::{Deserialize, Serialize};
+
+/// Relative difference allowed between the aspect ratio of the screen and `match_aspect_ratio`.
+pub const ASPECT_RATIO_TOLERANCE: f32 = 0.01;
+
+/// Region to capture as fractions of the resolution, between 0.0 and 1.0.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Copy, Clone)]
+pub struct Region {
+    /// The x offset as a fraction of the width.
+    #[serde(default)]
+    pub x: f32,
+    /// The y offset as a fraction of the height.
+    #[serde(default)]
+    pub y: f32,
+    /// The width as a fraction of the width, the remainder right of x if zero.
+    #[serde(default)]
+    pub width: f32,
+    /// The height as a fraction of the height, the remainder below y if zero.
+    #[serde(default)]
+    pub height: f32,
+}
+
+/// One of the regions placed side by side by a spanning source, see
+/// [`crate::shared_capture::SharedCaptureSource::spanning`].
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
+pub struct SpanPart {
+    /// The display whose resolution the specifications are matched against and resolved to, like
+    /// [`crate::Config::display`]. It is captured in full if no specification matches.
+    #[serde(default)]
+    pub display: u32,
+
+    /// The capture specifications of this region, the first one to match is used.
+    #[serde(default)]
+    pub capture: Vec<CaptureSpecification>,
+}
+
+/// The screen the specifications are matched against.
+#[derive(Debug, PartialEq, Eq, Default, Copy, Clone)]
+pub struct Screen {
+    pub width: u32,
+    pub height: u32,
+    /// The display the resolution belongs to.
+    pub display: u32,
+}
+
+/// The result of matching the specifications against a screen.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Match {
+    /// The index of the specification that matched, None if none did and the default is used.
+    pub index: Option<usize>,
+    /// The matched specification with the region resolved to pixels.
+    pub specification: CaptureSpecification,
+    /// For each specification that was tried, why it did or did not match.
+    pub reasons: Vec<String>,
+}
+
+impl std::fmt::Display for Match {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for reason in self.reasons.iter() {
+            writeln!(f, "{}", reason)?;
+        }
+        let s = &self.specification;
+        write!(
+            f,
+            "capturing display {} at x: {}, y: {}, width: {}, height: {}",
+            s.display, s.x, s.y, s.width, s.height
+        )
+    }
+}
+
+impl CaptureSpecification {
+    /// The reasons this specification doesn't match the screen, empty if it matches.
+    pub fn mismatches(&self, screen: &Screen) -> Vec<String> {
+        let mut reasons = vec![];
+        let axes = [
+            (
+                "width",
+                screen.width,
+                self.match_width,
+                self.min_width,
+                self.max_width,
+            ),
+            (
+                "height",
+                screen.height,
+                self.match_height,
+                self.min_height,
+                self.max_height,
+            ),
+        ];
+        for (name, value, exact, min, max) in axes {
+            if let Some(exact) = exact.filter(|v| *v != value) {
+                reasons.push(format!("{} {} is not {}", name, value, exact));
+            }
+            if let Some(min) = min.filter(|v| value < *v) {
+                reasons.push(format!("{} {} is below {}", name, value, min));
+            }
+            if let Some(max) = max.filter(|v| value > *v) {
+                reasons.push(format!("{} {} is above {}", name, value, max));
+            }
+        }
+        if let Some(ratio) = self.match_aspect_ratio {
+            let actual = screen.width as f32 / screen.height.max(1) as f32;
+            if (actual - ratio).abs() > ratio * ASPECT_RATIO_TOLERANCE {
+                reasons.push(format!("aspect ratio {:.3} is not {:.3}", actual, ratio));
+            }
+        }
+        if let Some(display) = self.match_display.filter(|v| *v != screen.display) {
+            reasons.push(format!("display {} is not {}", screen.display, display));
+        }
+        reasons
+    }
+
+    /// The specification with the region resolved to pixels of this screen and zero sizes filled
+    /// in, the criteria are kept as they are.
+    pub fn resolve(&self, screen: &Screen) -> CaptureSpecification {
+        let mut resolved = *self;
+        if let Some(region) = self.region {
+            let scale = |fraction: f32, size: u32| (fraction.clamp(0.0, 1.0) * size as f32) as u32;
+            resolved.x = scale(region.x, screen.width);
+            resolved.y = scale(region.y, screen.height);
+            resolved.width = scale(region.width, screen.width);
+            resolved.height = scale(region.height, screen.height);
+        }
+        if resolved.width == 0 {
+            resolved.width = screen.width.saturating_sub(resolved.x);
+        }
+        if resolved.height == 0 {
+            resolved.height = screen.height.saturating_sub(resolved.y);
+        }
+        resolved
+    }
+}
+
+/// Find the first specification that matches the screen, explaining the decision.
+pub fn explain(screen: &Screen, specs: &[CaptureSpecification]) -> Match {
+    let mut reasons = vec![];
+    for (i, spec) in specs.iter().enumerate() {
+        let mismatches = spec.mismatches(screen);
+        if mismatches.is_empty() {
+            reasons.push(format!("capture[{}]: matches", i));
+            return Match {
+                index: Some(i),
+                specification: spec.resolve(screen),
+                reasons,
+            };
+        }
+        reasons.push(format!("capture[{}]: {}", i, mismatches.join(", ")));
+    }
+
+    // No capture match found... well, return some sane default then.
+    reasons.push("no specification matches, capturing the whole screen".to_owned());
+    Match {
+        index: None,
+        specification: CaptureSpecification {
+            width: screen.width,
+            height: screen.height,
+            display: screen.display,
+            ..Default::default()
+        },
+        reasons,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explain() {
+        let specs: Vec<CaptureSpecification> = serde_yaml::from_str(
+            "[{match_width: 1920, match_height: 1080, y: 100},
+              {min_width: 3000, match_aspect_ratio: 3.556, region: {x: 0.5}},
+              {match_display: 1, display: 1, region: {width: 0.25, height: 0.5}}]",
+        )
+        .unwrap();
+        let screen = |width, height, display| Screen {
+            width,
+            height,
+            display,
+        };
+
+        let m = explain(&screen(1920, 1080, 0), &specs);
+        assert_eq!(m.index, Some(0));
+        assert_eq!(m.specification.height, 980);
+
+        // Two 1920x1080 monitors side by side, captures the right half.
+        let m = explain(&screen(3840, 1080, 0), &specs);
+        assert_eq!(m.index, Some(1));
+        assert_eq!(
+            (
+                m.specification.x,
+                m.specification.width,
+                m.specification.height
+            ),
+            (1920, 1920, 1080)
+        );
+        assert_eq!(m.reasons[0], "capture[0]: width 3840 is not 1920");
+
+        // Ultrawide is wide enough but has the wrong aspect ratio.
+        let m = explain(&screen(3440, 1440, 1), &specs);
+        assert_eq!(m.index, Some(2));
+        assert_eq!(m.reasons[1], "capture[1]: aspect ratio 2.389 is not 3.556");
+        assert_eq!((m.specification.width, m.specification.height), (860, 720));
+
+        let m = explain(&screen(2560, 1440, 0), &specs);
+        assert_eq!(m.index, None);
+        assert_eq!(m.specification.width, 2560);
+        assert_eq!(m.reasons.len(), 4);
+        assert!(m
+            .to_string()
+            .ends_with("x: 0, y: 0, width: 2560, height: 1440"));
+    }
+}
@@ -0,0 +1,166 @@
+//! Time source abstraction, such that timing dependent code can be tested deterministically. Also
+//! holds the detection of gaps in time, like those caused by suspending the machine.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of time that can also sleep.
+pub trait Clock: Send {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// The current wall clock time, like for the time of day.
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Sleep for the provided duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The system's monotonic clock.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock that only advances when told to, sleeping advances it instantly. Clones share the same
+/// time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+    start: Instant,
+    start_time: Arc<Mutex<SystemTime>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::at(SystemTime::now())
+    }
+}
+
+impl ManualClock {
+    /// Create a clock whose wall clock time starts at the provided time.
+    pub fn at(time: SystemTime) -> Self {
+        let start = Instant::now();
+        ManualClock {
+            now: Arc::new(Mutex::new(start)),
+            start,
+            start_time: Arc::new(Mutex::new(time)),
+        }
+    }
+
+    /// Advance the time by the provided duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Set the wall clock time without advancing the monotonic time, like the system clock being
+    /// adjusted.
+    pub fn set_system_time(&self, time: SystemTime) {
+        let elapsed = self.now().duration_since(self.start);
+        *self.start_time.lock().unwrap() = time - elapsed;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        *self.start_time.lock().unwrap() + self.now().duration_since(self.start)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Detects gaps in a loop that runs regularly, like those caused by suspending the machine or the
+/// wall clock jumping. Both the monotonic and the wall clock are compared, the monotonic clock
+/// doesn't advance during suspend on all platforms, like Linux.
+pub struct GapDetector {
+    clock: Box<dyn Clock>,
+    previous: Option<(Instant, SystemTime)>,
+    /// Time between checks above which it is considered a gap.
+    pub threshold: Duration,
+}
+
+impl GapDetector {
+    /// The default threshold above which the time between checks is considered a gap.
+    pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(2);
+
+    /// Create a detector using the system clock.
+    pub fn new(threshold: Duration) -> Self {
+        GapDetector::with_clock(threshold, Box::new(SystemClock))
+    }
+
+    /// Create a detector using the provided clock.
+    pub fn with_clock(threshold: Duration, clock: Box<dyn Clock>) -> Self {
+        GapDetector {
+            clock,
+            previous: None,
+            threshold,
+        }
+    }
+
+    /// Call this every iteration, returns the duration of the gap if the time since the previous
+    /// call exceeds the threshold on either clock. A wall clock jumping backwards counts as a gap
+    /// of the size of the jump.
+    pub fn check(&mut self) -> Option<Duration> {
+        let now = (self.clock.now(), self.clock.system_time());
+        let gap = self
+            .previous
+            .map(|(instant, time)| {
+                let monotonic = now.0.saturating_duration_since(instant);
+                let wall = now.1.duration_since(time).unwrap_or_else(|e| e.duration());
+                monotonic.max(wall)
+            })
+            .filter(|gap| *gap > self.threshold);
+        self.previous = Some(now);
+        gap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_gap_detector() {
+        let clock = ManualClock::default();
+        let mut detector = GapDetector::with_clock(Duration::from_secs(2), Box::new(clock.clone()));
+        assert_eq!(detector.check(), None);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(detector.check(), None);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(detector.check(), Some(Duration::from_secs(60)));
+        assert_eq!(detector.check(), None);
+    }
+
+    #[test]
+    fn test_gap_detector_wall_clock() {
+        // Like a resume on Linux, or the system time being set, only the wall clock jumps.
+        let clock = ManualClock::at(UNIX_EPOCH + Duration::from_secs(7200));
+        let mut detector = GapDetector::with_clock(Duration::from_secs(2), Box::new(clock.clone()));
+        assert_eq!(detector.check(), None);
+        clock.set_system_time(UNIX_EPOCH + Duration::from_secs(7260));
+        assert_eq!(detector.check(), Some(Duration::from_secs(60)));
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(detector.check(), None);
+
+        // Jumping backwards is a gap as well.
+        clock.set_system_time(UNIX_EPOCH + Duration::from_secs(3600));
+        assert!(detector.check().unwrap() > Duration::from_secs(3600));
+        assert_eq!(detector.check(), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Color configuration and helpers that operate on the led values.
+use lights::RGB;
+use serde::{Deserialize, Serialize};
+
+/// A color as it can be specified in the configuration.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Default, Copy, Clone)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl From<Color> for RGB {
+    fn from(c: Color) -> RGB {
+        RGB {
+            r: c.r,
+            g: c.g,
+            b: c.b,
+        }
+    }
+}
+
+/// Scale the provided leds by a factor.
+pub fn scale(leds: &mut [RGB], factor: f32) {
+    if factor == 1.0 {
+        return;
+    }
+    for led in leds.iter_mut() {
+        led.r = (led.r as f32 * factor) as u8;
+        led.g = (led.g as f32 * factor) as u8;
+        led.b = (led.b as f32 * factor) as u8;
+    }
+}
+
+/// The gains of the red, green and blue channels for white light of a color temperature in Kelvin,
+/// using Tanner Helland's approximation of the black body colors. Temperatures of 6600K and up
+/// keep red, lower temperatures are warmer.
+pub fn temperature_gains(kelvin: f32) -> [f32; 3] {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let green = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12216 * (t - 60.0).powf(-0.07551485)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177 * (t - 10.0).ln() - 305.0448
+    };
+    [red, green, blue].map(|c| c.clamp(0.0, 255.0) / 255.0)
+}
+
+/// Scale each channel of the provided leds by its gain.
+pub fn tint(leds: &mut [RGB], gains: [f32; 3]) {
+    for led in leds.iter_mut() {
+        led.r = (led.r as f32 * gains[0]) as u8;
+        led.g = (led.g as f32 * gains[1]) as u8;
+        led.b = (led.b as f32 * gains[2]) as u8;
+    }
+}
+
+/// Configuration for the minimum glow floor.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Copy, Clone)]
+pub struct FloorConfig {
+    /// The color of the floor, this is also the standby color.
+    pub color: Color,
+
+    /// The level (0.0 - 1.0) of the floor color that the leds never drop below.
+    pub level: f32,
+}
+
+impl Default for FloorConfig {
+    fn default() -> Self {
+        FloorConfig {
+            color: Color {
+                r: 255,
+                g: 180,
+                b: 100,
+            },
+            level: 0.05,
+        }
+    }
+}
+
+impl FloorConfig {
+    /// The floor color with the level applied.
+    pub fn floor(&self) -> RGB {
+        let level = self.level.clamp(0.0, 1.0);
+        RGB {
+            r: (self.color.r as f32 * level) as u8,
+            g: (self.color.g as f32 * level) as u8,
+            b: (self.color.b as f32 * level) as u8,
+        }
+    }
+
+    /// Apply the floor to the provided leds.
+    ///
+    /// This uses a screen blend; `1 - (1 - c) * (1 - f)`. Black becomes the floor color, full
+    /// intensity stays full intensity and the transition in between is smooth. This ensures normal
+    /// content is barely affected while dark content never drops below the floor.
+    pub fn apply(&self, leds: &mut [RGB]) {
+        let floor = self.floor();
+        let screen = |c: u8, f: u8| -> u8 {
+            let c = c as u32;
+            let f = f as u32;
+            (c + f - (c * f) / 255) as u8
+        };
+        for led in leds.iter_mut() {
+            led.r = screen(led.r, floor.r);
+            led.g = screen(led.g, floor.g);
+            led.b = screen(led.b, floor.b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_floor() {
+        let config = FloorConfig {
+            color: Color {
+                r: 200,
+                g: 100,
+                b: 0,
+            },
+            level: 0.5,
+        };
+        assert_eq!(
+            config.floor(),
+            RGB {
+                r: 100,
+                g: 50,
+                b: 0
+            }
+        );
+
+        let mut leds = [
+            RGB { r: 0, g: 0, b: 0 },
+            RGB {
+                r: 255,
+                g: 255,
+                b: 255,
+            },
+            RGB {
+                r: 128,
+                g: 10,
+                b: 10,
+            },
+        ];
+        config.apply(&mut leds);
+
+        // Black becomes the floor.
+        assert_eq!(leds[0], config.floor());
+        // Full brightness is unaffected.
+        assert_eq!(
+            leds[1],
+            RGB {
+                r: 255,
+                g: 255,
+                b: 255
+            }
+        );
+        // Anything in between never drops below the floor, nor the original value.
+        assert!(leds[2].r >= 128);
+        assert!(leds[2].g >= 50);
+        assert_eq!(leds[2].b, 10);
+    }
+
+    #[test]
+    fn test_temperature_gains() {
+        let neutral = temperature_gains(6600.0);
+        assert!(neutral.iter().all(|g| *g > 0.99));
+        // Candle light has barely any blue, daylight is bluish.
+        let warm = temperature_gains(1900.0);
+        assert_eq!((warm[0], warm[2]), (1.0, 0.0));
+        assert!(warm[1] > 0.4 && warm[1] < 0.6);
+        let cool = temperature_gains(10000.0);
+        assert!(cool[0] < cool[1] && cool[1] < cool[2]);
+    }
+}
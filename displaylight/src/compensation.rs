@@ -0,0 +1,142 @@
+//! Per led brightness compensation, leds at corners or near stands reflect differently, this allows
+//! correcting for that with a gain per led.
+use crate::zones::Layout;
+use lights::RGB;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// Map of gains, one for each led.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
+pub struct CompensationMap {
+    /// The gain for each led, leds beyond the length of this list are not modified.
+    pub gains: Vec<f32>,
+}
+
+impl CompensationMap {
+    /// Create a map with a gain of 1.0 for each of the leds.
+    pub fn uniform(leds: usize) -> Self {
+        CompensationMap {
+            gains: vec![1.0; leds],
+        }
+    }
+
+    /// The number of leds at each end of a side that are dimmed by [`CompensationMap::for_layout`].
+    pub const CORNER_LEDS: u32 = 3;
+
+    /// The gain of the led closest to a corner in [`CompensationMap::for_layout`].
+    pub const CORNER_GAIN: f32 = 0.7;
+
+    /// Create a starting map for the layout. At the corners the light of two sides adds up on the
+    /// wall, so the leds closest to each corner are dimmed, rising linearly from
+    /// [`CompensationMap::CORNER_GAIN`] to 1.0 over [`CompensationMap::CORNER_LEDS`] leds.
+    pub fn for_layout(layout: &Layout) -> Self {
+        let mut gains = Vec::with_capacity(layout.leds());
+        for side in [layout.left, layout.bottom, layout.right, layout.top] {
+            for i in 0..side {
+                let distance = i.min(side - 1 - i);
+                let gain = if distance < Self::CORNER_LEDS {
+                    Self::CORNER_GAIN
+                        + (1.0 - Self::CORNER_GAIN) * distance as f32 / Self::CORNER_LEDS as f32
+                } else {
+                    1.0
+                };
+                gains.push(gain);
+            }
+        }
+        CompensationMap { gains }
+    }
+
+    /// Load a map from a yaml file.
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|ref e| format!("Failed to read compensation map '{}': {}", path, e))?;
+        let map: CompensationMap = serde_yaml::from_str(&content)
+            .map_err(|ref e| format!("Failed to parse compensation map '{}': {}", path, e))?;
+        Ok(map)
+    }
+
+    /// Save the map to a yaml file.
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, serde_yaml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Apply the gains to the provided leds.
+    pub fn apply(&self, leds: &mut [RGB]) {
+        for (led, gain) in leds.iter_mut().zip(self.gains.iter()) {
+            let gain = gain.max(0.0);
+            led.r = (led.r as f32 * gain).min(255.0) as u8;
+            led.g = (led.g as f32 * gain).min(255.0) as u8;
+            led.b = (led.b as f32 * gain).min(255.0) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn test_compensation_map() {
+        let mut map = CompensationMap::uniform(3);
+        map.gains[1] = 0.5;
+        map.gains[2] = 2.0;
+
+        let path = temp_dir().join("compensation_map.yaml");
+        let path = path.to_str().expect("path must be ok");
+        map.save(path).expect("Should succeed.");
+        let loaded = CompensationMap::load(path).expect("Should succeed.");
+        assert_eq!(loaded, map);
+
+        let v = RGB {
+            r: 100,
+            g: 200,
+            b: 10,
+        };
+        let mut leds = [v; 4];
+        loaded.apply(&mut leds);
+        assert_eq!(leds[0], v);
+        assert_eq!(
+            leds[1],
+            RGB {
+                r: 50,
+                g: 100,
+                b: 5
+            }
+        );
+        assert_eq!(
+            leds[2],
+            RGB {
+                r: 200,
+                g: 255,
+                b: 20
+            }
+        );
+        // Beyond the map, untouched.
+        assert_eq!(leds[3], v);
+    }
+
+    #[test]
+    fn test_for_layout() {
+        let layout = Layout::for_leds(228);
+        let map = CompensationMap::for_layout(&layout);
+        assert_eq!(map.gains.len(), 228);
+
+        // Both ends of each side are dimmed, the middle is untouched.
+        let mut start = 0;
+        for side in [layout.left, layout.bottom, layout.right, layout.top] {
+            let gains = &map.gains[start..start + side as usize];
+            assert_eq!(gains[0], CompensationMap::CORNER_GAIN);
+            assert_eq!(gains[side as usize - 1], CompensationMap::CORNER_GAIN);
+            assert!(gains[1] > gains[0] && gains[2] > gains[1] && gains[3] > gains[2]);
+            assert_eq!(gains[3], 1.0);
+            assert_eq!(gains[side as usize / 2], 1.0);
+            start += side as usize;
+        }
+
+        // Tiny layouts don't underflow.
+        let map = CompensationMap::for_layout(&Layout::for_leds(3));
+        assert_eq!(map.gains.len(), 3);
+    }
+}
@@ -0,0 +1,118 @@
+//! Events emitted while running, tools can subscribe to these to react to what is seen. Like logging
+//! when letterboxing appears, syncing other lights to the average color or counting capture
+//! failures.
+use crate::rectangle::Rectangle;
+use crate::CaptureSpecification;
+use lights::RGB;
+
+use std::sync::{Arc, Mutex};
+
+/// The events that can be observed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event<'a> {
+    /// A frame was processed, these are the led colors to be written.
+    FrameProcessed(&'a [RGB]),
+    /// The detected borders changed, new zones are made for this region of interest.
+    BordersChanged(Rectangle),
+    /// The screen capture was set up.
+    CaptureSetup,
+    /// Setting up the screen capture failed.
+    CaptureSetupFailed(String),
+    /// Capturing an image failed, with the number of consecutive failures.
+    CaptureFailed(usize),
+    /// The screen capture was reset and will be set up again.
+    CaptureReset,
+    /// The resolution changed, the capture is prepared with this specification.
+    ResolutionChanged {
+        width: u32,
+        height: u32,
+        specification: CaptureSpecification,
+    },
+    /// Writing the leds to a sink failed, like a serial error.
+    OutputError(String),
+    /// The loop didn't run for this long, like when the machine was suspended. The state of the
+    /// stages is reset.
+    Resumed(std::time::Duration),
+}
+
+/// Identifies a subscription, used to unsubscribe.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct SubscriptionId(usize);
+
+type Callback = Box<dyn FnMut(&Event) + Send>;
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: usize,
+    callbacks: Vec<(SubscriptionId, Callback)>,
+}
+
+/// Handle to subscribe to events, this can be cloned and used from other threads.
+///
+/// Callbacks are invoked on the thread that emits the event and block the pipeline, so they should
+/// be quick. Subscribing or unsubscribing from within a callback deadlocks.
+#[derive(Default, Clone)]
+pub struct Events {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl Events {
+    /// Subscribe to all events, returns the id to unsubscribe with.
+    pub fn subscribe(&self, callback: impl FnMut(&Event) + Send + 'static) -> SubscriptionId {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let id = SubscriptionId(subscribers.next_id);
+        subscribers.next_id += 1;
+        subscribers.callbacks.push((id, Box::new(callback)));
+        id
+    }
+
+    /// Remove a subscription, returns false if it didn't exist.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let before = subscribers.callbacks.len();
+        subscribers.callbacks.retain(|(v, _)| *v != id);
+        subscribers.callbacks.len() != before
+    }
+
+    /// Invoke all subscribed callbacks with the event.
+    pub fn emit(&self, event: &Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for (_, callback) in subscribers.callbacks.iter_mut() {
+            callback(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let events = Events::default();
+        let seen = Arc::new(Mutex::new(vec![]));
+
+        let seen_a = seen.clone();
+        let a = events.subscribe(move |e| seen_a.lock().unwrap().push(format!("a {e:?}")));
+        let seen_b = seen.clone();
+        let b = events.subscribe(move |e| {
+            if let Event::CaptureFailed(count) = e {
+                seen_b.lock().unwrap().push(format!("b {count}"));
+            }
+        });
+        assert_ne!(a, b);
+
+        events.emit(&Event::CaptureFailed(3));
+        events.emit(&Event::CaptureReset);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["a CaptureFailed(3)", "b 3", "a CaptureReset"]
+        );
+
+        assert!(events.unsubscribe(a));
+        assert!(!events.unsubscribe(a));
+        seen.lock().unwrap().clear();
+        events.clone().emit(&Event::CaptureFailed(4));
+        assert_eq!(*seen.lock().unwrap(), vec!["b 4"]);
+    }
+}
@@ -0,0 +1,103 @@
+//! What to show on the leds when displaylight exits.
+use crate::color::{self, Color};
+use lights::RGB;
+use serde::{Deserialize, Serialize};
+
+/// The behavior on exit, in the config this is `leave`, `black`, `standby: {r: .., g: .., b: ..}`
+/// or `fade: <seconds>`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitBehavior {
+    /// Leave the leds as they are, the firmware decays them eventually.
+    Leave,
+    /// Set the leds to black.
+    Black,
+    /// Set all leds to this color.
+    Standby(Color),
+    /// Fade the last shown leds to black over this many seconds.
+    Fade(f32),
+}
+
+impl Default for ExitBehavior {
+    fn default() -> Self {
+        ExitBehavior::Fade(0.5)
+    }
+}
+
+impl ExitBehavior {
+    /// The frames to write on exit, given the last shown leds and the update rate in Hz.
+    pub fn frames(&self, last: &[RGB], rate: f32) -> Vec<Vec<RGB>> {
+        match *self {
+            ExitBehavior::Leave => vec![],
+            ExitBehavior::Black => vec![vec![RGB::default(); last.len()]],
+            ExitBehavior::Standby(c) => vec![vec![c.into(); last.len()]],
+            ExitBehavior::Fade(duration) => {
+                let count = std::cmp::max(1, (duration.max(0.0) * rate.max(0.0)).ceil() as usize);
+                (1..=count)
+                    .map(|i| {
+                        let mut leds = last.to_vec();
+                        color::scale(&mut leds, 1.0 - i as f32 / count as f32);
+                        leds
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_frames() {
+        let v = RGB {
+            r: 200,
+            g: 100,
+            b: 0,
+        };
+        let last = [v; 3];
+        assert!(ExitBehavior::Leave.frames(&last, 60.0).is_empty());
+        assert_eq!(
+            ExitBehavior::Black.frames(&last, 60.0),
+            vec![vec![RGB::default(); 3]]
+        );
+        let standby = Color { r: 1, g: 2, b: 3 };
+        assert_eq!(
+            ExitBehavior::Standby(standby).frames(&last, 60.0),
+            vec![vec![standby.into(); 3]]
+        );
+
+        // Fade over half a second at 10 Hz, ending at black.
+        let frames = ExitBehavior::Fade(0.5).frames(&last, 10.0);
+        assert_eq!(frames.len(), 5);
+        assert_eq!(
+            frames[0][0],
+            RGB {
+                r: 160,
+                g: 80,
+                b: 0
+            }
+        );
+        assert_eq!(frames[4], vec![RGB::default(); 3]);
+
+        // Zero duration still ends at black.
+        assert_eq!(
+            ExitBehavior::Fade(0.0).frames(&last, 10.0),
+            vec![vec![RGB::default(); 3]]
+        );
+
+        let parsed: Vec<ExitBehavior> =
+            serde_yaml::from_str("[leave, black, {standby: {r: 1, g: 2, b: 3}}, {fade: 1.5}]")
+                .expect("Should parse.");
+        assert_eq!(
+            parsed,
+            vec![
+                ExitBehavior::Leave,
+                ExitBehavior::Black,
+                ExitBehavior::Standby(standby),
+                ExitBehavior::Fade(1.5)
+            ]
+        );
+    }
+}
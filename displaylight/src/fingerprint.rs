@@ -0,0 +1,50 @@
+//! Cheap fingerprint of a captured image, used to detect frames that did not change.
+use screen_capture::ImageBGR;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Calculate a fingerprint of the image, using every `pixel_stride`'th pixel. A stride of 1 uses
+/// all pixels, larger strides are cheaper but may miss small changes.
+pub fn fingerprint(image: &dyn ImageBGR, pixel_stride: u32) -> u64 {
+    let width = image.width();
+    let height = image.height();
+    let mut h = FNV_OFFSET;
+    let mut mix = |v: u64| {
+        h ^= v;
+        h = h.wrapping_mul(FNV_PRIME);
+    };
+    mix(width as u64);
+    mix(height as u64);
+
+    let total = width as u64 * height as u64;
+    for i in (0..total).step_by(std::cmp::max(1, pixel_stride) as usize) {
+        let x = (i % width as u64) as u32;
+        let y = (i / width as u64) as u32;
+        let p = image.pixel(x, y);
+        mix((p.r as u64) << 16 | (p.g as u64) << 8 | p.b as u64);
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screen_capture::raster_image::RasterImageBGR;
+    use screen_capture::BGR;
+
+    #[test]
+    fn test_fingerprint() {
+        let mut img = RasterImageBGR::filled(100, 50, BGR { r: 0, g: 0, b: 0 });
+        let a = fingerprint(&img, 1);
+        assert_eq!(a, fingerprint(&img, 1));
+
+        img.set_pixel(99, 49, BGR { r: 0, g: 1, b: 0 });
+        let b = fingerprint(&img, 1);
+        assert_ne!(a, b);
+
+        // Different dimensions with the same content differ.
+        let img = RasterImageBGR::filled(50, 100, BGR { r: 0, g: 0, b: 0 });
+        assert_ne!(a, fingerprint(&img, 1));
+    }
+}
@@ -0,0 +1,189 @@
+//! A bounded channel that only holds the latest value, sending replaces any value that was not yet
+//! received. This is used between the pipeline threads, such that a slow stage always works on the
+//! most recent data instead of a backlog.
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+struct State<T> {
+    value: Option<T>,
+    closed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    condvar: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.condvar.notify_all();
+    }
+}
+
+/// The sending half of the channel.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving half of the channel.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Error returned by [`Receiver::try_recv`] and [`Receiver::recv_timeout`].
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RecvError {
+    /// No value is available at the moment.
+    Empty,
+    /// The other half of the channel was dropped and no value is available.
+    Disconnected,
+}
+
+/// Create a new latest value channel.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            value: None,
+            closed: false,
+        }),
+        condvar: Condvar::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Send a value, replacing the value that wasn't received yet. Returns the value as error if
+    /// the receiver was dropped.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(value);
+        }
+        state.value = Some(value);
+        self.shared.condvar.notify_all();
+        Ok(())
+    }
+
+    /// Send a value combined with the value that wasn't received yet, if any, such that a change
+    /// isn't lost when a newer one replaces it. Returns the value as error if the receiver was
+    /// dropped.
+    pub fn merge(&self, value: T, merge: impl FnOnce(T, T) -> T) -> Result<(), T> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(value);
+        }
+        state.value = Some(match state.value.take() {
+            Some(older) => merge(older, value),
+            None => value,
+        });
+        self.shared.condvar.notify_all();
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Block until a value is available, returns None if the sender was dropped.
+    pub fn recv(&self) -> Option<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(value) = state.value.take() {
+                return Some(value);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.shared.condvar.wait(state).unwrap();
+        }
+    }
+
+    /// Block until a value is available or the timeout expires.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvError> {
+        let state = self.shared.state.lock().unwrap();
+        let (mut state, _) = self
+            .shared
+            .condvar
+            .wait_timeout_while(state, timeout, |s| s.value.is_none() && !s.closed)
+            .unwrap();
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.closed => Err(RecvError::Disconnected),
+            None => Err(RecvError::Empty),
+        }
+    }
+
+    /// Retrieve the value if there is one, without blocking.
+    pub fn try_recv(&self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.closed => Err(RecvError::Disconnected),
+            None => Err(RecvError::Empty),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest() {
+        let (tx, rx) = channel::<u32>();
+        assert_eq!(rx.try_recv(), Err(RecvError::Empty));
+
+        // Only the latest value is kept.
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(rx.try_recv(), Ok(2));
+        assert_eq!(rx.try_recv(), Err(RecvError::Empty));
+
+        // Merging combines with the value that wasn't received.
+        tx.merge(1, |a, b| a + b).unwrap();
+        tx.merge(2, |a, b| a + b).unwrap();
+        assert_eq!(rx.try_recv(), Ok(3));
+        tx.merge(4, |a, b| a + b).unwrap();
+        assert_eq!(rx.try_recv(), Ok(4));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(1)),
+            Err(RecvError::Empty)
+        );
+
+        // Blocking receive across threads.
+        let t = std::thread::spawn(move || {
+            tx.send(3).unwrap();
+            tx
+        });
+        assert_eq!(rx.recv(), Some(3));
+
+        // Pending values are still received after the sender is dropped.
+        let tx = t.join().unwrap();
+        tx.send(4).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv(), Some(4));
+        assert_eq!(rx.recv(), None);
+        assert_eq!(rx.try_recv(), Err(RecvError::Disconnected));
+
+        // Sending fails if the receiver is gone.
+        let (tx, rx) = channel::<u32>();
+        drop(rx);
+        assert_eq!(tx.send(5), Err(5));
+    }
+}
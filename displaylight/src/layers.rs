@@ -0,0 +1,278 @@
+//! Layered loading of the config, later layers override the fields of earlier ones.
+//!
+//! The layers are, in order:
+//!   - The built-in defaults, the config shipped for the operating system.
+//!   - The system config, `/etc/displaylight/config.yaml` on unix.
+//!   - The user config, `$XDG_CONFIG_HOME/displaylight/config.yaml` (defaulting to `~/.config`) on
+//!     unix and `%APPDATA%\displaylight\config.yaml` on Windows.
+//!   - An explicitly provided config file.
+//!   - Environment variables like `DISPLAYLIGHT_LIMITING_FACTOR=0.3`, nested fields are separated
+//!     by a double underscore, like `DISPLAYLIGHT_POWER_BUDGET__BUDGET_MA=2000`.
+//!   - Overrides like `limiting_factor=0.3`, nested fields are separated by a dot, like
+//!     `power_budget.budget_ma=2000`.
+//!
+//! Files only need to contain the fields they change. Mappings are merged field by field, other
+//! values, including lists like `capture`, are replaced as a whole. Values are parsed as yaml, so
+//! `capture=[{match_width: 1920}]` works as an override.
+use crate::Config;
+use serde_yaml::{Mapping, Value};
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// The built-in defaults.
+#[cfg(windows)]
+pub const DEFAULTS: &str = include_str!("../config/windows.yaml");
+/// The built-in defaults.
+#[cfg(not(windows))]
+pub const DEFAULTS: &str = include_str!("../config/linux.yaml");
+
+/// Prefix of the environment variables overriding fields.
+pub const ENV_PREFIX: &str = "DISPLAYLIGHT_";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Layer {
+    /// A yaml document.
+    Yaml { source: String, content: String },
+    /// A file, read whenever the config is loaded.
+    File { path: PathBuf, required: bool },
+    /// A single field.
+    Field { path: Vec<String>, value: String },
+}
+
+/// The layers to load the config from, see the module documentation. The files are read whenever
+/// the config is loaded, such that changes to them are picked up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigLoader {
+    layers: Vec<Layer>,
+}
+
+impl Default for ConfigLoader {
+    fn default() -> Self {
+        ConfigLoader::new()
+    }
+}
+
+impl ConfigLoader {
+    /// A loader holding only the built-in defaults.
+    pub fn new() -> Self {
+        ConfigLoader {
+            layers: vec![Layer::Yaml {
+                source: "built-in defaults".to_owned(),
+                content: DEFAULTS.to_owned(),
+            }],
+        }
+    }
+
+    /// Add the system and user config files, each only used if it exists.
+    pub fn standard_files(mut self) -> Self {
+        for path in standard_paths() {
+            self.layers.push(Layer::File {
+                path,
+                required: false,
+            });
+        }
+        self
+    }
+
+    /// Add a config file, failing to read it is an error when loading.
+    pub fn file(mut self, path: &Path) -> Self {
+        self.layers.push(Layer::File {
+            path: path.to_path_buf(),
+            required: true,
+        });
+        self
+    }
+
+    /// Add the fields from the environment variables starting with [`ENV_PREFIX`], the log filter
+    /// variable [`crate::logging::ENV_FILTER`] is not a field and skipped.
+    pub fn environment(mut self, vars: impl Iterator<Item = (String, String)>) -> Self {
+        let mut vars: Vec<(String, String)> = vars
+            .filter(|(k, _)| k.starts_with(ENV_PREFIX) && k != crate::logging::ENV_FILTER)
+            .collect();
+        vars.sort();
+        for (key, value) in vars {
+            let path = key[ENV_PREFIX.len()..]
+                .to_lowercase()
+                .split("__")
+                .map(str::to_owned)
+                .collect();
+            self.layers.push(Layer::Field { path, value });
+        }
+        self
+    }
+
+    /// Add an override like `key=value`, nested keys are separated by dots.
+    pub fn set(mut self, assignment: &str) -> Result<Self, Box<dyn Error>> {
+        let (key, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| format!("Override '{}' is not of the form key=value", assignment))?;
+        let path: Vec<String> = key.trim().split('.').map(str::to_owned).collect();
+        if path.iter().any(|k| k.is_empty()) {
+            return Err(format!("Override '{}' has an empty key", assignment).into());
+        }
+        self.layers.push(Layer::Field {
+            path,
+            value: value.to_owned(),
+        });
+        Ok(self)
+    }
+
+    /// The files this loader reads, whether they exist or not.
+    pub fn files(&self) -> Vec<&Path> {
+        self.layers
+            .iter()
+            .filter_map(|l| match l {
+                Layer::File { path, .. } => Some(path.as_path()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Merge all layers into one yaml value, without checking whether it is a valid config.
+    pub fn merged(&self) -> Result<Value, Box<dyn Error>> {
+        let mut merged = Value::Mapping(Mapping::new());
+        for layer in self.layers.iter() {
+            match layer {
+                Layer::Yaml { source, content } => merge(&mut merged, parse(source, content)?),
+                Layer::File { path, required } => {
+                    let content = match std::fs::read_to_string(path) {
+                        Ok(content) => content,
+                        Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => continue,
+                        Err(e) => {
+                            return Err(format!("Failed to read '{}': {}", path.display(), e).into())
+                        }
+                    };
+                    merge(&mut merged, parse(&path.display().to_string(), &content)?);
+                }
+                Layer::Field { path, value } => {
+                    // Plain strings like a port name aren't valid yaml in all cases, keep them.
+                    let value = serde_yaml::from_str(value)
+                        .unwrap_or_else(|_| Value::String(value.clone()));
+                    let nested = path.iter().rev().fold(value, |value, key| {
+                        let mut mapping = Mapping::new();
+                        mapping.insert(Value::String(key.clone()), value);
+                        Value::Mapping(mapping)
+                    });
+                    merge(&mut merged, nested);
+                }
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Load the config from all layers and validate it.
+    pub fn load(&self) -> Result<Config, Box<dyn Error>> {
+        let config: Config = serde_yaml::from_value(self.merged()?)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// The system and user config files, in that order.
+pub fn standard_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/etc/displaylight/config.yaml"));
+    }
+    let user_dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+    };
+    if let Some(dir) = user_dir {
+        paths.push(dir.join("displaylight").join("config.yaml"));
+    }
+    paths
+}
+
+fn parse(source: &str, content: &str) -> Result<Value, Box<dyn Error>> {
+    let value: Value = serde_yaml::from_str(content)
+        .map_err(|ref e| format!("Failed to parse '{}': {}", source, e))?;
+    match value {
+        // An empty file, or one with only comments.
+        Value::Null => Ok(Value::Mapping(Mapping::new())),
+        Value::Mapping(_) => Ok(value),
+        _ => Err(format!("Failed to parse '{}': expected a mapping of fields", source).into()),
+    }
+}
+
+/// Merge the overrides into the base, mappings are merged recursively, other values replaced.
+pub(crate) fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Mapping(base), Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layers() {
+        let defaults = ConfigLoader::new().load().expect("Defaults are valid.");
+
+        let path = std::env::temp_dir().join("test_layers.yaml");
+        std::fs::write(
+            &path,
+            "# Only overrides some fields.\nlimiting_factor: 0.25\npower_budget:\n  ma_per_channel: 20\n  budget_ma: 1000\n",
+        )
+        .unwrap();
+        let env = vec![
+            ("DISPLAYLIGHT_RATE".to_owned(), "30".to_owned()),
+            (
+                "DISPLAYLIGHT_POWER_BUDGET__BUDGET_MA".to_owned(),
+                "2000".to_owned(),
+            ),
+            ("DISPLAYLIGHT_LOG".to_owned(), "debug".to_owned()),
+            ("HOME".to_owned(), "/root".to_owned()),
+        ];
+        let loader = ConfigLoader::new()
+            .file(&path)
+            .environment(env.into_iter())
+            .set("rate=20")
+            .unwrap()
+            .set("port=COM3")
+            .unwrap();
+        let config = loader.load().expect("Should be valid.");
+        assert_eq!(config.limiting_factor, 0.25);
+        assert_eq!(config.rate, 20.0);
+        assert_eq!(config.port, "COM3");
+        let budget = config.power_budget.expect("Set by the file.");
+        assert_eq!(budget.ma_per_channel, 20.0);
+        assert_eq!(budget.budget_ma, 2000.0);
+        assert_eq!(config.capture, defaults.capture);
+        assert_eq!(loader.files(), vec![path.as_path()]);
+
+        // Invalid values are reported, missing files are only an error if explicitly provided.
+        assert!(ConfigLoader::new().set("rate=0").unwrap().load().is_err());
+        assert!(ConfigLoader::new().set("rate").is_err());
+        let missing = std::env::temp_dir().join("test_layers_missing.yaml");
+        assert!(ConfigLoader::new().file(&missing).load().is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base: Value = serde_yaml::from_str("{a: 1, b: {c: 2, d: [1, 2]}}").unwrap();
+        merge(
+            &mut base,
+            serde_yaml::from_str("{b: {d: [3], e: 4}, f: 5}").unwrap(),
+        );
+        let expected: Value =
+            serde_yaml::from_str("{a: 1, b: {c: 2, d: [3], e: 4}, f: 5}").unwrap();
+        assert_eq!(base, expected);
+    }
+}
@@ -3,30 +3,66 @@
 //! The following happens in a loop:
 //!   - Retrieval of the image shown on the screen.
 //!   - Black border detection, if we have black borders we want to ignore this and get colors from the interesting part.
-//!   - Sample regions associated to each led.
+//!   - Sample regions associated to each led, this is skipped if the frame is unchanged.
 //!   - Set the leds to the average of the sampled colors.
+//!   - Optionally adapt the overall brightness to the average picture level.
+//!   - Optionally ensure the leds never drop below a minimum glow floor.
+//!   - Optionally apply a per led brightness compensation map.
 //!   - Sleep to ensure we match a certain update interval.
 //!
+//! Each of these steps is a stage of the [`pipeline::Pipeline`], which can be reordered, replaced
+//! or extended. The default stages are in the [`stages`] module. The steps can run sequentially,
+//! or split over a capture, processing and output thread.
+//!
+//! Tools can react to what is seen by subscribing to the [`events`], like processed frames, border
+//! changes and capture failures.
+//!
+//! The config is loaded in layers, from the built-in defaults up to command line overrides, see
+//! [`layers`]. Its files can be watched with [`DisplayLight::watch_config`], changes are applied
+//! without restarting, see [`reload`].
+//!
 //! What also happens is that if the resolution changes, the capture can be reconfigured based on a
 //! priority list, this allows retrieving a specific monitor if there's a multi monitor setup.
 
+pub mod auto_profile;
 pub mod border_detection;
+pub mod brightness;
+pub mod capture;
+pub mod clock;
+pub mod color;
+pub mod compensation;
+pub mod events;
+pub mod exit;
+pub mod fingerprint;
+pub mod latest;
+pub mod layers;
+pub mod logging;
+pub mod metrics;
+pub mod outputs;
+pub mod pipeline;
+pub mod profiles;
 pub mod rate_limiter;
 pub mod rectangle;
+pub mod reload;
 pub mod sampler;
+pub mod schedule;
+pub mod shared_capture;
+pub mod stages;
+pub mod validation;
 pub mod zones;
 
 #[cfg(test)]
 pub mod test_util;
 
-use rectangle::Rectangle;
-use screen_capture::{Capture, Resolution};
-
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
-/// Capture specification, if `match_*` is populated and matches the resolution's value it will be
-/// considered to match and the capture will be setup according to the other fields.
+/// Capture specification, if the `match_*`, `min_*` and `max_*` fields that are populated all match
+/// the resolution it will be considered to match and the capture will be setup according to the
+/// other fields. See [`capture`] for the matching.
 #[derive(Debug, PartialEq, Serialize, Deserialize, Default, Copy, Clone)]
 pub struct CaptureSpecification {
     /// The resolution's width to match to.
@@ -35,6 +71,31 @@ pub struct CaptureSpecification {
     /// The resolution's height to match to.
     pub match_height: Option<u32>,
 
+    /// The minimum width of the resolution.
+    #[serde(default)]
+    pub min_width: Option<u32>,
+    /// The maximum width of the resolution.
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    /// The minimum height of the resolution.
+    #[serde(default)]
+    pub min_height: Option<u32>,
+    /// The maximum height of the resolution.
+    #[serde(default)]
+    pub max_height: Option<u32>,
+
+    /// The aspect ratio (width / height) of the resolution to match to, within 1%.
+    #[serde(default)]
+    pub match_aspect_ratio: Option<f32>,
+
+    /// The display whose resolution is matched.
+    #[serde(default)]
+    pub match_display: Option<u32>,
+
+    /// The region to capture as fractions of the resolution, replaces x, y, width and height.
+    #[serde(default)]
+    pub region: Option<capture::Region>,
+
     #[serde(default)]
     /// The x offset to apply for this specification.
     pub x: u32,
@@ -54,15 +115,191 @@ pub struct CaptureSpecification {
     pub display: u32,
 }
 
+/// Power budget for the led string, see [`lights::PowerBudget`].
+#[derive(Debug, PartialEq, Serialize, Deserialize, Copy, Clone)]
+pub struct PowerBudgetConfig {
+    /// Current in mA drawn by a single channel (r, g or b) at full intensity.
+    pub ma_per_channel: f32,
+
+    /// Current in mA drawn by each led regardless of its color.
+    #[serde(default)]
+    pub idle_ma_per_led: f32,
+
+    /// The current in mA the power supply can deliver to the led string.
+    pub budget_ma: f32,
+}
+
+impl From<PowerBudgetConfig> for lights::PowerBudget {
+    fn from(c: PowerBudgetConfig) -> lights::PowerBudget {
+        lights::PowerBudget {
+            ma_per_channel: c.ma_per_channel,
+            idle_ma_per_led: c.idle_ma_per_led,
+            budget_ma: c.budget_ma,
+        }
+    }
+}
+
+/// Criteria to find the serial port if the port is `auto`, see [`lights::PortMatcher`]. Unset
+/// fields match anything, the vendor and product id default to those of the firmware.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PortMatchConfig {
+    /// The USB vendor id.
+    #[serde(default = "default_port_vid")]
+    pub vid: Option<u16>,
+
+    /// The USB product id.
+    #[serde(default = "default_port_pid")]
+    pub pid: Option<u16>,
+
+    /// The USB serial number, must be equal.
+    #[serde(default)]
+    pub serial_number: Option<String>,
+
+    /// The USB product string, must be contained in the device's product string.
+    #[serde(default)]
+    pub product: Option<String>,
+}
+
+fn default_port_vid() -> Option<u16> {
+    Some(lights::PortMatcher::FIRMWARE_VID)
+}
+
+fn default_port_pid() -> Option<u16> {
+    Some(lights::PortMatcher::FIRMWARE_PID)
+}
+
+impl Default for PortMatchConfig {
+    fn default() -> Self {
+        PortMatchConfig {
+            vid: default_port_vid(),
+            pid: default_port_pid(),
+            serial_number: None,
+            product: None,
+        }
+    }
+}
+
+impl From<PortMatchConfig> for lights::PortMatcher {
+    fn from(c: PortMatchConfig) -> lights::PortMatcher {
+        lights::PortMatcher {
+            vid: c.vid,
+            pid: c.pid,
+            serial_number: c.serial_number,
+            product: c.product,
+        }
+    }
+}
+
+/// One contiguous segment of the strip driven by its own microcontroller, see [`Config::segments`].
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SegmentConfig {
+    /// The serial port of the segment's microcontroller, like [`Config::port`].
+    pub port: String,
+
+    /// Criteria to find the serial port with if the port is `auto`.
+    #[serde(default)]
+    pub port_match: Option<PortMatchConfig>,
+
+    /// The number of leds in this segment.
+    pub leds: usize,
+}
+
+impl SegmentConfig {
+    /// The criteria to find the port with, None if the port is specified by name.
+    pub fn port_matcher(&self) -> Option<lights::PortMatcher> {
+        port_matcher(&self.port, &self.port_match)
+    }
+}
+
+fn port_matcher(port: &str, port_match: &Option<PortMatchConfig>) -> Option<lights::PortMatcher> {
+    if port == Config::PORT_AUTO {
+        Some(port_match.clone().unwrap_or_default().into())
+    } else {
+        None
+    }
+}
+
+/// Gamma the microcontroller applies to each channel, see [`lights::Config`]. The power budget
+/// estimates the current from the gamma corrected values.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Copy, Clone)]
+pub struct GammaConfig {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl Default for GammaConfig {
+    /// The gamma of the firmware's defaults.
+    fn default() -> Self {
+        let config = lights::Config::default();
+        GammaConfig {
+            r: config.gamma_r,
+            g: config.gamma_g,
+            b: config.gamma_b,
+        }
+    }
+}
+
+impl From<GammaConfig> for lights::Config {
+    fn from(c: GammaConfig) -> lights::Config {
+        lights::Config {
+            gamma_r: c.r,
+            gamma_g: c.g,
+            gamma_b: c.b,
+            ..Default::default()
+        }
+    }
+}
+
+fn default_limiting_factor() -> f32 {
+    1.0
+}
+
+fn default_leds() -> usize {
+    DisplayLight::MAX_LEDS
+}
+
+fn default_unchanged_frame_pixel_stride() -> u32 {
+    1
+}
+
+fn default_full_refresh_interval() -> f32 {
+    lights::Lights::FULL_REFRESH_INTERVAL.as_secs_f32()
+}
+
+fn default_profile_rules_release() -> f32 {
+    5.0
+}
+
+fn default_resume_gap() -> f32 {
+    clock::GapDetector::DEFAULT_THRESHOLD.as_secs_f32()
+}
+
 /// Configuration struct, specifying all the configurable properties of the displaylight struct..
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
 pub struct Config {
     /// The update rate at which the loop should run in Hz.
     pub rate: f32,
 
-    /// The serial port path or name used to control the leds. Like "/dev/ttyACM0" or "COM5".
+    /// The serial port path or name used to control the leds. Like "/dev/ttyACM0" or "COM5". If
+    /// set to `auto`, the port is found using [`Config::port_match`].
     pub port: String,
 
+    /// Criteria to find the serial port with if the port is `auto`.
+    #[serde(default)]
+    pub port_match: Option<PortMatchConfig>,
+
+    /// The number of leds along the edges of the screen, the zones are laid out for it, see
+    /// [`zones::Layout::for_leds`].
+    #[serde(default = "default_leds")]
+    pub leds: usize,
+
+    /// The strip split over multiple microcontrollers, each driving a contiguous segment of the
+    /// leds in order, adding up to [`Config::leds`]. Replaces [`Config::port`] if not empty, see
+    /// [`lights::Segments`].
+    #[serde(default)]
+    pub segments: Vec<SegmentConfig>,
+
     /// The depth in pixels of the vertical cells at the top and bottom of the screen.
     pub vertical_depth: u32,
 
@@ -91,226 +328,638 @@ pub struct Config {
     /// Allowed edge change (pixels) in vertical direction per second.
     pub edge_vertical_change_per_s: f32,
 
-    /// The limiting factor for the overall led brightness.
+    /// The limiting factor for the overall led brightness, every frame is scaled by it before the
+    /// power budget is applied.
+    #[serde(default = "default_limiting_factor")]
     pub limiting_factor: f32,
 
+    /// Optional power budget, only frames that would exceed this budget are scaled down.
+    #[serde(default)]
+    pub power_budget: Option<PowerBudgetConfig>,
+
+    /// The gamma the microcontroller applies, sent to it when connecting.
+    #[serde(default)]
+    pub gamma: GammaConfig,
+
+    /// Optional adaptive brightness, scales the leds based on the frame's average picture level.
+    #[serde(default)]
+    pub adaptive_brightness: Option<brightness::AdaptiveBrightnessConfig>,
+
+    /// Optional minimum glow floor, the leds never drop below this. The floor color is also shown
+    /// while the capture has failed or is being re-established.
+    #[serde(default)]
+    pub floor: Option<color::FloorConfig>,
+
+    /// Run capture, processing and output each on their own thread. Sequential is lighter on
+    /// low-power machines, threaded avoids a slow serial write delaying the capture. Not supported
+    /// with multiple outputs, validation rejects it and it is ignored with a warning.
+    #[serde(default)]
+    pub threaded: bool,
+
+    /// Skip border detection and sampling if the captured frame is identical to the previous one.
+    #[serde(default)]
+    pub unchanged_frame_skip: bool,
+
+    /// Compare every n'th pixel to determine whether a frame is unchanged, 1 compares all pixels.
+    #[serde(default = "default_unchanged_frame_pixel_stride")]
+    pub unchanged_frame_pixel_stride: u32,
+
+    /// Interval in seconds at which all leds are sent, otherwise only the changed leds are sent.
+    #[serde(default = "default_full_refresh_interval")]
+    pub full_refresh_interval: f32,
+
+    /// Optional path to a per led brightness compensation map, see [`compensation::CompensationMap`].
+    #[serde(default)]
+    pub compensation_map: Option<String>,
+
+    /// Optional address to serve the metrics on in the Prometheus text format, like
+    /// `127.0.0.1:9898`, see [`metrics::serve`].
+    #[serde(default)]
+    pub metrics_address: Option<String>,
+
+    /// What to show on the leds on exit, see [`exit::ExitBehavior`].
+    #[serde(default)]
+    pub exit: exit::ExitBehavior,
+
+    /// If the loop didn't run for this many seconds, like after suspending the machine, or the
+    /// system time jumped by more than that, the state of the stages is reset and the lights are
+    /// resynchronised. Zero disables this.
+    #[serde(default = "default_resume_gap")]
+    pub resume_gap: f32,
+
+    /// The display whose resolution the capture specifications are matched against, this is the
+    /// display `match_display` compares. It is captured in full if no specification matches.
+    #[serde(default)]
+    pub display: u32,
+
     /// A list of capture specifications, the first one to match will be used.
     pub capture: Vec<CaptureSpecification>,
+
+    /// Regions of multiple displays to place side by side for one strip spanning them, replaces
+    /// [`Config::capture`] if not empty. See [`shared_capture`].
+    #[serde(default)]
+    pub span: Vec<capture::SpanPart>,
+
+    /// Named profiles, each overriding any subset of the fields above, see [`profiles`].
+    #[serde(default)]
+    pub profiles: std::collections::BTreeMap<String, serde_yaml::Value>,
+
+    /// The active profile, None uses the fields as they are.
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// Rules to switch the profile automatically based on the detected content, see
+    /// [`auto_profile`].
+    #[serde(default)]
+    pub profile_rules: Vec<auto_profile::ProfileRule>,
+
+    /// Seconds no rule must match before the configured profile is used again.
+    #[serde(default = "default_profile_rules_release")]
+    pub profile_rules_release: f32,
+
+    /// Time of day keyframes for the limiting factor, color temperature and profile, see
+    /// [`schedule`].
+    #[serde(default)]
+    pub schedule: Option<schedule::ScheduleConfig>,
+
+    /// Outputs served from this process, each overriding any subset of the fields above, see
+    /// [`outputs`]. Empty uses the fields as they are for a single output.
+    #[serde(default)]
+    pub outputs: Vec<serde_yaml::Value>,
 }
 
-/// Iterates through the specs to find the best one, augmends the missing or 0 values and returns it.
-/// See the documentation of [`CaptureSpecification`] for further information.
-fn get_config(width: u32, height: u32, specs: &[CaptureSpecification]) -> CaptureSpecification {
-    for spec in specs.iter() {
-        let mut matches = true;
-        if let Some(match_width) = spec.match_width {
-            matches &= match_width == width;
-        }
-        if let Some(match_height) = spec.match_height {
-            matches &= match_height == height;
-        }
-        if !matches {
-            continue;
-        }
+impl Config {
+    /// The value of [`Config::port`] that enables finding the port automatically.
+    pub const PORT_AUTO: &'static str = "auto";
 
-        // We found the best match, copy this and populate it as best we can.
-        let mut populated: CaptureSpecification = *spec;
-        populated.width = if populated.width == 0 {
-            width - populated.x
-        } else {
-            populated.width
-        };
-        populated.height = if populated.height == 0 {
-            height - populated.y
+    /// The criteria to find the port with, None if the port is specified by name.
+    pub fn port_matcher(&self) -> Option<lights::PortMatcher> {
+        port_matcher(&self.port, &self.port_match)
+    }
+
+    /// Load a config file on top of the built-in defaults and validate it, the file only needs
+    /// to hold the fields that differ. See [`layers::ConfigLoader`] for all layers.
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        layers::ConfigLoader::new().file(path).load()
+    }
+
+    /// The config with the fields of the active profile applied, see [`profiles::apply`].
+    pub fn effective(&self) -> Result<Config, Box<dyn Error>> {
+        profiles::apply(self, self.profile.as_deref())
+    }
+
+    /// Check that the values are usable, the error lists every problem found, see [`validation`].
+    pub fn validate(&self) -> Result<(), validation::Problems> {
+        let problems = validation::check(self);
+        if problems.is_empty() {
+            Ok(())
         } else {
-            populated.height
-        };
-        return populated;
+            Err(validation::Problems(problems))
+        }
     }
 
-    // No capture match found... well, return some sane default then.
-    CaptureSpecification {
-        width,
-        height,
-        ..Default::default()
+    /// The name of the port, finding it if the port is `auto`.
+    pub fn port_name(&self) -> Result<String, Box<dyn Error>> {
+        match self.port_matcher() {
+            Some(matcher) => lights::find_port(&matcher),
+            None => Ok(self.port.clone()),
+        }
     }
 }
 
 /// DisplayLight object that will perform the loop to check the screen, analyse and update the leds.
 pub struct DisplayLight {
+    /// The config as provided, the active profile is applied to make the effective config.
+    base: Config,
     config: Config,
-    grabber: Option<Box<dyn Capture>>,
-    lights: lights::Lights,
+    /// The pipeline of each output, see [`outputs`].
+    pipelines: Vec<pipeline::Pipeline>,
+    /// The capture shared by the outputs, None if there is a single output.
+    shared_capture: Option<shared_capture::SharedCapture>,
     limiter: rate_limiter::Limiter,
+    stop: pipeline::StopHandle,
+
+    /// The lights of each output, shared with the pipeline's sinks such that they can be
+    /// reconfigured. Empty if the pipeline wasn't made from the config.
+    lights: Vec<Arc<Mutex<lights::Segments>>>,
+    watcher: Option<reload::ConfigWatcher>,
+    switch: profiles::ProfileSwitch,
+    /// The profile switched to while running, kept when the config is reloaded.
+    switched_profile: Option<Option<String>>,
+    /// Requests profile switches on the switch based on the events.
+    auto_profile: Arc<Mutex<auto_profile::AutoProfile>>,
+    /// Requests profile switches on the switch based on the time of day.
+    schedule: schedule::Schedule,
+    /// While running threaded the changes to the pipeline and limiter, they are applied between
+    /// two frames. None otherwise.
+    running: Option<pipeline::Change>,
 }
 
 impl DisplayLight {
-    const MAX_LEDS: usize = 228;
+    /// The number of leds in the original string, the default of [`Config::leds`]. Each led
+    /// corresponds to one zone.
+    pub const MAX_LEDS: usize = 228;
+
+    /// Instantiate a new instance using the provided configuration, with a pipeline and lights for
+    /// each of its outputs. The serial ports don't need to be present, they are (re)connected to
+    /// whenever they become available. The compensation maps are loaded, failure to load them is
+    /// returned. The active profile is applied.
+    pub fn new(base: Config) -> Result<DisplayLight, Box<dyn Error>> {
+        let config = base.effective()?;
+        let output_configs = outputs::configs(&config)?;
+        if config.threaded && output_configs.len() > 1 {
+            warn!(
+                "Ignoring threaded, the {} outputs share the capture and run on one thread.",
+                output_configs.len()
+            );
+        }
+
+        // Multiple outputs share the capture and the metrics.
+        let shared_capture = (output_configs.len() > 1).then(shared_capture::SharedCapture::new);
+        let registry = metrics::Registry::default();
+        let schedule = schedule::Schedule::default();
+        let mut pipelines = vec![];
+        let mut all_lights = vec![];
+        for output_config in output_configs.iter() {
+            let mut pipeline = match shared_capture.as_ref() {
+                Some(shared) => pipeline::Pipeline::for_output(
+                    output_config,
+                    shared,
+                    registry.clone(),
+                    &schedule,
+                )?,
+                None => pipeline::Pipeline::from_config(output_config)?,
+            };
+            let lights = Arc::new(Mutex::new(DisplayLight::make_lights(output_config)));
+            pipeline.sinks.push(Box::new(lights.clone()));
+            pipelines.push(pipeline);
+            all_lights.push(lights);
+        }
+
+        if let Some(address) = config.metrics_address.as_ref() {
+            metrics::serve(pipelines[0].metrics(), address)?;
+        }
+
+        let first = pipelines.remove(0);
+        let mut d = DisplayLight::from_pipeline(config, first);
+        d.pipelines.append(&mut pipelines);
+        d.shared_capture = shared_capture;
+        d.base = base;
+        d.lights = all_lights;
+        Ok(d)
+    }
+
+    /// Create the lights for the port or segments as specified by the config of an output, with the
+    /// power budget, gamma and refresh interval set. The ports are connected to right away, and
+    /// reconnected to later if that fails.
+    pub fn make_lights(config: &Config) -> lights::Segments {
+        let open = |port: &str, matcher: Option<lights::PortMatcher>| match matcher {
+            Some(matcher) => lights::Lights::new_reconnecting_matching(matcher),
+            None => lights::Lights::new_reconnecting(port),
+        };
+        let segments = if config.segments.is_empty() {
+            vec![(open(&config.port, config.port_matcher()), config.leds)]
+        } else {
+            config
+                .segments
+                .iter()
+                .map(|s| (open(&s.port, s.port_matcher()), s.leds))
+                .collect()
+        };
+        let mut lights = lights::Segments::new(segments);
+        DisplayLight::configure_lights(&mut lights, config);
+        lights
+    }
 
-    /// Instantiate a new instance using the provided configuration. This will try to connect to
-    /// the serial port immediately and returns failure if that doesn't succeed.
-    pub fn new(config: Config) -> Result<DisplayLight, Box<dyn Error>> {
-        Ok(DisplayLight {
+    fn configure_lights(lights: &mut lights::Segments, config: &Config) {
+        lights.set_power_budget(config.power_budget.map(|v| v.into()));
+        if let Err(e) = lights.set_config(&config.gamma.into()) {
+            warn!("Failed to send the config to the lights: {e}");
+        }
+        lights.set_full_refresh_interval(std::time::Duration::from_secs_f32(
+            config.full_refresh_interval.max(0.0),
+        ));
+    }
+
+    /// Instantiate a new instance with a custom pipeline, only the rate and threading of the
+    /// configuration are used. The config is used as is, its active profile is not applied.
+    pub fn from_pipeline(config: Config, pipeline: pipeline::Pipeline) -> DisplayLight {
+        let switch = profiles::ProfileSwitch::default();
+        let auto_profile = Arc::new(Mutex::new(auto_profile::AutoProfile::new(
+            &config.profile_rules,
+            config.profile.as_deref(),
+            std::time::Duration::from_secs_f32(config.profile_rules_release.max(0.0)),
+        )));
+        let auto_profile_events = auto_profile.clone();
+        let auto_switch = switch.clone();
+        pipeline.events().subscribe(move |e| {
+            if let Some(profile) = auto_profile_events.lock().unwrap().observe(e) {
+                auto_switch.request(profile.as_deref());
+            }
+        });
+
+        // Share the schedule with the brightness filter, such that both follow the same clock.
+        let schedule = pipeline.schedule();
+        schedule.set_config(config.schedule.clone().unwrap_or_default());
+        DisplayLight {
             limiter: rate_limiter::Limiter::new(config.rate),
-            lights: lights::Lights::new(&config.port)?,
+            pipelines: vec![pipeline],
+            shared_capture: None,
+            base: config.clone(),
             config,
-            grabber: None,
-        })
+            stop: Default::default(),
+            lights: vec![],
+            watcher: None,
+            switch,
+            switched_profile: None,
+            auto_profile,
+            schedule,
+            running: None,
+        }
     }
 
-    fn setup(&mut self) {
-        self.lights.set_limit_factor(self.config.limiting_factor);
+    /// The pipeline that is run, that of the first output if there are multiple.
+    pub fn pipeline(&self) -> &pipeline::Pipeline {
+        &self.pipelines[0]
     }
 
-    /// Enter the main loop, this function will never return.
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
-        // Perform one time setup.
-        self.setup();
+    /// The pipeline that is run, stages can be replaced or added before running. That of the
+    /// first output if there are multiple.
+    pub fn pipeline_mut(&mut self) -> &mut pipeline::Pipeline {
+        &mut self.pipelines[0]
+    }
 
-        // Create the canvas, container of current led pixels to be updated or reused.
-        let mut canvas = [lights::RGB::default(); DisplayLight::MAX_LEDS];
+    /// The pipelines of all outputs, in the order of the config's outputs.
+    pub fn pipelines(&self) -> &[pipeline::Pipeline] {
+        &self.pipelines
+    }
 
-        // Sampler only updates based on the black border detection, cache it such that we can reuse
-        // it.
-        let mut cached_sampler: Option<(Rectangle, sampler::Sampler)> = None;
+    /// The pipelines of all outputs, stages can be replaced or added before running.
+    pub fn pipelines_mut(&mut self) -> &mut [pipeline::Pipeline] {
+        &mut self.pipelines
+    }
 
-        // Border change rate limiter, to avoid flickering.
-        let mut border_rate_limiter = border_detection::RectangleChangeLimiter::new(
-            self.config.edge_horizontal_change_per_s,
-            self.config.edge_vertical_change_per_s,
-        );
+    /// Handle to stop [`DisplayLight::run`], this can be used from another thread.
+    pub fn stop_handle(&self) -> pipeline::StopHandle {
+        self.stop.clone()
+    }
+
+    /// Handle to the time spent in each of the stages, this can be inspected while running. Those
+    /// of the first output if there are multiple.
+    pub fn timings(&self) -> pipeline::Timings {
+        self.pipeline().timings()
+    }
+
+    /// Handle to subscribe to the events, like processed frames, border changes and capture or
+    /// output failures. Those of the first output if there are multiple, the automatic profile
+    /// switching follows these as well.
+    pub fn events(&self) -> events::Events {
+        self.pipeline().events()
+    }
+
+    /// The registry holding the metrics, like the frame count, stage durations and failure counts.
+    /// These are the totals over all outputs.
+    pub fn metrics(&self) -> metrics::Registry {
+        self.pipeline().metrics()
+    }
+
+    /// The achieved rate and timing jitter of the loop, see [`rate_limiter::Limiter::stats`].
+    pub fn limiter_stats(&self) -> rate_limiter::LimiterStats {
+        self.limiter.stats()
+    }
+
+    /// The config currently in use, with the active profile applied.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The active profile, None if no profile is used.
+    pub fn profile(&self) -> Option<&str> {
+        self.base.profile.as_deref()
+    }
+
+    /// Switch to the named profile, None uses the config without a profile. Only the stages
+    /// affected by the change are replaced, such that the switch is seamless. On failure the
+    /// current profile is kept. While running use [`DisplayLight::profile_switch`] instead.
+    pub fn set_profile(&mut self, profile: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let mut base = self.base.clone();
+        base.profile = profile.map(str::to_owned);
+        self.reconfigure(base)?;
+        self.switched_profile = Some(profile.map(str::to_owned));
+        Ok(())
+    }
+
+    /// Handle to switch the profile while running, this can be used from another thread. The
+    /// switch is applied before the next frame, failures are logged.
+    pub fn profile_switch(&self) -> profiles::ProfileSwitch {
+        self.switch.clone()
+    }
+
+    /// Use a different clock for the schedule, like a [`clock::ManualClock`] to test it. This
+    /// applies to both the profile switching and the brightness of the outputs.
+    pub fn set_schedule_clock(&mut self, clock: Box<dyn clock::Clock>) {
+        self.schedule.set_clock(clock);
+    }
+
+    /// Request a profile switch if the scheduled profile changed.
+    fn poll_schedule(&mut self) {
+        if let Some(profile) = self.schedule.profile_change() {
+            self.switch.request(profile.as_deref());
+        }
+    }
+
+    /// Apply a requested profile switch, if any.
+    fn poll_profile_switch(&mut self) {
+        let Some(profile) = self.switch.take() else {
+            return;
+        };
+        let name = profile.as_deref().unwrap_or("none");
+        match self.set_profile(profile.as_deref()) {
+            Ok(()) => info!("Switched to profile '{}'.", name),
+            Err(e) => warn!(
+                "Keeping the current profile, switching to '{}' failed: {}",
+                name, e
+            ),
+        }
+    }
+
+    /// Watch the files of the loader while running, on a change all layers are loaded again and
+    /// applied with [`DisplayLight::reconfigure`]. A config that fails to load is logged and the
+    /// current config is kept.
+    pub fn watch_config(&mut self, loader: layers::ConfigLoader) {
+        self.watcher = Some(reload::ConfigWatcher::new(loader));
+    }
 
-        // The resolution is used for the capture setup and config retrieval, store the old value.
-        let mut cached_resolution: Option<Resolution> = None;
-
-        let mut consecutive_capture_fails: usize = 0;
-        loop {
-            // If the grabber isn't setup yet, try to set it up.
-            if self.grabber.is_none() {
-                let grabber = screen_capture::capture();
-                // Ensure we also clear the cached resolution, such that we actually prepare the capture again.
-                cached_resolution = None;
-                match grabber {
-                    Ok(g) => self.grabber = Some(g),
-                    Err(e) => {
-                        println!("Setting up grabber failed: {e:?}");
-                        self.limiter.sleep();
-                        continue;
+    /// Apply a new config without restarting, with its active profile. If the pipelines were made
+    /// from the config the stages that changed are replaced, the lights are reconfigured and
+    /// reopened if the port changed. The rate is always applied. On failure the current config is
+    /// kept. Changes to the metrics address require a restart, changing the number of outputs is
+    /// an error.
+    pub fn reconfigure(&mut self, base: Config) -> Result<(), Box<dyn Error>> {
+        base.validate()?;
+        let config = base.effective()?;
+        if !self.lights.is_empty() {
+            let output_configs = outputs::configs(&config)?;
+            let previous_configs = outputs::configs(&self.config)?;
+            if output_configs.len() != previous_configs.len() {
+                return Err(format!(
+                    "Changing the number of outputs from {} to {} requires a restart",
+                    previous_configs.len(),
+                    output_configs.len()
+                )
+                .into());
+            }
+            // Prepare all outputs first, such that either all or none of them change. The stages
+            // were made for the previous configs.
+            let prepared = previous_configs
+                .iter()
+                .zip(output_configs.iter())
+                .map(|(previous, config)| {
+                    pipeline::Prepared::new(Some(previous), config, &self.schedule)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            for (i, (output_config, prepared)) in output_configs.iter().zip(prepared).enumerate() {
+                match self.running.as_mut() {
+                    Some(running) => {
+                        *running = std::mem::take(running).merge(pipeline::Change {
+                            prepared: Some(prepared),
+                            rate: None,
+                        });
                     }
+                    None => self.pipelines[i].apply(prepared),
+                }
+                let previous = &previous_configs[i];
+                let mut lights = self.lights[i].lock().unwrap();
+                if output_config.port != previous.port
+                    || output_config.port_match != previous.port_match
+                    || output_config.segments != previous.segments
+                {
+                    info!("Port changed, reopening the lights.");
+                    *lights = DisplayLight::make_lights(output_config);
+                } else {
+                    DisplayLight::configure_lights(&mut lights, output_config);
                 }
             }
-            let grabber = self.grabber.as_mut().unwrap();
-
-            // First, check if the resolution of the desktop environment has changed, if so, act.
-            let current_resolution = grabber.resolution();
-            if cached_resolution.is_none()
-                || *cached_resolution.as_ref().unwrap() != current_resolution
-            {
-                let width = current_resolution.width;
-                let height = current_resolution.height;
-
-                // Resolution has changed, figure out the best match in our configurations and
-                // prepare the capture accordingly.
-                let config = get_config(width, height, &self.config.capture);
-
-                if let Err(e) = grabber.prepare_capture(
-                    config.display,
-                    config.x,
-                    config.y,
-                    config.width,
-                    config.height,
-                ) {
-                    println!("Failed preparing capture {e:?}");
-                    self.grabber = None;
-                    continue;
-                };
-                // Store the current resolution.
-                cached_resolution = Some(current_resolution);
+        }
+        // Keep the clock and the profile last switched to, only the keyframes change.
+        let schedule = config.schedule.clone().unwrap_or_default();
+        if schedule != self.schedule.config() {
+            self.schedule.set_config(schedule);
+        }
+        if config.rate != self.config.rate {
+            match self.running.as_mut() {
+                Some(running) => running.rate = Some(config.rate),
+                None => self.limiter = rate_limiter::Limiter::new(config.rate),
             }
+        }
+        if config.metrics_address != self.config.metrics_address {
+            warn!("Changing the metrics address requires a restart.");
+        }
+        self.base = base;
+        self.config = config;
+        Ok(())
+    }
 
-            // Now, we are ready to try and get the image:
-            let res = grabber.capture_image();
-            if let Err(e) = res {
-                consecutive_capture_fails += 1;
-                if consecutive_capture_fails > 10 {
-                    println!("Got 10 consecutive capture fails, resetting grabber; {e:?}");
-                    self.grabber = None;
-                    consecutive_capture_fails = 0;
+    /// Apply the config if the watched file changed.
+    fn poll_config(&mut self) {
+        if let Some(reloaded) = self.watcher.as_mut().and_then(|w| w.poll()) {
+            self.apply_reloaded(reloaded);
+        }
+    }
+
+    /// Apply a config loaded from the watched file, logging why if it can't be applied.
+    fn apply_reloaded(&mut self, reloaded: Result<Config, Box<dyn Error>>) {
+        let path = match self.watcher.as_ref() {
+            Some(watcher) => watcher.description(),
+            None => return,
+        };
+        // A profile switched to while running takes precedence over the one in the files, the
+        // rules return to the one in the files.
+        let switched_profile = self.switched_profile.clone();
+        let mut rules = None;
+        let reloaded = reloaded.map(|mut config| {
+            rules = Some((
+                config.profile_rules.clone(),
+                config.profile.clone(),
+                config.profile_rules_release,
+            ));
+            if let Some(profile) = switched_profile {
+                config.profile = profile;
+            }
+            config
+        });
+        match reloaded.and_then(|config| self.reconfigure(config)) {
+            Ok(()) => {
+                if let Some((rules, default, release)) = rules {
+                    self.auto_profile.lock().unwrap().configure(
+                        &rules,
+                        default.as_deref(),
+                        std::time::Duration::from_secs_f32(release.max(0.0)),
+                    );
                 }
-                // Getting the image failed... :( Lets wait a bit and try again.
-                // Lets keep the leds at the old color. May make failures less noticable, but uac on windows doesn't
-                // look ugly when we can't grab the image for a while.
-                self.lights.set_leds(&canvas)?;
-                self.limiter.sleep();
-                continue;
+                info!("Reloaded config from {}.", path)
             }
+            Err(e) => warn!(
+                "Keeping the current config, reloading {} failed: {}",
+                path, e
+            ),
+        }
+    }
 
-            // Then, we can grab the actual image.
-            let img = grabber.image();
-            if let Err(e) = img {
-                self.lights.set_leds(&canvas)?;
-                self.limiter.sleep();
-                consecutive_capture_fails += 1;
-                println!("Failed to retrieve {consecutive_capture_fails} images, error: {e:?}");
-                continue;
+    /// Process exactly one frame for each output, without sleeping. All outputs are processed,
+    /// the first error is returned.
+    pub fn step(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(shared) = self.shared_capture.as_ref() {
+            shared.next_frame();
+        }
+        let mut res = Ok(());
+        for pipeline in self.pipelines.iter_mut() {
+            let step = pipeline.step();
+            if res.is_ok() {
+                res = step;
             }
-            consecutive_capture_fails = 0;
-            let img = img.unwrap();
-
-            // Detect the black borders if we are configured to do so.
-            let borders = if self.config.edge_detection_enable {
-                border_detection::find_borders(
-                    &*img,
-                    self.config.edge_detection_bisect_count,
-                    self.config.edge_detection_rectangular_only,
-                )
-            } else {
-                Some(Rectangle {
-                    x_min: 0,
-                    y_min: 0,
-                    x_max: img.width() - 1,
-                    y_max: img.height() - 1,
-                })
-            };
+        }
+        res
+    }
 
-            // Border size changed, make a new sampler.
-            if let Some(mut borders) = borders {
-                // First update, force the border rate change.
-                if cached_sampler.is_none() {
-                    border_rate_limiter.set(&borders, &std::time::Instant::now());
-                }
-                border_rate_limiter.update(&borders, &std::time::Instant::now());
-                borders = border_rate_limiter.rectangle();
-
-                if cached_sampler.is_none() || cached_sampler.as_ref().unwrap().0 != borders {
-                    // println!("Borders: {:?}", borders);
-                    // With the edges known, we can make the zones.
-                    let zones = zones::Zones::make_zones(
-                        &borders,
-                        self.config.horizontal_depth,
-                        self.config.vertical_depth,
-                    );
-                    // println!("zones: {:?}", zones);
-                    assert_eq!(zones.len(), DisplayLight::MAX_LEDS);
-
-                    // With the zones known, we can create the sampler.
-                    let sampler = sampler::Sampler::make_sampler(
-                        &zones,
-                        self.config.sample_pixel_distance,
-                        self.config.sample_diagonalize_points,
-                    );
-                    cached_sampler = Some((borders, sampler));
-                }
+    /// Check all outputs for a gap in time, see [`pipeline::Pipeline::check_gap`].
+    fn check_gap(&mut self) -> Option<std::time::Duration> {
+        let mut gap = None;
+        for pipeline in self.pipelines.iter_mut() {
+            gap = pipeline.check_gap().or(gap);
+        }
+        gap
+    }
+
+    /// Enter the main loop, this returns when stopped through the [`DisplayLight::stop_handle`].
+    /// Errors writing the leds are logged and the loop continues, the lights reconnect if the
+    /// device went away. A profile switch or config reload is applied between two frames.
+    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        while !self.stop.is_stopped() {
+            // The outputs share the capture, they can only run on one thread, warned about in new.
+            if self.config.threaded && self.pipelines.len() == 1 {
+                self.run_threaded()?;
+                continue;
+            }
+            // After a gap the schedule would try to catch up, start it from now.
+            if self.check_gap().is_some() {
+                self.limiter.reset();
             }
+            // Errors writing the leds are logged and emitted by the pipeline, the sinks may recover.
+            let _ = self.step();
+            self.limiter.sleep();
+            self.poll_config();
+            self.poll_schedule();
+            self.poll_profile_switch();
+        }
+        Ok(())
+    }
 
-            // With the sampler, we can now sample and get color values.
-            let sampler = &cached_sampler.as_ref().unwrap().1;
-            sampler.sample_into(&*img, &mut canvas);
+    /// Run the threaded pipeline until stopped or until a config change turns threaded off. The
+    /// config, schedule and profile switch are polled before each capture, changes are handed to
+    /// the running threads, see [`pipeline::Pipeline::run_threaded`].
+    fn run_threaded(&mut self) -> Result<(), Box<dyn Error>> {
+        // The threads borrow the pipeline and limiter, reconfigure collects the changes for them.
+        let mut pipeline = self.pipelines.remove(0);
+        let mut limiter = std::mem::replace(
+            &mut self.limiter,
+            rate_limiter::Limiter::new(self.config.rate),
+        );
+        let run_stop = pipeline::StopHandle::default();
+        self.running = Some(Default::default());
+        let res = pipeline.run_threaded(&mut limiter, &run_stop, || {
+            self.poll_config();
+            self.poll_schedule();
+            self.poll_profile_switch();
+            if self.stop.is_stopped() || !self.config.threaded {
+                run_stop.stop();
+            }
+            self.running
+                .replace(Default::default())
+                .filter(|change| !change.is_empty())
+        });
+        self.running = None;
+        self.pipelines.insert(0, pipeline);
+        self.limiter = limiter;
+        res
+    }
 
-            // And, finally, we can set the leds to those colors.
-            self.lights.set_leds(&canvas)?;
+    /// Perform the configured exit behavior on the leds of all outputs and close them, like the
+    /// serial ports. Call this after [`DisplayLight::run`] returned.
+    pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        let frames: Vec<Vec<Vec<lights::RGB>>> = self
+            .pipelines
+            .iter()
+            .map(|p| {
+                let leds = p.config().map_or(DisplayLight::MAX_LEDS, |c| c.leds);
+                let last = p
+                    .last_leds()
+                    .map(|v| v.to_vec())
+                    .unwrap_or_else(|| vec![Default::default(); leds]);
+                self.config.exit.frames(&last, self.config.rate)
+            })
+            .collect();
+        let count = frames.iter().map(Vec::len).max().unwrap_or(0);
+        let mut res = Ok(());
+        for i in 0..count {
             self.limiter.sleep();
+            for (pipeline, frames) in self.pipelines.iter_mut().zip(frames.iter()) {
+                if let Some(leds) = frames.get(i) {
+                    if let Err(e) = pipeline.write(leds) {
+                        res = Err(e);
+                    }
+                }
+            }
+            if res.is_err() {
+                break;
+            }
         }
+        // Dropping the sinks closes them, the lights are shared with our handles to them.
+        for pipeline in self.pipelines.iter_mut() {
+            pipeline.sinks.clear();
+        }
+        self.lights.clear();
+        res
     }
 }
 
@@ -360,7 +1009,7 @@ mod tests {
             .expect("Should succeed.");
 
         // With the edges known, we can make the zones.
-        let zones = zones::Zones::make_zones(&b, 200, 200);
+        let zones = zones::Zones::make_zones(&b, &Default::default(), 200, 200);
         assert_eq!(zones.len(), 228);
 
         // With the zones known, we can create the sampler.
@@ -401,6 +1050,195 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_port_match_config() {
+        // Omitted fields default to the firmware's ids.
+        let port_match: PortMatchConfig =
+            serde_yaml::from_str("serial_number: TEST").expect("Should parse.");
+        let config = Config {
+            port: "auto".to_owned(),
+            port_match: Some(port_match),
+            ..Default::default()
+        };
+        let matcher = config.port_matcher().expect("Port is auto.");
+        assert_eq!(matcher.vid, Some(lights::PortMatcher::FIRMWARE_VID));
+        assert_eq!(matcher.pid, Some(lights::PortMatcher::FIRMWARE_PID));
+        assert_eq!(matcher.serial_number, Some("TEST".to_owned()));
+
+        let match_config: PortMatchConfig =
+            serde_yaml::from_str("vid: 0x1234\npid: ~\nproduct: Serial").expect("Should parse.");
+        assert_eq!(match_config.vid, Some(0x1234));
+        assert_eq!(match_config.pid, None);
+        assert_eq!(match_config.product, Some("Serial".to_owned()));
+
+        let config = Config {
+            port: "/dev/ttyACM0".to_owned(),
+            ..Default::default()
+        };
+        assert!(config.port_matcher().is_none());
+        assert_eq!(config.port_name().expect("Named port."), "/dev/ttyACM0");
+    }
+
+    #[test]
+    fn test_outputs() {
+        let loader = layers::ConfigLoader::new()
+            .set("outputs=[{port: /nonexistent/left, capture: [{region: {width: 0.5}}]}, {port: /nonexistent/right, limiting_factor: 0.25}]")
+            .unwrap()
+            .set("floor={color: {r: 20, g: 0, b: 0}, level: 1.0}")
+            .unwrap();
+        let mut d = DisplayLight::new(loader.load().unwrap()).expect("Ports are opened lazily.");
+        assert_eq!(d.pipelines().len(), 2);
+        // Without a screen both outputs are unavailable, but still show their standby color.
+        d.step().expect("Writes are dropped while disconnected.");
+        assert!(d.pipelines().iter().all(|p| p.last_leds().is_some()));
+
+        // Fields of an output can change while running, the number of outputs can't.
+        let mut config = d.config().clone();
+        config.outputs[1] = serde_yaml::from_str("{port: /nonexistent/right}").unwrap();
+        d.reconfigure(config.clone()).expect("Should apply.");
+
+        // If one output fails, none of them change.
+        let mut failing = config.clone();
+        failing.outputs = vec![
+            serde_yaml::from_str(
+                "{port: /nonexistent/left, floor: {color: {r: 0, g: 20, b: 0}, level: 1.0}}",
+            )
+            .unwrap(),
+            serde_yaml::from_str(
+                "{port: /nonexistent/right, compensation_map: /nonexistent/map.yaml}",
+            )
+            .unwrap(),
+        ];
+        assert!(d.reconfigure(failing).is_err());
+        d.step().expect("Writes are dropped while disconnected.");
+        assert_eq!(d.pipelines()[0].last_leds().unwrap()[0].r, 20);
+
+        config.outputs.pop();
+        assert!(d.reconfigure(config).is_err());
+        assert_eq!(d.config().outputs.len(), 2);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let loader = layers::ConfigLoader::new()
+            .set("port=/nonexistent/port")
+            .unwrap();
+        let mut d = DisplayLight::new(loader.load().unwrap()).expect("Ports are opened lazily.");
+        let lights = Arc::downgrade(&d.lights[0]);
+        d.shutdown()
+            .expect("Writes are dropped while disconnected.");
+        // Nothing holds on to the lights anymore, so the port is closed.
+        assert!(lights.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_threaded_profile_switch() {
+        struct Blue;
+        impl pipeline::Source for Blue {
+            fn acquire(&mut self) -> pipeline::Acquired {
+                let img = RasterImageBGR::filled(400, 300, BGR { r: 0, g: 0, b: 200 });
+                pipeline::Acquired::Image(Box::new(img))
+            }
+        }
+        let config = layers::ConfigLoader::new()
+            .set("port=/nonexistent/port")
+            .unwrap()
+            .set("threaded=true")
+            .unwrap()
+            .set("limiting_factor=1.0")
+            .unwrap()
+            .set("profiles={dim: {limiting_factor: 0.5, rate: 100}}")
+            .unwrap()
+            .load()
+            .expect("Should be valid.");
+        let mut d = DisplayLight::new(config).expect("Ports are opened lazily.");
+        d.pipeline_mut().source = Box::new(Blue);
+        let blue = Arc::new(Mutex::new(vec![]));
+        let seen = blue.clone();
+        d.events().subscribe(move |e| {
+            if let events::Event::FrameProcessed(leds) = e {
+                seen.lock().unwrap().push(leds[0].b);
+            }
+        });
+
+        // Switch while the threads are running, the frames keep coming.
+        let switch = d.profile_switch();
+        let stop = d.stop_handle();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(150));
+            switch.request(Some("dim"));
+            std::thread::sleep(std::time::Duration::from_millis(150));
+            stop.stop();
+        });
+        d.run().expect("Should succeed.");
+        t.join().unwrap();
+
+        assert_eq!(d.profile(), Some("dim"));
+        assert_eq!(d.config().rate, 100.0);
+        assert_eq!(d.pipeline().config().unwrap().limiting_factor, 0.5);
+        let blue = blue.lock().unwrap();
+        let switched = blue
+            .iter()
+            .position(|b| *b == 100)
+            .expect("Should be dimmed.");
+        assert!(switched > 0);
+        assert!(blue[..switched].iter().all(|b| *b == 200));
+        assert!(blue[switched..].iter().all(|b| *b == 100));
+    }
+
+    #[test]
+    fn test_set_profile() {
+        let config = layers::ConfigLoader::new()
+            .set("profiles={dim: {limiting_factor: 0.1, rate: 30}}")
+            .unwrap()
+            .load()
+            .expect("Should be valid.");
+        let pipeline = pipeline::Pipeline::new(
+            Box::new(stages::ScreenCaptureSource::new(&[], Default::default())),
+            Box::new(stages::BlackBorderDetector::new(&config)),
+            Box::new(stages::EdgeZones {
+                layout: Default::default(),
+                horizontal_depth: 10,
+                vertical_depth: 10,
+            }),
+            Box::new(stages::GridSampler::new(5, false)),
+        );
+        let mut d = DisplayLight::from_pipeline(config.clone(), pipeline);
+        assert_eq!(d.profile(), None);
+
+        d.set_profile(Some("dim")).expect("Should switch.");
+        assert_eq!(d.profile(), Some("dim"));
+        assert_eq!(d.config().limiting_factor, 0.1);
+        assert_eq!(d.config().rate, 30.0);
+
+        // Unknown profiles keep the current one, switching while running goes through the handle.
+        assert!(d.set_profile(Some("missing")).is_err());
+        assert_eq!(d.profile(), Some("dim"));
+        d.profile_switch().request(None);
+        d.poll_profile_switch();
+        assert_eq!(d.profile(), None);
+        assert_eq!(d.config().limiting_factor, config.limiting_factor);
+        // The schedule switches the profile at its keyframes, starting at 12:00.
+        let mut scheduled = config.clone();
+        scheduled.schedule = serde_yaml::from_str(
+            "{utc_offset: 0.0, keyframes: [{time: '08:00', profile: ~}, {time: '20:00', profile: dim}]}",
+        )
+        .unwrap();
+        d.reconfigure(scheduled).expect("Should be valid.");
+        let clock = clock::ManualClock::at(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(12 * 3600),
+        );
+        d.set_schedule_clock(Box::new(clock.clone()));
+        d.poll_schedule();
+        d.poll_profile_switch();
+        assert_eq!(d.profile(), None);
+        clock.advance(std::time::Duration::from_secs(9 * 3600));
+        d.poll_schedule();
+        d.poll_profile_switch();
+        assert_eq!(d.profile(), Some("dim"));
+        assert!(d.config().schedule.is_some());
+    }
+
     #[test]
     fn test_config() {
         let spec1: CaptureSpecification = CaptureSpecification {
@@ -412,7 +1250,12 @@ mod tests {
             ..Default::default()
         };
         let specs = [spec1, spec2];
-        let res = get_config(3840, 1080, &specs);
+        let screen = capture::Screen {
+            width: 3840,
+            height: 1080,
+            display: 0,
+        };
+        let res = capture::explain(&screen, &specs).specification;
         assert_eq!(res.x, 1920);
         assert_eq!(res.width, 3840 - 1920);
         assert_eq!(res.height, 1080);
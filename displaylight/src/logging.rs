@@ -0,0 +1,307 @@
+//! Leveled logging to stderr and optionally as JSON lines to a file, for long running sessions.
+//!
+//! The filter is a comma separated list of a default level and `target=level` pairs, like
+//! `info,lights=debug,displaylight::stages=trace`. A target matches itself and its submodules, the
+//! longest matching target determines the level.
+//!
+//! Key/value fields of a record, like `debug!(latency_ms = 4.2; "Stage timings")`, are appended
+//! to the line on stderr and written as fields of the JSON object.
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The environment variable holding the filter, used if no verbosity is given on the command line.
+pub const ENV_FILTER: &str = "DISPLAYLIGHT_LOG";
+
+/// Determines the level per target.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// Create a filter with one level for all targets.
+    pub fn new(default: LevelFilter) -> Self {
+        Filter {
+            default,
+            targets: vec![],
+        }
+    }
+
+    /// Parse a filter like `info,lights=debug`, the default level is info if omitted.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut filter = Filter::new(LevelFilter::Info);
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let parse_level = |v: &str| {
+                v.parse::<LevelFilter>()
+                    .map_err(|_| format!("Invalid log level '{}' in '{}'", v, spec))
+            };
+            match part.split_once('=') {
+                Some((target, level)) => filter
+                    .targets
+                    .push((target.trim().to_owned(), parse_level(level.trim())?)),
+                None => filter.default = parse_level(part)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    /// The level for the provided target.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(t, _)| {
+                target == t
+                    || (target.starts_with(t.as_str()) && target[t.len()..].starts_with("::"))
+            })
+            .max_by_key(|(t, _)| t.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// The most verbose level of any target.
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+/// Logger writing to stderr and optionally JSON lines to a file.
+pub struct Logger {
+    filter: Filter,
+    file: Option<Mutex<LineWriter<File>>>,
+}
+
+impl Logger {
+    /// Create a logger, appending JSON lines to the file if provided.
+    pub fn new(filter: Filter, json_file: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let file = match json_file {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|ref e| format!("Failed to open log file '{}': {}", path, e))?;
+                Some(Mutex::new(LineWriter::new(file)))
+            }
+            None => None,
+        };
+        Ok(Logger { filter, file })
+    }
+
+    /// Install this logger as the global logger.
+    pub fn init(self) -> Result<(), Box<dyn Error>> {
+        log::set_max_level(self.filter.max_level());
+        log::set_boxed_logger(Box::new(self))?;
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = timestamp(SystemTime::now());
+        let message = record.args().to_string();
+        let mut fields = Fields::default();
+        // Only fails if the visitor does, ours doesn't.
+        let _ = record.key_values().visit(&mut fields);
+        let text: String = fields
+            .0
+            .iter()
+            .map(|f| format!(" {}={}", f.key, f.text))
+            .collect();
+        eprintln!(
+            "{} {:<5} {}: {}{}",
+            time,
+            record.level(),
+            record.target(),
+            message,
+            text
+        );
+        if let Some(file) = self.file.as_ref() {
+            let line = json_line(
+                &time,
+                record.level().as_str(),
+                record.target(),
+                &message,
+                &fields.0,
+            );
+            // Nowhere to report failing to log, drop the line.
+            let _ = writeln!(file.lock().unwrap(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.as_ref() {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// A key/value field of a record, the value formatted as text and as JSON.
+#[derive(Debug, PartialEq, Clone)]
+struct Field {
+    key: String,
+    text: String,
+    json: String,
+}
+
+/// Collects the key/value fields of a record.
+#[derive(Debug, Default)]
+struct Fields(Vec<Field>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let text = value.to_string();
+        // Numbers and booleans are written as is, anything else as a string.
+        let number = value.to_i64().is_some()
+            || value.to_u64().is_some()
+            || value.to_f64().is_some_and(f64::is_finite);
+        let json = if number || value.to_bool().is_some() {
+            text.clone()
+        } else {
+            format!("\"{}\"", json_escape(&text))
+        };
+        self.0.push(Field {
+            key: key.to_string(),
+            text,
+            json,
+        });
+        Ok(())
+    }
+}
+
+/// Format the time as an RFC 3339 timestamp in UTC with milliseconds.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+
+    // Convert days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Escape a string for use in JSON.
+fn json_escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// Make one JSON object for a log record, the fields follow the message.
+fn json_line(time: &str, level: &str, target: &str, message: &str, fields: &[Field]) -> String {
+    let fields: String = fields
+        .iter()
+        .map(|f| format!(r#","{}":{}"#, json_escape(&f.key), f.json))
+        .collect();
+    format!(
+        r#"{{"time":"{}","level":"{}","target":"{}","message":"{}"{}}}"#,
+        json_escape(time),
+        json_escape(level),
+        json_escape(target),
+        json_escape(message),
+        fields
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse("warn, lights=debug,displaylight::stages=trace").unwrap();
+        assert_eq!(filter.level("displaylight"), LevelFilter::Warn);
+        assert_eq!(filter.level("lights"), LevelFilter::Debug);
+        assert_eq!(filter.level("lights::power"), LevelFilter::Debug);
+        assert_eq!(filter.level("lightsaber"), LevelFilter::Warn);
+        assert_eq!(filter.level("displaylight::stages"), LevelFilter::Trace);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        assert_eq!(Filter::parse("").unwrap(), Filter::new(LevelFilter::Info));
+        assert_eq!(
+            Filter::parse("DEBUG").unwrap(),
+            Filter::new(LevelFilter::Debug)
+        );
+        assert!(Filter::parse("lights=loud").is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(timestamp(time), "2023-11-14T22:13:20.123Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+
+        assert_eq!(
+            json_line("t", "INFO", "lights", "Port \"COM5\"\n\u{1}", &[]),
+            r#"{"time":"t","level":"INFO","target":"lights","message":"Port \"COM5\"\n\u0001"}"#
+        );
+    }
+
+    #[test]
+    fn test_fields() {
+        let pairs: [(&str, &dyn kv::ToValue); 5] = [
+            ("latency_ms", &4.5),
+            ("frames", &600),
+            ("reconnected", &true),
+            ("port", &"COM5"),
+            ("gain", &f64::NAN),
+        ];
+        let record = Record::builder().key_values(&pairs).build();
+        let mut fields = Fields::default();
+        record.key_values().visit(&mut fields).unwrap();
+        assert_eq!(fields.0[0].text, "4.5");
+        assert_eq!(fields.0[3].text, "COM5");
+        assert_eq!(
+            json_line("t", "DEBUG", "displaylight", "Timings", &fields.0),
+            r#"{"time":"t","level":"DEBUG","target":"displaylight","message":"Timings","latency_ms":4.5,"frames":600,"reconnected":true,"port":"COM5","gain":"NaN"}"#
+        );
+    }
+}
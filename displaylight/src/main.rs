@@ -1,6 +1,14 @@
+use displaylight::capture;
+use displaylight::compensation::CompensationMap;
+use displaylight::layers::ConfigLoader;
+use displaylight::logging;
+use displaylight::outputs;
+use displaylight::validation;
+use displaylight::zones::Layout;
 use displaylight::{Config, DisplayLight};
+use log::info;
 use std::error::Error;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 extern crate clap;
 use clap::{App, Arg, SubCommand};
@@ -11,67 +19,318 @@ fn main() -> Result<(), Box<dyn Error>> {
         .arg(
             Arg::with_name("config")
                 .short("c")
-                .help("Override the config, instead of autoselecting based on the OS.."),
+                .long("config")
+                .takes_value(true)
+                .help(
+                    "Config file to load, its fields override those of the system and user \
+                     config files.",
+                ),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Override a field like 'limiting_factor=0.3' or 'power_budget.budget_ma=2000'."),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .short("p")
+                .long("profile")
+                .takes_value(true)
+                .help("The profile to use, overrides the profile selected by the config."),
+        )
+        .arg(
+            Arg::with_name("print_config")
+                .long("print-config")
+                .help("Print the effective config, with all layers merged, and quit."),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .multiple(true)
+                .help("Increase the log verbosity, overrides the DISPLAYLIGHT_LOG filter."),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .multiple(true)
+                .help("Decrease the log verbosity, overrides the DISPLAYLIGHT_LOG filter."),
+        )
+        .arg(
+            Arg::with_name("log_file")
+                .long("log-file")
+                .takes_value(true)
+                .help("Append the log as JSON lines to this file, with the fields of each record."),
         )
         .subcommand(
             SubCommand::with_name("list_ports").about("List serial ports / com ports and quit."),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about(
+                    "Check a config file on top of the built-in defaults and report every problem, \
+                     exits non-zero on errors.",
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("The config file to check."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("explain-capture")
+                .about(
+                    "Show which capture specification of the config matches a resolution and why \
+                     the others don't.",
+                )
+                .arg(
+                    Arg::with_name("width")
+                        .required(true)
+                        .help("The width of the resolution."),
+                )
+                .arg(
+                    Arg::with_name("height")
+                        .required(true)
+                        .help("The height of the resolution."),
+                )
+                .arg(
+                    Arg::with_name("display")
+                        .short("d")
+                        .long("display")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("The display the resolution belongs to."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compensation-map")
+                .about(
+                    "Light all leds of an output uniformly to create a compensation map. Writes a \
+                     starting map with dimmed corners if the file doesn't exist, else shows the \
+                     existing map's gains for tuning.",
+                )
+                .arg(
+                    Arg::with_name("file").help(
+                        "The compensation map file to create or show, defaults to the \
+                         compensation_map of the output.",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("The index of the output whose leds to light."),
+                )
+                .arg(
+                    Arg::with_name("level")
+                        .short("l")
+                        .long("level")
+                        .takes_value(true)
+                        .default_value("128")
+                        .help("The intensity (0-255) to light the leds with."),
+                ),
         );
 
     let matches = app.clone().get_matches();
+    setup_logging(&matches)?;
 
     if let Some(_matches) = matches.subcommand_matches("list_ports") {
         println!("Ports:\n{:#?}", lights::available_ports()?);
         return Ok(());
     }
 
-    // Determine what config to load.
-    let mut config_path: Option<PathBuf> = None;
-    if let Some(v_in) = matches.value_of("config") {
-        let path = Path::new(v_in);
-        if path.exists() {
-            config_path = Some(path.to_path_buf())
+    if let Some(matches) = matches.subcommand_matches("check-config") {
+        let path = matches.value_of("file").expect("File is required.");
+        if !check_config(Path::new(path)) {
+            std::process::exit(1);
         }
-    } else {
-        // No config specified, try to find it in the current directories' config folder.
-        // This is mostly just for development conveniency.
-        let path = PathBuf::from("config").join(format!("{}.yaml", std::env::consts::OS));
+        return Ok(());
+    }
+
+    // Determine the layers to load the config from.
+    let mut loader = ConfigLoader::new().standard_files();
+    if let Some(path) = matches.value_of("config") {
+        loader = loader.file(Path::new(path));
+    }
+    loader = loader.environment(std::env::vars());
+    for assignment in matches.values_of("set").into_iter().flatten() {
+        loader = loader.set(assignment)?;
+    }
+    if let Some(profile) = matches.value_of("profile") {
+        loader = loader.set(&format!("profile={}", profile))?;
+    }
+    for path in loader.files() {
         if path.exists() {
-            config_path = Some(path);
+            info!("Config: {}", path.display());
         }
     }
+    let config = loader.load()?;
+
+    if matches.is_present("print_config") {
+        print!("{}", serde_yaml::to_string(&config.effective()?)?);
+        return Ok(());
+    }
 
-    let mut config: Config = Default::default();
-    if config_path.is_some() {
-        let path = config_path
-            .as_ref()
-            .unwrap()
-            .to_str()
-            .expect("Path is always valid.");
-        println!("Config: {}", path);
-
-        use std::fs::File;
-        use std::io::Read;
-        match File::open(path) {
-            Ok(mut file) => {
-                let mut content = String::new();
-                file.read_to_string(&mut content)
-                    .expect("Should be able to read the file.");
-                match serde_yaml::from_str(&content) {
-                    Ok(parsed_config) => config = parsed_config,
-                    Err(failure_message) => {
-                        println!("Something went wrong parsing the configuration file:");
-                        return Err(Box::new(failure_message));
-                    }
-                }
-            }
-            Err(error) => {
-                return Err(Box::new(error));
-            }
+    if let Some(matches) = matches.subcommand_matches("compensation-map") {
+        return compensation_map(&config, matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("explain-capture") {
+        let value = |name| {
+            matches
+                .value_of(name)
+                .expect("Required or defaulted.")
+                .parse()
+        };
+        let screen = capture::Screen {
+            width: value("width")?,
+            height: value("height")?,
+            display: value("display")?,
+        };
+        println!(
+            "{}",
+            capture::explain(&screen, &config.effective()?.capture)
+        );
+        return Ok(());
+    }
+
+    let mut d = DisplayLight::new(config)?;
+    d.watch_config(loader);
+
+    // Stop the loop on the first signal, such that the exit behavior is performed. Exit right away
+    // on the second one.
+    let stop = d.stop_handle();
+    ctrlc::set_handler(move || {
+        if stop.is_stopped() {
+            std::process::exit(1);
         }
+        info!("Stopping...");
+        stop.stop();
+    })?;
+
+    d.run()?;
+    d.shutdown()
+}
+
+/// Setup the logger, the verbosity flags take precedence over the environment variable.
+fn setup_logging(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let verbose = matches.occurrences_of("verbose") as i64;
+    let quiet = matches.occurrences_of("quiet") as i64;
+    let filter = if verbose > 0 || quiet > 0 {
+        // Info by default, each -v or -q moves one level.
+        let levels = [
+            log::LevelFilter::Off,
+            log::LevelFilter::Error,
+            log::LevelFilter::Warn,
+            log::LevelFilter::Info,
+            log::LevelFilter::Debug,
+            log::LevelFilter::Trace,
+        ];
+        let index = (3 + verbose - quiet).clamp(0, levels.len() as i64 - 1);
+        logging::Filter::new(levels[index as usize])
     } else {
-        println!("No config specified or found.");
+        match std::env::var(logging::ENV_FILTER) {
+            Ok(spec) => logging::Filter::parse(&spec)?,
+            Err(_) => logging::Filter::new(log::LevelFilter::Info),
+        }
+    };
+    logging::Logger::new(filter, matches.value_of("log_file"))?.init()
+}
+
+/// Check the config file, printing the problems found. Returns whether it is valid.
+fn check_config(path: &Path) -> bool {
+    let parsed = ConfigLoader::new()
+        .file(path)
+        .merged()
+        .and_then(|v| Ok((serde_yaml::from_value(v.clone())?, v)));
+    let (config, merged): (Config, _) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            println!("{}", e);
+            return false;
+        }
+    };
+    let mut problems = validation::check_unknown(&merged, &config);
+    problems.append(&mut validation::check(&config));
+    for problem in problems.iter() {
+        println!("{}: {}", path.display(), problem);
+    }
+    if problems.is_empty() {
+        println!("{}: ok", path.display());
     }
+    problems.is_empty()
+}
 
-    let mut d = DisplayLight::new(config)?;
-    d.run()
+/// Light all leds of an output uniformly with the compensation map applied, creating a starting
+/// map for its layout if needed. The lights are made like [`DisplayLight`] makes them, with the
+/// effective config of the output.
+fn compensation_map(config: &Config, matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let level: u8 = matches
+        .value_of("level")
+        .expect("Level has a default.")
+        .parse()?;
+    let index: usize = matches
+        .value_of("output")
+        .expect("Output has a default.")
+        .parse()?;
+
+    let mut output_configs = outputs::configs(&config.effective()?)?;
+    if index >= output_configs.len() {
+        return Err(format!(
+            "Output {} doesn't exist, there are {} outputs.",
+            index,
+            output_configs.len()
+        )
+        .into());
+    }
+    let output = output_configs.swap_remove(index);
+    let path = match matches.value_of("file") {
+        Some(path) => path.to_owned(),
+        None => output
+            .compensation_map
+            .clone()
+            .ok_or("No file provided and the output has no compensation_map.")?,
+    };
+
+    let map = if Path::new(&path).exists() {
+        println!("Showing existing compensation map: {}", path);
+        let map = CompensationMap::load(&path)?;
+        if map.gains.len() != output.leds {
+            println!(
+                "The map has {} gains for the {} leds of the output.",
+                map.gains.len(),
+                output.leds
+            );
+        }
+        map
+    } else {
+        let map = CompensationMap::for_layout(&Layout::for_leds(output.leds));
+        map.save(&path)?;
+        println!("Wrote starting compensation map: {}", path);
+        map
+    };
+
+    let mut leds = vec![
+        lights::RGB {
+            r: level,
+            g: level,
+            b: level,
+        };
+        output.leds
+    ];
+    map.apply(&mut leds);
+
+    let mut lights = DisplayLight::make_lights(&output);
+    for (segment, _) in lights.segments_mut() {
+        if segment.connection_state() != lights::ConnectionState::Connected {
+            return Err("Failed to connect to the lights of the output.".into());
+        }
+    }
+    lights.set_leds(&leds)?;
+    println!("Adjust the gains in the file and run this again to inspect the result.");
+    Ok(())
 }
@@ -0,0 +1,311 @@
+//! A registry of counters and histograms, readable through the library and exposable in the
+//! Prometheus text format over a local HTTP endpoint, see [`serve`].
+use log::{info, warn};
+
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Bucket upper bounds in seconds, suitable for the durations of the stages.
+pub const DURATION_BUCKETS: [f64; 12] = [
+    0.0001, 0.0005, 0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0,
+];
+
+/// A monotonically increasing counter, this can be cloned and shared.
+#[derive(Debug, Default, Clone)]
+pub struct Counter {
+    value: Arc<AtomicU64>,
+}
+
+impl Counter {
+    /// Increment the counter by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Increment the counter by the provided amount.
+    pub fn add(&self, amount: u64) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// The current value.
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// The state of a histogram at some point in time.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HistogramSnapshot {
+    /// The upper bounds of the buckets.
+    pub bounds: Vec<f64>,
+    /// The number of observations in each bucket, not cumulative. The last entry holds the
+    /// observations above the largest bound.
+    pub counts: Vec<u64>,
+    /// The sum of all observations.
+    pub sum: f64,
+    /// The number of observations.
+    pub count: u64,
+}
+
+impl HistogramSnapshot {
+    /// The mean of the observations, zero if there are none.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+}
+
+/// Histogram counting observations in buckets, this can be cloned and shared.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    data: Arc<Mutex<HistogramSnapshot>>,
+}
+
+impl Histogram {
+    /// Create a histogram with the provided ascending bucket upper bounds.
+    pub fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            data: Arc::new(Mutex::new(HistogramSnapshot {
+                bounds: bounds.to_vec(),
+                counts: vec![0; bounds.len() + 1],
+                sum: 0.0,
+                count: 0,
+            })),
+        }
+    }
+
+    /// Record an observation.
+    pub fn observe(&self, value: f64) {
+        let mut data = self.data.lock().unwrap();
+        let index = data
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(data.bounds.len());
+        data.counts[index] += 1;
+        data.sum += value;
+        data.count += 1;
+    }
+
+    /// Record a duration in seconds.
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    /// The current state.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Counter),
+    Histogram(Histogram),
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    help: String,
+    metric: Metric,
+}
+
+/// Registry holding the metrics by name, this can be cloned and shared.
+#[derive(Debug, Default, Clone)]
+pub struct Registry {
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl Registry {
+    /// Get the counter with this name, creating it if it doesn't exist yet.
+    ///
+    /// Panics if a histogram with this name exists.
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        let metric = self.get_or_insert(name, help, || Metric::Counter(Default::default()));
+        match metric {
+            Metric::Counter(c) => c,
+            Metric::Histogram(_) => panic!("Metric {} is not a counter.", name),
+        }
+    }
+
+    /// Get the histogram with this name, creating it with the provided bounds if it doesn't exist
+    /// yet.
+    ///
+    /// Panics if a counter with this name exists.
+    pub fn histogram(&self, name: &str, help: &str, bounds: &[f64]) -> Histogram {
+        let metric = self.get_or_insert(name, help, || Metric::Histogram(Histogram::new(bounds)));
+        match metric {
+            Metric::Histogram(h) => h,
+            Metric::Counter(_) => panic!("Metric {} is not a histogram.", name),
+        }
+    }
+
+    fn get_or_insert(&self, name: &str, help: &str, make: impl FnOnce() -> Metric) -> Metric {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter().find(|e| e.name == name) {
+            return entry.metric.clone();
+        }
+        let metric = make();
+        entries.push(Entry {
+            name: name.to_owned(),
+            help: help.to_owned(),
+            metric: metric.clone(),
+        });
+        metric
+    }
+
+    /// The value of the counter with this name, None if there is no such counter.
+    pub fn counter_value(&self, name: &str) -> Option<u64> {
+        let entries = self.entries.lock().unwrap();
+        match entries.iter().find(|e| e.name == name).map(|e| &e.metric) {
+            Some(Metric::Counter(c)) => Some(c.get()),
+            _ => None,
+        }
+    }
+
+    /// The state of the histogram with this name, None if there is no such histogram.
+    pub fn histogram_snapshot(&self, name: &str) -> Option<HistogramSnapshot> {
+        let entries = self.entries.lock().unwrap();
+        match entries.iter().find(|e| e.name == name).map(|e| &e.metric) {
+            Some(Metric::Histogram(h)) => Some(h.snapshot()),
+            _ => None,
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let entries = self.entries.lock().unwrap();
+        let mut res = String::new();
+        for entry in entries.iter() {
+            res += &format!("# HELP {} {}\n", entry.name, entry.help);
+            match &entry.metric {
+                Metric::Counter(c) => {
+                    res += &format!("# TYPE {} counter\n", entry.name);
+                    res += &format!("{} {}\n", entry.name, c.get());
+                }
+                Metric::Histogram(h) => {
+                    let s = h.snapshot();
+                    res += &format!("# TYPE {} histogram\n", entry.name);
+                    let mut cumulative = 0;
+                    for (bound, count) in s.bounds.iter().zip(s.counts.iter()) {
+                        cumulative += count;
+                        res +=
+                            &format!("{}_bucket{{le=\"{}\"}} {}\n", entry.name, bound, cumulative);
+                    }
+                    res += &format!("{}_bucket{{le=\"+Inf\"}} {}\n", entry.name, s.count);
+                    res += &format!("{}_sum {}\n", entry.name, s.sum);
+                    res += &format!("{}_count {}\n", entry.name, s.count);
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Serve the metrics over HTTP on the provided address, like `127.0.0.1:9898`, at `/metrics`.
+/// This spawns a thread that handles the requests one at a time, binding failures are returned.
+pub fn serve(registry: Registry, address: &str) -> Result<std::net::SocketAddr, Box<dyn Error>> {
+    let listener = TcpListener::bind(address)
+        .map_err(|ref e| format!("Failed to serve metrics on '{}': {}", address, e))?;
+    let local = listener.local_addr()?;
+    info!("Serving metrics on http://{}/metrics", local);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Metrics connection failed: {}", e);
+                    continue;
+                }
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+            let mut request_line = String::new();
+            if BufReader::new(&stream)
+                .read_line(&mut request_line)
+                .is_err()
+            {
+                continue;
+            }
+            let path = request_line.split_whitespace().nth(1).unwrap_or("");
+            let (status, body) = if path == "/metrics" {
+                ("200 OK", registry.render())
+            } else {
+                ("404 Not Found", "Metrics are at /metrics\n".to_owned())
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    Ok(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_metrics() {
+        let registry = Registry::default();
+        let counter = registry.counter("frames_total", "Frames.");
+        counter.inc();
+        registry.counter("frames_total", "Frames.").add(2);
+        assert_eq!(registry.counter_value("frames_total"), Some(3));
+        assert_eq!(registry.counter_value("missing"), None);
+
+        let h = registry.histogram("write_seconds", "Writes.", &[0.1, 1.0]);
+        h.observe(0.05);
+        h.observe(0.1);
+        h.observe_duration(Duration::from_millis(500));
+        h.observe(5.0);
+        let s = registry
+            .histogram_snapshot("write_seconds")
+            .expect("Should exist.");
+        assert_eq!(s.counts, vec![2, 1, 1]);
+        assert_eq!(s.count, 4);
+        assert!((s.mean() - 5.65 / 4.0).abs() < 1e-9);
+
+        assert_eq!(
+            registry.render(),
+            "# HELP frames_total Frames.\n\
+             # TYPE frames_total counter\n\
+             frames_total 3\n\
+             # HELP write_seconds Writes.\n\
+             # TYPE write_seconds histogram\n\
+             write_seconds_bucket{le=\"0.1\"} 2\n\
+             write_seconds_bucket{le=\"1\"} 3\n\
+             write_seconds_bucket{le=\"+Inf\"} 4\n\
+             write_seconds_sum 5.65\n\
+             write_seconds_count 4\n"
+        );
+    }
+
+    #[test]
+    fn test_serve() {
+        let registry = Registry::default();
+        registry.counter("frames_total", "Frames.").inc();
+        let address = serve(registry, "127.0.0.1:0").expect("Should bind.");
+
+        let mut stream = std::net::TcpStream::connect(address).expect("Should connect.");
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("frames_total 1\n"));
+    }
+}
@@ -0,0 +1,98 @@
+//! Multiple outputs served from one process, each with its own capture, layout, port and processing.
+//!
+//! Each entry of `outputs` overrides any subset of the config's fields for that output, like:
+//! ```yaml
+//! outputs:
+//!   - {port: /dev/ttyACM0, capture: [{region: {width: 0.5}}]}
+//!   - {port: /dev/ttyACM1, capture: [{region: {x: 0.5}}], limiting_factor: 0.3}
+//! ```
+//! The fields are merged like those of a [`crate::profiles`] profile, on top of the config with the
+//! active profile applied. Fields that apply to the process as a whole, listed in
+//! [`PROCESS_FIELDS`], can't be set per output. The outputs capture through one
+//! [`crate::shared_capture::SharedCapture`], such that each display is captured once per frame
+//! regardless of the number of outputs. Without outputs the config itself is the only output.
+use crate::Config;
+use serde_yaml::Value;
+
+use std::error::Error;
+
+/// The fields that apply to the process as a whole.
+pub const PROCESS_FIELDS: [&str; 11] = [
+    "outputs",
+    "profile",
+    "profiles",
+    "profile_rules",
+    "profile_rules_release",
+    "schedule",
+    "rate",
+    "threaded",
+    "metrics_address",
+    "resume_gap",
+    "exit",
+];
+
+/// Return the config of the output at this index, with its fields applied. The result is not
+/// validated.
+pub fn apply(config: &Config, index: usize) -> Result<Config, Box<dyn Error>> {
+    let overrides = config
+        .outputs
+        .get(index)
+        .ok_or_else(|| format!("There is no output {}", index))?;
+    let Value::Mapping(mapping) = overrides else {
+        return Err(format!("Output {} must be a mapping of fields", index).into());
+    };
+    for key in PROCESS_FIELDS {
+        if mapping.contains_key(&Value::String(key.to_owned())) {
+            return Err(format!("Output {} can't set '{}'", index, key).into());
+        }
+    }
+    let mut base = config.clone();
+    base.outputs.clear();
+    let mut merged = serde_yaml::to_value(&base)?;
+    crate::layers::merge(&mut merged, overrides.clone());
+    Ok(serde_yaml::from_value(merged)
+        .map_err(|ref e| format!("Invalid output {}: {}", index, e))?)
+}
+
+/// The configs of all outputs, the config itself if there are no outputs.
+pub fn configs(config: &Config) -> Result<Vec<Config>, Box<dyn Error>> {
+    if config.outputs.is_empty() {
+        return Ok(vec![config.clone()]);
+    }
+    (0..config.outputs.len())
+        .map(|i| apply(config, i))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configs() {
+        let config = crate::layers::ConfigLoader::new()
+            .set("outputs=[{port: /dev/ttyACM1, horizontal_depth: 100}, {port: /dev/ttyACM2, capture: [{region: {x: 0.5}}]}]")
+            .unwrap()
+            .load()
+            .expect("Should be valid.");
+        let outputs = configs(&config).expect("Should apply.");
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].port, "/dev/ttyACM1");
+        assert_eq!(outputs[0].horizontal_depth, 100);
+        assert_eq!(outputs[0].capture, config.capture);
+        assert_eq!(outputs[1].vertical_depth, config.vertical_depth);
+        assert_eq!(outputs[1].capture[0].region.map(|r| r.x), Some(0.5));
+        assert!(outputs.iter().all(|o| o.outputs.is_empty()));
+
+        let mut single = config.clone();
+        single.outputs.clear();
+        assert_eq!(configs(&single).unwrap(), vec![single.clone()]);
+
+        let error = crate::layers::ConfigLoader::new()
+            .set("outputs=[{rate: 30}]")
+            .unwrap()
+            .load()
+            .expect_err("Rate is for the whole process.");
+        assert!(error.to_string().contains("outputs[0]"));
+    }
+}
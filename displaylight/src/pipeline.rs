@@ -0,0 +1,1574 @@
+//! A composable pipeline that turns images into led colors.
+//!
+//! The pipeline consists of the following stages, each of which is a trait object that can be
+//! replaced, reordered or extended:
+//!   - [`Source`]; provides the images, like [`crate::stages::ScreenCaptureSource`].
+//!   - [`BorderDetector`]; determines the region of interest in the image.
+//!   - [`ZoneMapper`]; maps the region of interest to one zone per led.
+//!   - [`ZoneSampler`]; samples the zones of the image into led colors.
+//!   - [`ColorFilter`]; any number of filters modifying the led colors.
+//!   - [`Sink`]; any number of outputs for the led colors, like [`lights::Lights`].
+//!
+//! [`Pipeline::step`] processes exactly one frame. The stages can also be split over threads with
+//! [`Pipeline::run_threaded`]; the source up to and including the sampler runs on the calling
+//! thread, because the image is owned by the source and can't be handed to another thread. The
+//! filters and sinks each run on their own thread, linked with [`crate::latest`] channels. The
+//! output thread paces the frames; each tick it requests a new capture and writes the latest
+//! processed frame. A slow serial write then doesn't delay the capture, and a slow capture doesn't
+//! stall the output.
+//!
+//! If the loop didn't run for a while, like after the machine was suspended, the state of all
+//! stages is reset through their `reset` methods, see [`Pipeline::check_gap`].
+use crate::clock::GapDetector;
+use crate::events::{Event, Events};
+use crate::metrics::{Counter, Histogram, Registry, DURATION_BUCKETS};
+use crate::rectangle::Rectangle;
+use crate::schedule::Schedule;
+use crate::shared_capture::{SharedCapture, SharedCaptureSource};
+use crate::{fingerprint, latest, rate_limiter, stages, Config, DisplayLight};
+use lights::RGB;
+use log::{debug, info, warn};
+use screen_capture::ImageBGR;
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Result of acquiring an image from a [`Source`].
+pub enum Acquired {
+    /// An image was acquired.
+    Image(Box<dyn ImageBGR>),
+    /// Acquiring failed, the previous led colors are kept. If it keeps failing the standby color
+    /// is shown, see [`Pipeline::STANDBY_AFTER_FAILURES`].
+    Failed,
+    /// The source is unavailable, the standby color is shown if there is one.
+    Unavailable,
+}
+
+/// Provides the images to be analysed.
+pub trait Source {
+    /// Acquire the next image.
+    fn acquire(&mut self) -> Acquired;
+
+    /// Reset any state after a gap in time, like preparing the capture again.
+    fn reset(&mut self) {}
+}
+
+/// Determines the region of interest in an image.
+pub trait BorderDetector {
+    /// Return the region of interest, None if it can't be determined (yet).
+    fn detect(&mut self, image: &dyn ImageBGR) -> Option<Rectangle>;
+
+    /// Called instead of [`BorderDetector::detect`] if the image is identical to the previous one.
+    /// Returns whether the borders are settled, such that detecting them can be skipped. If not,
+    /// detect is called.
+    fn unchanged(&mut self) -> bool {
+        false
+    }
+
+    /// Reset any state after a gap in time, like the tracking of the borders.
+    fn reset(&mut self) {}
+}
+
+/// Maps the region of interest to the zones, one zone for each led.
+pub trait ZoneMapper {
+    /// Return the zones for the provided region of interest.
+    fn zones(&mut self, borders: &Rectangle) -> Vec<Rectangle>;
+}
+
+/// Samples the zones of an image into led colors.
+pub trait ZoneSampler {
+    /// Prepare sampling the provided zones, called whenever the zones change.
+    fn prepare(&mut self, zones: &[Rectangle]);
+
+    /// Sample the image into the leds, one led for each of the zones.
+    fn sample(&mut self, image: &dyn ImageBGR, leds: &mut [RGB]);
+
+    /// Reset any state after a gap in time.
+    fn reset(&mut self) {}
+}
+
+/// Modifies the led colors, applied in order after sampling.
+pub trait ColorFilter: Send {
+    /// Apply the filter to the leds.
+    fn apply(&mut self, leds: &mut [RGB]);
+
+    /// Reset any state after a gap in time, like smoothing.
+    fn reset(&mut self) {}
+}
+
+/// Receives the final led colors.
+pub trait Sink: Send {
+    /// Write the leds.
+    fn write(&mut self, leds: &[RGB]) -> Result<(), Box<dyn Error>>;
+
+    /// Reset any state after a gap in time, like resending everything to a device that may have
+    /// lost its state.
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// A shared sink, such that it can still be accessed while it is part of the pipeline.
+impl<T: Sink> Sink for Arc<Mutex<T>> {
+    fn write(&mut self, leds: &[RGB]) -> Result<(), Box<dyn Error>> {
+        self.lock().unwrap().write(leds)
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.lock().unwrap().reset()
+    }
+}
+
+/// Duration spent in each of the stages.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StageTimings {
+    /// Time spent capturing, detecting borders and sampling.
+    pub capture: Duration,
+    /// Time spent applying the color filters.
+    pub processing: Duration,
+    /// Time spent writing the leds to the sinks.
+    pub output: Duration,
+    /// Time between the start of the capture and the leds being written.
+    pub latency: Duration,
+}
+
+#[derive(Debug, Default)]
+struct TimingsData {
+    last: StageTimings,
+    average: StageTimings,
+    frames: u64,
+}
+
+/// Handle to the stage timings, this can be cloned and inspected while the loop is running.
+#[derive(Debug, Default, Clone)]
+pub struct Timings {
+    data: Arc<Mutex<TimingsData>>,
+}
+
+impl Timings {
+    /// Weight of a new measurement in the moving average.
+    const ALPHA: f64 = 0.05;
+
+    /// The average timings are logged every this many frames.
+    const LOG_INTERVAL: u64 = 1000;
+
+    /// The timings of the most recent measurements.
+    pub fn last(&self) -> StageTimings {
+        self.data.lock().unwrap().last
+    }
+
+    /// Exponential moving average of the timings.
+    pub fn average(&self) -> StageTimings {
+        self.data.lock().unwrap().average
+    }
+
+    fn update(&self, f: impl Fn(&mut StageTimings) -> &mut Duration, value: Duration) {
+        let mut data = self.data.lock().unwrap();
+        *f(&mut data.last) = value;
+        let average = f(&mut data.average);
+        *average = if average.is_zero() {
+            value
+        } else {
+            average.mul_f64(1.0 - Self::ALPHA) + value.mul_f64(Self::ALPHA)
+        };
+    }
+
+    /// Count a frame written to the sinks, logging the average timings every
+    /// [`Timings::LOG_INTERVAL`] frames.
+    fn frame(&self) {
+        let mut data = self.data.lock().unwrap();
+        data.frames += 1;
+        if data.frames % Self::LOG_INTERVAL == 0 {
+            let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+            let average = data.average;
+            debug!(
+                frames = data.frames,
+                capture_ms = ms(average.capture),
+                processing_ms = ms(average.processing),
+                output_ms = ms(average.output),
+                latency_ms = ms(average.latency);
+                "Average stage timings"
+            );
+        }
+    }
+}
+
+/// Result of the analysis, passed from the capture to the processing.
+#[derive(Debug, Clone)]
+enum Captured {
+    /// Sampled values of a frame.
+    Frame(Vec<RGB>),
+    /// Capturing failed, keep showing the previous frame.
+    Failed,
+    /// Capture is unavailable, show the standby color for this many leds if there is one.
+    Unavailable(usize),
+}
+
+/// Handles to the metrics of the pipeline.
+#[derive(Clone)]
+struct PipelineMetrics {
+    registry: Registry,
+    frames: Counter,
+    border_detection: Histogram,
+    sampling: Histogram,
+    capture: Histogram,
+    output: Histogram,
+    latency: Histogram,
+}
+
+impl PipelineMetrics {
+    /// Register the metrics, the counters of failures and changes are fed from the events.
+    fn new(registry: Registry, events: &Events) -> Self {
+        let histogram = |name, help| registry.histogram(name, help, &DURATION_BUCKETS);
+        let metrics = PipelineMetrics {
+            frames: registry.counter(
+                "displaylight_frames_total",
+                "Number of frames written to the sinks.",
+            ),
+            border_detection: histogram(
+                "displaylight_border_detection_seconds",
+                "Time spent detecting the borders.",
+            ),
+            sampling: histogram(
+                "displaylight_sampling_seconds",
+                "Time spent sampling the zones.",
+            ),
+            capture: histogram(
+                "displaylight_capture_seconds",
+                "Time spent capturing, detecting borders and sampling.",
+            ),
+            output: histogram(
+                "displaylight_output_seconds",
+                "Time spent writing the leds to the sinks, like the serial port.",
+            ),
+            latency: histogram(
+                "displaylight_latency_seconds",
+                "Time between the start of the capture and the leds being written.",
+            ),
+            registry: registry.clone(),
+        };
+
+        let capture_failures = registry.counter(
+            "displaylight_capture_failures_total",
+            "Number of failed captures.",
+        );
+        let capture_resets = registry.counter(
+            "displaylight_capture_resets_total",
+            "Number of times the screen capture was reset.",
+        );
+        let border_changes = registry.counter(
+            "displaylight_border_changes_total",
+            "Number of times the detected borders changed.",
+        );
+        let output_errors = registry.counter(
+            "displaylight_output_errors_total",
+            "Number of failed writes to the sinks.",
+        );
+        let resumes = registry.counter(
+            "displaylight_resumes_total",
+            "Number of times the stages were reset after a gap in time, like a suspend.",
+        );
+        events.subscribe(move |e| match e {
+            Event::CaptureFailed(_) => capture_failures.inc(),
+            Event::CaptureReset => capture_resets.inc(),
+            Event::BordersChanged(_) => border_changes.inc(),
+            Event::OutputError(_) => output_errors.inc(),
+            Event::Resumed(_) => resumes.inc(),
+            _ => {}
+        });
+        metrics
+    }
+}
+
+/// State of the analysis; from the source up to and including the sampling.
+struct Analysis {
+    /// The borders the zones were last made for.
+    borders: Option<Rectangle>,
+
+    /// The sampled values, reused if the frame is unchanged.
+    sampled: Vec<RGB>,
+
+    /// Fingerprint of the previous frame, to detect unchanged frames.
+    previous_fingerprint: Option<u64>,
+
+    /// The number of consecutive failed acquisitions.
+    failures: u32,
+
+    border_detection_time: Histogram,
+    sampling_time: Histogram,
+}
+
+impl Analysis {
+    fn new(metrics: &PipelineMetrics) -> Self {
+        Analysis {
+            borders: None,
+            sampled: vec![RGB::default(); DisplayLight::MAX_LEDS],
+            previous_fingerprint: None,
+            failures: 0,
+            border_detection_time: metrics.border_detection.clone(),
+            sampling_time: metrics.sampling.clone(),
+        }
+    }
+
+    /// Reset the analysis stages after a gap in time, the zones are made again on the next capture.
+    fn reset(
+        &mut self,
+        source: &mut dyn Source,
+        border_detector: &mut dyn BorderDetector,
+        sampler: &mut dyn ZoneSampler,
+    ) {
+        source.reset();
+        border_detector.reset();
+        sampler.reset();
+        self.borders = None;
+        self.previous_fingerprint = None;
+        self.failures = 0;
+    }
+
+    /// Acquire an image and sample it.
+    fn capture(
+        &mut self,
+        source: &mut dyn Source,
+        border_detector: &mut dyn BorderDetector,
+        zone_mapper: &mut dyn ZoneMapper,
+        sampler: &mut dyn ZoneSampler,
+        unchanged_frame_stride: Option<u32>,
+        events: &Events,
+    ) -> Captured {
+        let img = match source.acquire() {
+            Acquired::Image(img) => img,
+            // Keep the previous leds over a brief failure, but not while it keeps failing.
+            Acquired::Failed => {
+                self.failures += 1;
+                if self.failures > Pipeline::STANDBY_AFTER_FAILURES {
+                    return Captured::Unavailable(self.sampled.len());
+                }
+                return Captured::Failed;
+            }
+            Acquired::Unavailable => return Captured::Unavailable(self.sampled.len()),
+        };
+        self.failures = 0;
+
+        // Skip border detection, zone making and sampling if the frame is identical to the previous
+        // one and the borders are settled.
+        let fingerprint = unchanged_frame_stride.map(|s| fingerprint::fingerprint(&*img, s));
+        let unchanged = fingerprint.is_some()
+            && fingerprint == self.previous_fingerprint
+            && self.borders.is_some();
+        self.previous_fingerprint = fingerprint;
+        if unchanged && border_detector.unchanged() {
+            return Captured::Frame(self.sampled.clone());
+        }
+
+        let start = Instant::now();
+        let borders = border_detector.detect(&*img);
+        self.border_detection_time.observe_duration(start.elapsed());
+
+        // Border size changed, make new zones and prepare the sampler.
+        if let Some(borders) = borders {
+            if self.borders != Some(borders) {
+                debug!(
+                    x_min = borders.x_min,
+                    y_min = borders.y_min,
+                    x_max = borders.x_max,
+                    y_max = borders.y_max;
+                    "Borders changed"
+                );
+                events.emit(&Event::BordersChanged(borders));
+                let zones = zone_mapper.zones(&borders);
+                sampler.prepare(&zones);
+                self.sampled.resize(zones.len(), RGB::default());
+                self.borders = Some(borders);
+            }
+        }
+
+        // Without borders ever being found there's nothing to sample yet, keep the old values.
+        if self.borders.is_none() {
+            return Captured::Failed;
+        }
+        let start = Instant::now();
+        sampler.sample(&*img, &mut self.sampled);
+        self.sampling_time.observe_duration(start.elapsed());
+        Captured::Frame(self.sampled.clone())
+    }
+}
+
+/// Apply the filters to the capture result, returns the leds to be shown, None if nothing is to be
+/// shown. The canvas holds the previous result, kept if capturing failed. While the capture is
+/// unavailable the standby color is shown as is, without it the leds are left alone.
+fn process(
+    filters: &mut [Box<dyn ColorFilter>],
+    canvas: &mut Option<Vec<RGB>>,
+    standby: Option<RGB>,
+    captured: Captured,
+    events: &Events,
+) -> Option<Vec<RGB>> {
+    let mut leds = match (captured, standby) {
+        (Captured::Frame(sampled), _) => sampled,
+        (Captured::Failed, _) => return canvas.clone(),
+        (Captured::Unavailable(count), Some(standby)) => {
+            *canvas = Some(vec![standby; count]);
+            return canvas.clone();
+        }
+        (Captured::Unavailable(_), None) => return None,
+    };
+    for filter in filters.iter_mut() {
+        filter.apply(&mut leds);
+    }
+    events.emit(&Event::FrameProcessed(&leds));
+    *canvas = Some(leds);
+    canvas.clone()
+}
+
+/// Write the leds to all sinks, a failing sink doesn't stop the others. Returns the first error.
+fn output(
+    sinks: &mut [Box<dyn Sink>],
+    leds: &[RGB],
+    events: &Events,
+) -> Result<(), Box<dyn Error>> {
+    let mut res = Ok(());
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.write(leds) {
+            warn!("Writing leds failed: {e}");
+            events.emit(&Event::OutputError(e.to_string()));
+            if res.is_ok() {
+                res = Err(e);
+            }
+        }
+    }
+    res
+}
+
+/// Reset the filters after a gap in time.
+fn reset_filters(filters: &mut [Box<dyn ColorFilter>]) {
+    for filter in filters.iter_mut() {
+        filter.reset();
+    }
+}
+
+/// Reset the sinks after a gap in time, failures are logged and emitted like write failures.
+fn reset_sinks(sinks: &mut [Box<dyn Sink>], events: &Events) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.reset() {
+            warn!("Resetting output failed: {e}");
+            events.emit(&Event::OutputError(e.to_string()));
+        }
+    }
+}
+
+/// Check for a gap in time, logging and emitting it if there is one.
+fn check_gap(gap_detector: &mut Option<GapDetector>, events: &Events) -> Option<Duration> {
+    let gap = gap_detector.as_mut()?.check()?;
+    info!(
+        "Resuming after a gap of {:.1}s, resetting.",
+        gap.as_secs_f32()
+    );
+    events.emit(&Event::Resumed(gap));
+    Some(gap)
+}
+
+/// Make the screen capture source for the capture specifications of the config.
+fn make_source(
+    config: &Config,
+    shared_capture: Option<&SharedCapture>,
+    events: &Events,
+) -> Box<dyn Source> {
+    if !config.span.is_empty() {
+        return Box::new(SharedCaptureSource::spanning(
+            shared_capture,
+            &config.span,
+            events.clone(),
+        ));
+    }
+    match shared_capture {
+        Some(shared) => Box::new(SharedCaptureSource::new(
+            shared,
+            &config.capture,
+            config.display,
+            events.clone(),
+        )),
+        None => {
+            let mut source = stages::ScreenCaptureSource::new(&config.capture, events.clone());
+            source.set_display(config.display);
+            Box::new(source)
+        }
+    }
+}
+
+/// Whether the fields selected by `f` differ from those of the previous config, true if there is
+/// no previous config.
+fn changed<T: PartialEq>(
+    previous: Option<&Config>,
+    config: &Config,
+    f: impl Fn(&Config) -> T,
+) -> bool {
+    previous.map_or(true, |p| f(p) != f(config))
+}
+
+/// A config checked by [`Pipeline::prepare`], such that applying it can't fail.
+pub struct Prepared {
+    config: Config,
+    /// The new filters, None if their fields didn't change.
+    filters: Option<Vec<Box<dyn ColorFilter>>>,
+}
+
+impl Prepared {
+    /// Do the work of [`Pipeline::reconfigure`] that can fail for a pipeline whose stages were
+    /// made for the previous config, None if they weren't made from a config. The brightness
+    /// follows the schedule, see [`Pipeline::schedule`]. Nothing is changed.
+    pub fn new(
+        previous: Option<&Config>,
+        config: &Config,
+        schedule: &Schedule,
+    ) -> Result<Prepared, Box<dyn Error>> {
+        let mut prepared = Prepared {
+            config: config.clone(),
+            filters: None,
+        };
+        if changed(previous, config, |c| {
+            (
+                c.limiting_factor,
+                c.adaptive_brightness,
+                c.floor,
+                c.compensation_map.clone(),
+                c.schedule.clone(),
+            )
+        }) {
+            let mut filters: Vec<Box<dyn ColorFilter>> = vec![Box::new(stages::Brightness {
+                limiting_factor: config.limiting_factor,
+                adaptive: config
+                    .adaptive_brightness
+                    .map(crate::brightness::AdaptiveBrightness::new),
+                schedule: config.schedule.is_some().then(|| schedule.clone()),
+            })];
+            if let Some(floor) = config.floor {
+                filters.push(Box::new(stages::Floor(floor)));
+            }
+            if let Some(path) = config.compensation_map.as_ref() {
+                let map = crate::compensation::CompensationMap::load(path)?;
+                filters.push(Box::new(stages::Compensation(map)));
+            }
+            prepared.filters = Some(filters);
+        }
+        Ok(prepared)
+    }
+
+    /// Combine with a config prepared after this one, applying the result is the same as applying
+    /// both in order.
+    pub fn merge(self, newer: Prepared) -> Prepared {
+        Prepared {
+            config: newer.config,
+            filters: newer.filters.or(self.filters),
+        }
+    }
+}
+
+/// A change applied to a running pipeline between two frames, see [`Pipeline::run_threaded`].
+#[derive(Default)]
+pub struct Change {
+    /// A new config for the stages.
+    pub prepared: Option<Prepared>,
+    /// A new rate for the limiter.
+    pub rate: Option<f32>,
+}
+
+impl Change {
+    /// Whether nothing changes.
+    pub fn is_empty(&self) -> bool {
+        self.prepared.is_none() && self.rate.is_none()
+    }
+
+    /// Combine with a change made after this one, applying the result is the same as applying
+    /// both in order.
+    pub fn merge(self, newer: Change) -> Change {
+        Change {
+            prepared: match (self.prepared, newer.prepared) {
+                (Some(older), Some(newer)) => Some(older.merge(newer)),
+                (older, newer) => newer.or(older),
+            },
+            rate: newer.rate.or(self.rate),
+        }
+    }
+}
+
+/// The part of a change for the processing thread.
+struct ProcessingChange {
+    filters: Option<Vec<Box<dyn ColorFilter>>>,
+    standby: Option<RGB>,
+}
+
+impl ProcessingChange {
+    fn merge(self, newer: ProcessingChange) -> ProcessingChange {
+        ProcessingChange {
+            filters: newer.filters.or(self.filters),
+            standby: newer.standby,
+        }
+    }
+
+    fn apply(self, filters: &mut Vec<Box<dyn ColorFilter>>, standby: &mut Option<RGB>) {
+        if let Some(new_filters) = self.filters {
+            *filters = new_filters;
+        }
+        *standby = self.standby;
+    }
+}
+
+/// The part of a change for the output thread.
+#[derive(Default)]
+struct OutputChange {
+    gap_detector: Option<Option<GapDetector>>,
+    rate: Option<f32>,
+}
+
+impl OutputChange {
+    fn merge(self, newer: OutputChange) -> OutputChange {
+        OutputChange {
+            gap_detector: newer.gap_detector.or(self.gap_detector),
+            rate: newer.rate.or(self.rate),
+        }
+    }
+
+    fn apply(self, gap_detector: &mut Option<GapDetector>, limiter: &mut rate_limiter::Limiter) {
+        if let Some(new_gap_detector) = self.gap_detector {
+            *gap_detector = new_gap_detector;
+        }
+        if let Some(rate) = self.rate {
+            *limiter = rate_limiter::Limiter::new(rate);
+        }
+    }
+}
+
+/// Handle to stop a running loop, this can be cloned and used from other threads.
+#[derive(Debug, Default, Clone)]
+pub struct StopHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    /// Request the loop to stop, it returns after finishing the current frame.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether stopping was requested.
+    pub fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+}
+
+/// The stages that turn images into led colors, see the module documentation.
+pub struct Pipeline {
+    pub source: Box<dyn Source>,
+    pub border_detector: Box<dyn BorderDetector>,
+    pub zone_mapper: Box<dyn ZoneMapper>,
+    pub sampler: Box<dyn ZoneSampler>,
+    pub filters: Vec<Box<dyn ColorFilter>>,
+    pub sinks: Vec<Box<dyn Sink>>,
+
+    /// If set, zone making and sampling is skipped if the image is unchanged, comparing every n'th
+    /// pixel.
+    pub unchanged_frame_stride: Option<u32>,
+
+    /// The color shown while the source is unavailable, None leaves the leds as they are.
+    pub standby: Option<RGB>,
+
+    analysis: Analysis,
+    canvas: Option<Vec<RGB>>,
+    timings: Timings,
+    events: Events,
+    metrics: PipelineMetrics,
+    gap_detector: Option<GapDetector>,
+
+    /// The config the stages were last made for, see [`Pipeline::reconfigure`].
+    config: Option<Config>,
+    /// The capture shared with other outputs that the source is made for, None for a source of its
+    /// own.
+    shared_capture: Option<SharedCapture>,
+    /// The schedule the brightness filter follows, set to that of the config on reconfigure.
+    schedule: Schedule,
+}
+
+impl Pipeline {
+    /// The number of consecutive failed acquisitions after which the standby color is shown, like
+    /// while the capture is unavailable.
+    pub const STANDBY_AFTER_FAILURES: u32 = 5;
+
+    /// Create a pipeline from the provided stages, without filters or sinks.
+    pub fn new(
+        source: Box<dyn Source>,
+        border_detector: Box<dyn BorderDetector>,
+        zone_mapper: Box<dyn ZoneMapper>,
+        sampler: Box<dyn ZoneSampler>,
+    ) -> Self {
+        Pipeline::create(
+            source,
+            border_detector,
+            zone_mapper,
+            sampler,
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    fn create(
+        source: Box<dyn Source>,
+        border_detector: Box<dyn BorderDetector>,
+        zone_mapper: Box<dyn ZoneMapper>,
+        sampler: Box<dyn ZoneSampler>,
+        events: Events,
+        registry: Registry,
+    ) -> Self {
+        let metrics = PipelineMetrics::new(registry, &events);
+        Pipeline {
+            source,
+            border_detector,
+            zone_mapper,
+            sampler,
+            filters: vec![],
+            sinks: vec![],
+            unchanged_frame_stride: None,
+            standby: None,
+            analysis: Analysis::new(&metrics),
+            canvas: None,
+            timings: Default::default(),
+            events,
+            metrics,
+            gap_detector: Some(GapDetector::new(GapDetector::DEFAULT_THRESHOLD)),
+            config: None,
+            shared_capture: None,
+            schedule: Default::default(),
+        }
+    }
+
+    /// Handle to the schedule the brightness filter follows, clones share the clock.
+    pub fn schedule(&self) -> Schedule {
+        self.schedule.clone()
+    }
+
+    /// Create the default pipeline as specified by the config, without sinks. This loads the
+    /// compensation map, failure to load it is returned.
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn Error>> {
+        Pipeline::from_output_config(config, None, Default::default(), Default::default())
+    }
+
+    /// Create the default pipeline for one of multiple outputs, see [`crate::outputs`]. The source
+    /// crops its region from the shared capture and the metrics are registered in the registry,
+    /// such that they are the totals over the outputs. The brightness follows the shared schedule,
+    /// such that all outputs use the same clock.
+    pub fn for_output(
+        config: &Config,
+        shared_capture: &SharedCapture,
+        registry: Registry,
+        schedule: &Schedule,
+    ) -> Result<Self, Box<dyn Error>> {
+        Pipeline::from_output_config(
+            config,
+            Some(shared_capture.clone()),
+            registry,
+            schedule.clone(),
+        )
+    }
+
+    fn from_output_config(
+        config: &Config,
+        shared_capture: Option<SharedCapture>,
+        registry: Registry,
+        schedule: Schedule,
+    ) -> Result<Self, Box<dyn Error>> {
+        let events = Events::default();
+        let mut pipeline = Pipeline::create(
+            make_source(config, shared_capture.as_ref(), &events),
+            Box::new(stages::BlackBorderDetector::new(config)),
+            Box::new(stages::EdgeZones {
+                layout: crate::zones::Layout::for_leds(config.leds),
+                horizontal_depth: config.horizontal_depth,
+                vertical_depth: config.vertical_depth,
+            }),
+            Box::new(stages::GridSampler::new(
+                config.sample_pixel_distance,
+                config.sample_diagonalize_points,
+            )),
+            events,
+            registry,
+        );
+        pipeline.shared_capture = shared_capture;
+        pipeline.schedule = schedule;
+        pipeline.reconfigure(config)?;
+        Ok(pipeline)
+    }
+
+    /// Replace the stages except the sinks with those specified by the config, keeping the
+    /// events, metrics and the leds last written. Only the stages whose fields changed since the
+    /// previous call are replaced, such that the others keep their state and switching between
+    /// similar configs is seamless. Failure to load the compensation map is returned, in which
+    /// case nothing is changed.
+    pub fn reconfigure(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        let prepared = self.prepare(config)?;
+        self.apply(prepared);
+        Ok(())
+    }
+
+    /// Do the work of [`Pipeline::reconfigure`] that can fail, like loading the compensation map,
+    /// without changing anything. Use this to check multiple pipelines before applying any.
+    pub fn prepare(&self, config: &Config) -> Result<Prepared, Box<dyn Error>> {
+        Prepared::new(self.config.as_ref(), config, &self.schedule)
+    }
+
+    /// Apply a config prepared by [`Pipeline::prepare`], see [`Pipeline::reconfigure`].
+    pub fn apply(&mut self, prepared: Prepared) {
+        let (processing, output) = self.apply_analysis(prepared);
+        processing.apply(&mut self.filters, &mut self.standby);
+        if let Some(gap_detector) = output.gap_detector {
+            self.gap_detector = gap_detector;
+        }
+    }
+
+    /// Apply the part of a prepared config for the stages up to sampling, returning the parts for
+    /// the filters and the sinks. These run on their own threads in [`Pipeline::run_threaded`].
+    fn apply_analysis(&mut self, prepared: Prepared) -> (ProcessingChange, OutputChange) {
+        let Prepared { config, filters } = prepared;
+        let config = &config;
+        let previous = self.config.as_ref();
+        if changed(previous, config, |c| c.schedule.clone()) {
+            self.schedule
+                .set_config(config.schedule.clone().unwrap_or_default());
+        }
+
+        if changed(previous, config, |c| {
+            (c.display, c.capture.clone(), c.span.clone())
+        }) {
+            self.source = make_source(config, self.shared_capture.as_ref(), &self.events);
+        }
+        if changed(previous, config, |c| {
+            (
+                c.edge_detection_enable,
+                c.edge_detection_bisect_count,
+                c.edge_detection_rectangular_only,
+                c.edge_horizontal_change_per_s,
+                c.edge_vertical_change_per_s,
+            )
+        }) {
+            self.border_detector = Box::new(stages::BlackBorderDetector::new(config));
+        }
+        if changed(previous, config, |c| {
+            (
+                c.leds,
+                c.horizontal_depth,
+                c.vertical_depth,
+                c.sample_pixel_distance,
+                c.sample_diagonalize_points,
+            )
+        }) {
+            self.zone_mapper = Box::new(stages::EdgeZones {
+                layout: crate::zones::Layout::for_leds(config.leds),
+                horizontal_depth: config.horizontal_depth,
+                vertical_depth: config.vertical_depth,
+            });
+            self.sampler = Box::new(stages::GridSampler::new(
+                config.sample_pixel_distance,
+                config.sample_diagonalize_points,
+            ));
+            // Make the zones again on the next frame, for the new number of leds.
+            self.analysis.borders = None;
+            self.analysis.previous_fingerprint = None;
+            self.analysis.sampled = vec![RGB::default(); config.leds];
+        }
+        self.unchanged_frame_stride = config
+            .unchanged_frame_skip
+            .then_some(config.unchanged_frame_pixel_stride);
+        let processing = ProcessingChange {
+            filters,
+            standby: config.floor.map(|f| f.floor()),
+        };
+        let output = OutputChange {
+            gap_detector: changed(previous, config, |c| c.resume_gap).then(|| {
+                (config.resume_gap > 0.0)
+                    .then(|| GapDetector::new(Duration::from_secs_f32(config.resume_gap)))
+            }),
+            rate: None,
+        };
+        self.config = Some(config.clone());
+        (processing, output)
+    }
+
+    /// The config the stages were last made for, None if the pipeline wasn't made from a config.
+    pub fn config(&self) -> Option<&Config> {
+        self.config.as_ref()
+    }
+
+    /// The leds as last written to the sinks, None if nothing was written yet.
+    pub fn last_leds(&self) -> Option<&[RGB]> {
+        self.canvas.as_deref()
+    }
+
+    /// Write the leds directly to all sinks, bypassing the other stages.
+    pub fn write(&mut self, leds: &[RGB]) -> Result<(), Box<dyn Error>> {
+        output(&mut self.sinks, leds, &self.events)
+    }
+
+    /// Handle to the time spent in each of the stages, this can be inspected while running.
+    pub fn timings(&self) -> Timings {
+        self.timings.clone()
+    }
+
+    /// Handle to subscribe to the events emitted by the pipeline. A custom source can be given a
+    /// clone of this handle to emit its own events.
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    /// The registry holding the metrics of the pipeline, like the frame count, stage durations and
+    /// failure counts.
+    pub fn metrics(&self) -> Registry {
+        self.metrics.registry.clone()
+    }
+
+    /// Set the detector for gaps in time, None disables resetting after gaps. By default gaps over
+    /// [`GapDetector::DEFAULT_THRESHOLD`] are detected.
+    pub fn set_gap_detector(&mut self, gap_detector: Option<GapDetector>) {
+        self.gap_detector = gap_detector;
+    }
+
+    /// Reset the state of all stages, like after the machine was suspended.
+    pub fn reset(&mut self) {
+        self.analysis.reset(
+            &mut *self.source,
+            &mut *self.border_detector,
+            &mut *self.sampler,
+        );
+        reset_filters(&mut self.filters);
+        reset_sinks(&mut self.sinks, &self.events);
+    }
+
+    /// Check whether there was a gap in time since the previous check, if so the stages are reset
+    /// and the duration of the gap is returned. Call this once for every frame, before
+    /// [`Pipeline::step`].
+    pub fn check_gap(&mut self) -> Option<Duration> {
+        let gap = check_gap(&mut self.gap_detector, &self.events)?;
+        self.reset();
+        Some(gap)
+    }
+
+    /// Process exactly one frame, from the source to the sinks.
+    pub fn step(&mut self) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        let captured = self.analysis.capture(
+            &mut *self.source,
+            &mut *self.border_detector,
+            &mut *self.zone_mapper,
+            &mut *self.sampler,
+            self.unchanged_frame_stride,
+            &self.events,
+        );
+        let captured_time = Instant::now();
+        self.timings
+            .update(|t| &mut t.capture, captured_time - start);
+        self.metrics.capture.observe_duration(captured_time - start);
+
+        let leds = process(
+            &mut self.filters,
+            &mut self.canvas,
+            self.standby,
+            captured,
+            &self.events,
+        );
+        let processed_time = Instant::now();
+        self.timings
+            .update(|t| &mut t.processing, processed_time - captured_time);
+
+        if let Some(leds) = leds {
+            output(&mut self.sinks, &leds, &self.events)?;
+            let output_time = Instant::now();
+            self.timings
+                .update(|t| &mut t.output, output_time - processed_time);
+            self.timings.update(|t| &mut t.latency, output_time - start);
+            self.timings.frame();
+            self.metrics.frames.inc();
+            self.metrics
+                .output
+                .observe_duration(output_time - processed_time);
+            self.metrics.latency.observe_duration(output_time - start);
+        }
+        Ok(())
+    }
+
+    /// Run the analysis on the current thread, filters and sinks each on their own thread. The
+    /// sink thread paces the frames with the limiter and checks for gaps in time, on a gap the
+    /// limiter and all stages are reset. Sink errors are logged and emitted as events,
+    /// but don't stop the loop; sinks like [`lights::Lights`] can recover. Returns when stopped.
+    ///
+    /// Before each frame the changes are polled on the current thread, a change is applied to the
+    /// analysis right away and handed to the other threads, which apply it before their next
+    /// frame. The threads keep running, such that a change doesn't drop any frames.
+    pub fn run_threaded(
+        &mut self,
+        limiter: &mut rate_limiter::Limiter,
+        stop: &StopHandle,
+        mut changes: impl FnMut() -> Option<Change>,
+    ) -> Result<(), Box<dyn Error>> {
+        // The filters and sinks are owned by their threads while running.
+        let mut filters = std::mem::take(&mut self.filters);
+        let mut canvas = self.canvas.take();
+        let mut standby = self.standby;
+        let mut sinks = std::mem::take(&mut self.sinks);
+        let mut gap_detector = self.gap_detector.take();
+        let (timings, events, metrics) = (
+            self.timings.clone(),
+            self.events.clone(),
+            self.metrics.clone(),
+        );
+        let (timings, events, metrics) = (&timings, &events, &metrics);
+
+        // Set by the output on a gap in time, the other threads reset their stages before their
+        // next frame.
+        let reset_analysis = AtomicBool::new(false);
+        let reset_processing = AtomicBool::new(false);
+        let (reset_analysis, reset_processing) = (&reset_analysis, &reset_processing);
+
+        // Output requests a new capture, capture hands the sampled values to processing, processing
+        // hands the leds to the output. Changes are handed on from capture to the others.
+        let (request_tx, request_rx) = latest::channel::<()>();
+        let (captured_tx, captured_rx) = latest::channel::<(Instant, Captured)>();
+        let (processed_tx, processed_rx) = latest::channel::<(Instant, Option<Vec<RGB>>)>();
+        let (processing_change_tx, processing_change_rx) = latest::channel::<ProcessingChange>();
+        let (output_change_tx, output_change_rx) = latest::channel::<OutputChange>();
+        let (processing_change_rx, output_change_rx) = (&processing_change_rx, &output_change_rx);
+
+        let res = std::thread::scope(|s| {
+            let (filters, canvas, standby) = (&mut filters, &mut canvas, &mut standby);
+            let processing = s.spawn(move || {
+                while let Some((start, captured)) = captured_rx.recv() {
+                    if let Ok(change) = processing_change_rx.try_recv() {
+                        change.apply(filters, standby);
+                    }
+                    if reset_processing.swap(false, Ordering::SeqCst) {
+                        reset_filters(filters);
+                    }
+                    let processing_start = Instant::now();
+                    let leds = process(filters, canvas, *standby, captured, events);
+                    timings.update(|t| &mut t.processing, processing_start.elapsed());
+                    if processed_tx.send((start, leds)).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let (sinks, gap_detector, limiter) = (&mut sinks, &mut gap_detector, &mut *limiter);
+            let output = s.spawn(move || {
+                let mut current: Option<Vec<RGB>> = None;
+                while !stop.is_stopped() {
+                    if let Ok(change) = output_change_rx.try_recv() {
+                        change.apply(gap_detector, limiter);
+                    }
+                    if check_gap(gap_detector, events).is_some() {
+                        limiter.reset();
+                        reset_sinks(sinks, events);
+                        reset_analysis.store(true, Ordering::SeqCst);
+                        reset_processing.store(true, Ordering::SeqCst);
+                    }
+
+                    // Request the next frame, if capture is gone we are done.
+                    if request_tx.send(()).is_err() {
+                        break;
+                    }
+                    limiter.sleep();
+
+                    // Use the latest processed frame, or keep sending the current one.
+                    let mut start = None;
+                    match processed_rx.try_recv() {
+                        Ok((capture_start, leds)) => {
+                            current = leds;
+                            start = Some(capture_start);
+                        }
+                        Err(latest::RecvError::Empty) => {}
+                        Err(latest::RecvError::Disconnected) => break,
+                    }
+
+                    if let Some(leds) = current.as_ref() {
+                        let output_start = Instant::now();
+                        // Errors are logged and emitted by output, the sinks may recover.
+                        if output(sinks, leds, events).is_ok() {
+                            timings.frame();
+                            metrics.frames.inc();
+                        }
+                        timings.update(|t| &mut t.output, output_start.elapsed());
+                        metrics.output.observe_duration(output_start.elapsed());
+                        if let Some(start) = start {
+                            timings.update(|t| &mut t.latency, start.elapsed());
+                            metrics.latency.observe_duration(start.elapsed());
+                        }
+                    }
+                }
+            });
+
+            // Capture runs on this thread, whenever the output requests a new frame.
+            while request_rx.recv().is_some() {
+                if let Some(change) = changes() {
+                    let mut output_change = OutputChange::default();
+                    if let Some(prepared) = change.prepared {
+                        let (processing, output) = self.apply_analysis(prepared);
+                        let _ = processing_change_tx.merge(processing, ProcessingChange::merge);
+                        output_change = output;
+                    }
+                    output_change.rate = change.rate;
+                    let _ = output_change_tx.merge(output_change, OutputChange::merge);
+                }
+                if reset_analysis.swap(false, Ordering::SeqCst) {
+                    self.analysis.reset(
+                        &mut *self.source,
+                        &mut *self.border_detector,
+                        &mut *self.sampler,
+                    );
+                }
+                let start = Instant::now();
+                let captured = self.analysis.capture(
+                    &mut *self.source,
+                    &mut *self.border_detector,
+                    &mut *self.zone_mapper,
+                    &mut *self.sampler,
+                    self.unchanged_frame_stride,
+                    events,
+                );
+                timings.update(|t| &mut t.capture, start.elapsed());
+                metrics.capture.observe_duration(start.elapsed());
+                if captured_tx.send((start, captured)).is_err() {
+                    break;
+                }
+            }
+
+            // Output stopped, close the channels such that processing stops as well.
+            drop(captured_tx);
+            drop(request_rx);
+            processing.join().expect("Processing thread panicked.");
+            output.join().expect("Output thread panicked.");
+            Ok(())
+        });
+
+        // Changes the threads didn't get to are applied to the stages they return.
+        if let Ok(change) = processing_change_rx.try_recv() {
+            change.apply(&mut filters, &mut standby);
+        }
+        if let Ok(change) = output_change_rx.try_recv() {
+            change.apply(&mut gap_detector, limiter);
+        }
+        self.filters = filters;
+        self.canvas = canvas;
+        self.standby = standby;
+        self.sinks = sinks;
+        self.gap_detector = gap_detector;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stages;
+    use screen_capture::raster_image::RasterImageBGR;
+    use screen_capture::BGR;
+
+    /// Source providing a fixed image, or nothing if there is none.
+    struct ImageSource(Option<RasterImageBGR>);
+    impl Source for ImageSource {
+        fn acquire(&mut self) -> Acquired {
+            match self.0.as_ref() {
+                Some(img) => Acquired::Image(Box::new(img.clone())),
+                None => Acquired::Unavailable,
+            }
+        }
+    }
+
+    /// Sink recording everything written to it.
+    #[derive(Clone, Default)]
+    struct RecordingSink(Arc<Mutex<Vec<Vec<RGB>>>>);
+    impl Sink for RecordingSink {
+        fn write(&mut self, leds: &[RGB]) -> Result<(), Box<dyn Error>> {
+            self.0.lock().unwrap().push(leds.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Filter that inverts the red channel.
+    struct InvertRed;
+    impl ColorFilter for InvertRed {
+        fn apply(&mut self, leds: &mut [RGB]) {
+            for led in leds.iter_mut() {
+                led.r = 255 - led.r;
+            }
+        }
+    }
+
+    fn make_pipeline(img: Option<RasterImageBGR>) -> (Pipeline, RecordingSink) {
+        let config = Config {
+            edge_detection_enable: true,
+            edge_detection_bisect_count: 5,
+            horizontal_depth: 10,
+            vertical_depth: 10,
+            sample_pixel_distance: 5,
+            limiting_factor: 1.0,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(
+            Box::new(ImageSource(img)),
+            Box::new(stages::BlackBorderDetector::new(&config)),
+            Box::new(stages::EdgeZones {
+                layout: Default::default(),
+                horizontal_depth: config.horizontal_depth,
+                vertical_depth: config.vertical_depth,
+            }),
+            Box::new(stages::GridSampler::new(5, false)),
+        );
+        let sink = RecordingSink::default();
+        pipeline.sinks.push(Box::new(sink.clone()));
+        (pipeline, sink)
+    }
+
+    #[test]
+    fn test_step() {
+        let img = RasterImageBGR::filled(400, 300, BGR { r: 0, g: 100, b: 0 });
+        let (mut pipeline, sink) = make_pipeline(Some(img));
+        pipeline.filters.push(Box::new(InvertRed));
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_events = seen.clone();
+        pipeline.events().subscribe(move |e| {
+            seen_events.lock().unwrap().push(match e {
+                Event::BordersChanged(_) => "borders",
+                Event::FrameProcessed(leds) if leds.len() == DisplayLight::MAX_LEDS => "frame",
+                _ => "other",
+            })
+        });
+        pipeline.step().expect("Should succeed.");
+        pipeline.step().expect("Should succeed.");
+        assert_eq!(*seen.lock().unwrap(), vec!["borders", "frame", "frame"]);
+
+        let metrics = pipeline.metrics();
+        assert_eq!(metrics.counter_value("displaylight_frames_total"), Some(2));
+        assert_eq!(
+            metrics.counter_value("displaylight_border_changes_total"),
+            Some(1)
+        );
+        let sampling = metrics
+            .histogram_snapshot("displaylight_sampling_seconds")
+            .expect("Should exist.");
+        assert_eq!(sampling.count, 2);
+
+        let written = sink.0.lock().unwrap().clone();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[1].len(), DisplayLight::MAX_LEDS);
+        assert!(written[1].iter().all(|v| *v
+            == RGB {
+                r: 255,
+                g: 100,
+                b: 0
+            }));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let config = crate::layers::ConfigLoader::new()
+            .set("schedule={utc_offset: 0.0, keyframes: [{time: '08:00', limiting_factor: 0.5, color_temperature: 6600}, {time: '20:00', limiting_factor: 1.0, color_temperature: 2000}]}")
+            .unwrap()
+            .load()
+            .expect("Should be valid.");
+        let mut pipeline = Pipeline::from_config(&config).expect("Should succeed.");
+        let img = RasterImageBGR::filled(
+            400,
+            300,
+            BGR {
+                r: 255,
+                g: 255,
+                b: 255,
+            },
+        );
+        pipeline.source = Box::new(ImageSource(Some(img)));
+        let sink = RecordingSink::default();
+        pipeline.sinks.push(Box::new(sink.clone()));
+
+        // The brightness filter follows the clock of the display light's schedule.
+        let mut d = DisplayLight::from_pipeline(config.clone(), pipeline);
+        let clock =
+            crate::clock::ManualClock::at(std::time::UNIX_EPOCH + Duration::from_secs(8 * 3600));
+        d.set_schedule_clock(Box::new(clock.clone()));
+        d.step().expect("Should succeed.");
+        let gray = RGB {
+            r: 127,
+            g: 127,
+            b: 127,
+        };
+        assert_eq!(sink.0.lock().unwrap().last().unwrap()[0], gray);
+
+        // In the evening the leds are at full brightness and warm.
+        clock.advance(Duration::from_secs(12 * 3600));
+        d.step().expect("Should succeed.");
+        let warm = RGB {
+            r: 255,
+            g: 136,
+            b: 13,
+        };
+        assert_eq!(sink.0.lock().unwrap().last().unwrap()[0], warm);
+
+        // Changing the schedule keeps the clock.
+        let mut dimmed = config;
+        dimmed.schedule.as_mut().unwrap().keyframes[1].limiting_factor = Some(0.8);
+        d.reconfigure(dimmed).expect("Should be valid.");
+        d.step().expect("Should succeed.");
+        let dimmed = RGB {
+            r: 204,
+            g: 108,
+            b: 10,
+        };
+        assert_eq!(sink.0.lock().unwrap().last().unwrap()[0], dimmed);
+    }
+
+    #[test]
+    fn test_step_unchanged() {
+        let img = RasterImageBGR::filled(400, 300, BGR { r: 0, g: 100, b: 0 });
+        let (mut pipeline, sink) = make_pipeline(Some(img));
+        pipeline.unchanged_frame_stride = Some(1);
+        for _ in 0..3 {
+            pipeline.step().expect("Should succeed.");
+        }
+
+        // The borders are settled, detection and sampling only ran for the first frame.
+        let metrics = pipeline.metrics();
+        for name in [
+            "displaylight_border_detection_seconds",
+            "displaylight_sampling_seconds",
+        ] {
+            let snapshot = metrics.histogram_snapshot(name).expect("Should exist.");
+            assert_eq!(snapshot.count, 1);
+        }
+        let written = sink.0.lock().unwrap().clone();
+        assert_eq!(written.len(), 3);
+        assert_eq!(written[0], written[2]);
+    }
+
+    #[test]
+    fn test_step_unavailable() {
+        let (mut pipeline, sink) = make_pipeline(None);
+        pipeline.filters.push(Box::new(InvertRed));
+
+        // Without a standby color the leds are left alone.
+        pipeline.step().expect("Should succeed.");
+        assert!(sink.0.lock().unwrap().is_empty());
+        assert_eq!(pipeline.last_leds(), None);
+
+        // The standby color is shown as is, the filters are skipped.
+        pipeline.standby = Some(RGB { r: 20, g: 0, b: 0 });
+        pipeline.step().expect("Should succeed.");
+        let written = sink.0.lock().unwrap().clone();
+        assert_eq!(written.len(), 1);
+        assert!(written[0].iter().all(|v| *v == RGB { r: 20, g: 0, b: 0 }));
+
+        // Source that fails after the first image.
+        struct FailingSource(u32);
+        impl Source for FailingSource {
+            fn acquire(&mut self) -> Acquired {
+                self.0 += 1;
+                match self.0 {
+                    1 => Acquired::Image(Box::new(RasterImageBGR::filled(
+                        400,
+                        300,
+                        BGR { r: 0, g: 100, b: 0 },
+                    ))),
+                    _ => Acquired::Failed,
+                }
+            }
+        }
+        let (mut pipeline, sink) = make_pipeline(None);
+        pipeline.source = Box::new(FailingSource(0));
+        pipeline.standby = Some(RGB { r: 20, g: 0, b: 0 });
+
+        // A brief failure keeps the previous leds, if it keeps failing the standby color is shown.
+        for _ in 0..=Pipeline::STANDBY_AFTER_FAILURES {
+            pipeline.step().expect("Should succeed.");
+        }
+        let written = sink.0.lock().unwrap().clone();
+        assert!(written
+            .iter()
+            .all(|leds| leds.iter().all(|v| *v == RGB { r: 0, g: 100, b: 0 })));
+        pipeline.step().expect("Should succeed.");
+        let written = sink.0.lock().unwrap().clone();
+        assert!(written
+            .last()
+            .unwrap()
+            .iter()
+            .all(|v| *v == RGB { r: 20, g: 0, b: 0 }));
+    }
+
+    #[test]
+    fn test_leds() {
+        // The standby color is shown on the configured number of leds, also after a change.
+        let config = crate::layers::ConfigLoader::new()
+            .set("leds=300")
+            .unwrap()
+            .set("segments=[{port: /dev/ttyACM0, leds: 150}, {port: /dev/ttyACM1, leds: 150}]")
+            .unwrap()
+            .set("floor={color: {r: 20, g: 0, b: 0}, level: 1.0}")
+            .unwrap()
+            .load()
+            .expect("Should be valid.");
+        let mut pipeline = Pipeline::from_config(&config).expect("Should succeed.");
+        pipeline.source = Box::new(ImageSource(None));
+        let sink = RecordingSink::default();
+        pipeline.sinks.push(Box::new(sink.clone()));
+        pipeline.step().expect("Should succeed.");
+        assert_eq!(sink.0.lock().unwrap().last().unwrap().len(), 300);
+
+        let mut fewer = config.clone();
+        fewer.leds = 100;
+        pipeline.reconfigure(&fewer).expect("Should succeed.");
+        pipeline.step().expect("Should succeed.");
+        assert_eq!(sink.0.lock().unwrap().last().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn test_run_threaded() {
+        let img = RasterImageBGR::filled(400, 300, BGR { r: 0, g: 0, b: 50 });
+        let (mut pipeline, sink) = make_pipeline(Some(img));
+        let stop = StopHandle::default();
+        let mut limiter = rate_limiter::Limiter::new(100.0);
+
+        let stopper = stop.clone();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            stopper.stop();
+        });
+        pipeline
+            .run_threaded(&mut limiter, &stop, || None)
+            .expect("Should succeed.");
+        t.join().unwrap();
+
+        let written = sink.0.lock().unwrap().clone();
+        assert!(!written.is_empty());
+        assert!(written
+            .iter()
+            .all(|leds| leds.iter().all(|v| *v == RGB { r: 0, g: 0, b: 50 })));
+        assert!(pipeline.timings().average().capture > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_run_threaded_change() {
+        let config = crate::layers::ConfigLoader::new()
+            .set("limiting_factor=1.0")
+            .unwrap()
+            .load()
+            .expect("Should be valid.");
+        let mut pipeline = Pipeline::from_config(&config).expect("Should succeed.");
+        let img = RasterImageBGR::filled(400, 300, BGR { r: 0, g: 0, b: 200 });
+        pipeline.source = Box::new(ImageSource(Some(img)));
+        let sink = RecordingSink::default();
+        pipeline.sinks.push(Box::new(sink.clone()));
+
+        // Dim the leds while running, the threads keep running and pick up the new filters.
+        let mut dimmed = config.clone();
+        dimmed.limiting_factor = 0.5;
+        let mut prepared = Some(pipeline.prepare(&dimmed).expect("Should succeed."));
+        let stop = StopHandle::default();
+        let mut limiter = rate_limiter::Limiter::new(200.0);
+        let mut polls = 0;
+        pipeline
+            .run_threaded(&mut limiter, &stop, || {
+                polls += 1;
+                if polls == 30 {
+                    stop.stop();
+                }
+                (polls == 10).then(|| Change {
+                    prepared: prepared.take(),
+                    rate: None,
+                })
+            })
+            .expect("Should succeed.");
+
+        let written = sink.0.lock().unwrap().clone();
+        let blue: Vec<u8> = written.iter().map(|leds| leds[0].b).collect();
+        assert_eq!(blue.first(), Some(&200));
+        assert_eq!(blue.last(), Some(&100));
+        let switch = blue.iter().position(|b| *b == 100).unwrap();
+        assert!(blue[..switch].iter().all(|b| *b == 200));
+        assert!(blue[switch..].iter().all(|b| *b == 100));
+        assert_eq!(pipeline.config().unwrap().limiting_factor, 0.5);
+        assert_eq!(pipeline.sinks.len(), 1);
+        assert_eq!(pipeline.filters.len(), 1);
+    }
+
+    #[test]
+    fn test_check_gap() {
+        use crate::clock::ManualClock;
+        use std::sync::atomic::AtomicUsize;
+
+        /// Sink and filter counting their resets.
+        #[derive(Clone, Default)]
+        struct CountingReset(Arc<AtomicUsize>);
+        impl Sink for CountingReset {
+            fn write(&mut self, _: &[RGB]) -> Result<(), Box<dyn Error>> {
+                Ok(())
+            }
+            fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+        impl ColorFilter for CountingReset {
+            fn apply(&mut self, _: &mut [RGB]) {}
+            fn reset(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let img = RasterImageBGR::filled(400, 300, BGR { r: 0, g: 100, b: 0 });
+        let (mut pipeline, _) = make_pipeline(Some(img));
+        let clock = ManualClock::default();
+        pipeline.set_gap_detector(Some(GapDetector::with_clock(
+            Duration::from_secs(2),
+            Box::new(clock.clone()),
+        )));
+        let resets = CountingReset::default();
+        pipeline.sinks.push(Box::new(resets.clone()));
+        pipeline.filters.push(Box::new(resets.clone()));
+        let borders_changed = Arc::new(AtomicUsize::new(0));
+        let borders_changed_events = borders_changed.clone();
+        pipeline.events().subscribe(move |e| {
+            if let Event::BordersChanged(_) = e {
+                borders_changed_events.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        for _ in 0..3 {
+            assert_eq!(pipeline.check_gap(), None);
+            pipeline.step().expect("Should succeed.");
+            clock.advance(Duration::from_millis(20));
+        }
+        assert_eq!(resets.0.load(Ordering::SeqCst), 0);
+        assert_eq!(borders_changed.load(Ordering::SeqCst), 1);
+
+        // Suspended for an hour, the sink and filter are reset and the zones are made again.
+        clock.advance(Duration::from_secs(3600));
+        assert!(pipeline.check_gap().is_some());
+        pipeline.step().expect("Should succeed.");
+        assert_eq!(resets.0.load(Ordering::SeqCst), 2);
+        assert_eq!(borders_changed.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            pipeline
+                .metrics()
+                .counter_value("displaylight_resumes_total"),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_timings() {
+        let timings = Timings::default();
+        timings.update(|t| &mut t.capture, Duration::from_millis(10));
+        assert_eq!(timings.last().capture, Duration::from_millis(10));
+        assert_eq!(timings.average().capture, Duration::from_millis(10));
+
+        timings.update(|t| &mut t.capture, Duration::from_millis(20));
+        assert_eq!(timings.last().capture, Duration::from_millis(20));
+        let average = timings.average().capture.as_secs_f64();
+        assert!((average - 0.0105).abs() < 1e-6);
+        assert_eq!(timings.last().output, Duration::ZERO);
+    }
+}
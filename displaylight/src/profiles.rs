@@ -0,0 +1,109 @@
+//! Named profiles, each overriding any subset of the config's fields.
+//!
+//! Profiles are defined in the config and the active one is selected with the `profile` field,
+//! like:
+//! ```yaml
+//! profile: movie
+//! profiles:
+//!   movie: {edge_detection_enable: true, adaptive_brightness: {min: 0.3, max: 1.0, rise_time_constant: 1.0, fall_time_constant: 4.0}}
+//!   game: {edge_detection_enable: false, adaptive_brightness: ~}
+//!   desktop: {limiting_factor: 0.2, edge_detection_enable: false}
+//! ```
+//! The fields of a profile are merged with the config like the layers of [`crate::layers`]. While
+//! running, the profile can be switched with a [`ProfileSwitch`].
+use crate::Config;
+use serde_yaml::Value;
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+/// Return the config with the fields of the named profile applied, the config itself if None. The
+/// result is not validated.
+pub fn apply(config: &Config, profile: Option<&str>) -> Result<Config, Box<dyn Error>> {
+    let Some(name) = profile else {
+        return Ok(config.clone());
+    };
+    let overrides = config
+        .profiles
+        .get(name)
+        .ok_or_else(|| format!("Unknown profile '{}'", name))?;
+    if let Value::Mapping(mapping) = overrides {
+        for key in [
+            "profile",
+            "profiles",
+            "profile_rules",
+            "profile_rules_release",
+            "schedule",
+            "outputs",
+        ] {
+            if mapping.contains_key(&Value::String(key.to_owned())) {
+                return Err(format!("Profile '{}' can't set '{}'", name, key).into());
+            }
+        }
+    }
+    let mut merged = serde_yaml::to_value(config)?;
+    crate::layers::merge(&mut merged, overrides.clone());
+    let mut applied: Config = serde_yaml::from_value(merged)
+        .map_err(|ref e| format!("Invalid profile '{}': {}", name, e))?;
+    applied.profile = Some(name.to_owned());
+    Ok(applied)
+}
+
+/// Handle to request switching the profile while running, this can be cloned and used from other
+/// threads. The switch is applied before the next frame.
+#[derive(Debug, Default, Clone)]
+pub struct ProfileSwitch {
+    requested: Arc<Mutex<Option<Option<String>>>>,
+}
+
+impl ProfileSwitch {
+    /// Request switching to the named profile, None uses the config without a profile.
+    pub fn request(&self, profile: Option<&str>) {
+        *self.requested.lock().unwrap() = Some(profile.map(str::to_owned));
+    }
+
+    /// Whether a switch was requested and not applied yet.
+    pub fn is_pending(&self) -> bool {
+        self.requested.lock().unwrap().is_some()
+    }
+
+    /// Take the requested profile, if any.
+    pub fn take(&self) -> Option<Option<String>> {
+        self.requested.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let config = crate::layers::ConfigLoader::new()
+            .set("profiles={dim: {limiting_factor: 0.1, power_budget: {ma_per_channel: 20, budget_ma: 500}}, bad: {profile: dim}}")
+            .unwrap()
+            .load()
+            .expect_err("Profile 'bad' sets the profile.");
+        assert!(config.to_string().contains("profiles.bad"));
+
+        let config = crate::layers::ConfigLoader::new()
+            .set("profiles={dim: {limiting_factor: 0.1, power_budget: {ma_per_channel: 20, budget_ma: 500}}}")
+            .unwrap()
+            .load()
+            .expect("Should be valid.");
+        let dim = apply(&config, Some("dim")).expect("Should apply.");
+        assert_eq!(dim.limiting_factor, 0.1);
+        assert_eq!(dim.power_budget.map(|b| b.budget_ma), Some(500.0));
+        assert_eq!(dim.rate, config.rate);
+        assert_eq!(dim.profile.as_deref(), Some("dim"));
+        assert_eq!(apply(&config, None).unwrap(), config);
+        assert!(apply(&config, Some("missing")).is_err());
+
+        let switch = ProfileSwitch::default();
+        assert!(!switch.is_pending());
+        switch.clone().request(Some("dim"));
+        assert!(switch.is_pending());
+        assert_eq!(switch.take(), Some(Some("dim".to_owned())));
+        assert_eq!(switch.take(), None);
+    }
+}
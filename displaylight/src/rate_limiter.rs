@@ -1,56 +1,195 @@
 //! Rate limiting struct that sleeps to meet a desired rate.
-use std::thread::sleep;
+use crate::clock::{Clock, SystemClock};
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
+/// Statistics of the achieved timing over the recent ticks.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct LimiterStats {
+    /// The achieved rate in Hz.
+    pub rate: f32,
+    /// Median lateness of the ticks relative to their deadline.
+    pub jitter_p50: Duration,
+    /// 90th percentile of the lateness.
+    pub jitter_p90: Duration,
+    /// 99th percentile of the lateness.
+    pub jitter_p99: Duration,
+    /// Largest lateness.
+    pub jitter_max: Duration,
+    /// Total number of ticks skipped because they were missed entirely.
+    pub skipped: u64,
+}
+
 /// Rate limiter struct.
+///
+/// Ticks are scheduled at absolute deadlines, such that oversleeping doesn't make the rate drift.
+/// If a deadline is missed by more than a period, the missed ticks are skipped instead of
+/// running them in a burst.
 pub struct Limiter {
-    start: Instant,
+    clock: Box<dyn Clock>,
     period: Duration,
+    next: Instant,
+    last_tick: Option<Instant>,
+    skipped: u64,
+
+    /// Recent intervals between ticks and their lateness, for the statistics.
+    intervals: VecDeque<Duration>,
+    lateness: VecDeque<Duration>,
 }
 
 impl Limiter {
+    /// The number of recent ticks the statistics are calculated over.
+    pub const STATS_WINDOW: usize = 600;
+
     /// Create a new rate limiter, that runs at the rate specified in Hz.
     pub fn new(rate: f32) -> Limiter {
+        Limiter::with_clock(rate, Box::new(SystemClock))
+    }
+
+    /// Create a new rate limiter using the provided clock.
+    pub fn with_clock(rate: f32, clock: Box<dyn Clock>) -> Limiter {
+        let period = Duration::from_secs_f64(1.0 / rate as f64);
         Limiter {
-            start: Instant::now(),
-            period: Duration::from_secs_f32(1.0f32 / rate),
+            next: clock.now() + period,
+            clock,
+            period,
+            last_tick: None,
+            skipped: 0,
+            intervals: VecDeque::with_capacity(Limiter::STATS_WINDOW),
+            lateness: VecDeque::with_capacity(Limiter::STATS_WINDOW),
         }
     }
 
-    /// Sleep will sleep a duration that ensures we sleep period - (time spent since leaving previous sleep).
-    /// Thereby trying to meet the desired rate as best as possible.
+    /// Sleep until the next deadline, if it already passed this returns immediately. Deadlines that
+    /// were missed entirely are skipped.
     pub fn sleep(&mut self) {
-        let now = Instant::now();
-        let since_last = now - self.start;
+        let now = self.clock.now();
+        if now < self.next {
+            self.clock.sleep(self.next - now);
+        }
+        let woke = self.clock.now();
+        let deadline = self.next;
 
-        if since_last < self.period {
-            // actually have to rate limit, for period - what we already spent doing things.
-            let delta = self.period - since_last;
-            sleep(delta);
+        // Advance to the first deadline after waking up, skipping the missed ones.
+        self.next = deadline + self.period;
+        if self.next <= woke {
+            let missed = ((woke - self.next).as_secs_f64() / self.period.as_secs_f64()) as u32 + 1;
+            self.next += self.period * missed;
+            self.skipped += missed as u64;
         }
-        self.start = Instant::now();
+
+        push_bounded(&mut self.lateness, woke.saturating_duration_since(deadline));
+        if let Some(last_tick) = self.last_tick {
+            push_bounded(&mut self.intervals, woke - last_tick);
+        }
+        self.last_tick = Some(woke);
+    }
+
+    /// Restart the schedule from now, forgetting the previous tick. Used after the loop was paused.
+    pub fn reset(&mut self) {
+        self.next = self.clock.now() + self.period;
+        self.last_tick = None;
+    }
+
+    /// Statistics over the recent ticks.
+    pub fn stats(&self) -> LimiterStats {
+        let total: Duration = self.intervals.iter().sum();
+        let rate = if total.is_zero() {
+            0.0
+        } else {
+            self.intervals.len() as f32 / total.as_secs_f32()
+        };
+        let mut lateness: Vec<Duration> = self.lateness.iter().copied().collect();
+        lateness.sort();
+        LimiterStats {
+            rate,
+            jitter_p50: percentile(&lateness, 0.5),
+            jitter_p90: percentile(&lateness, 0.9),
+            jitter_p99: percentile(&lateness, 0.99),
+            jitter_max: lateness.last().copied().unwrap_or_default(),
+            skipped: self.skipped,
+        }
+    }
+}
+
+fn push_bounded(values: &mut VecDeque<Duration>, value: Duration) {
+    if values.len() == Limiter::STATS_WINDOW {
+        values.pop_front();
+    }
+    values.push_back(value);
+}
+
+/// Nearest rank percentile of sorted values.
+fn percentile(sorted: &[Duration], p: f32) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
     }
+    let rank = (p * sorted.len() as f32).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
 
     #[test]
     fn test_limiter() {
-        let mut limiter = Limiter::new(10.0);
-        let mut t_old = Instant::now();
-        for _i in 0..10 {
+        let clock = ManualClock::default();
+        let mut limiter = Limiter::with_clock(10.0, Box::new(clock.clone()));
+        let start = clock.now();
+        for i in 1..=10 {
+            // Doing some work doesn't shift the schedule.
+            clock.advance(Duration::from_millis(30));
             limiter.sleep();
-            let t_new = Instant::now();
+            assert_eq!(clock.now() - start, Duration::from_millis(100) * i);
+        }
+        let stats = limiter.stats();
+        assert!((stats.rate - 10.0).abs() < 1e-3);
+        assert_eq!(stats.jitter_max, Duration::ZERO);
+        assert_eq!(stats.skipped, 0);
+    }
 
-            // println!("Time difference: {:?}", t_new - t_old);
-            let desired: f32 = 0.1;
-            const WITHIN: f32 = 0.05;
-            assert!((((t_new - t_old).as_secs_f32() - desired).abs()) < WITHIN);
-            t_old = t_new;
+    #[test]
+    fn test_limiter_late() {
+        let clock = ManualClock::default();
+        let mut limiter = Limiter::with_clock(10.0, Box::new(clock.clone()));
+        let start = clock.now();
 
-            sleep(Duration::from_secs_f32(0.05)); // sleep some extra here to ensure we do 'work'.
-        }
+        // Slightly late, returns immediately and the next deadline stays on schedule.
+        clock.advance(Duration::from_millis(120));
+        limiter.sleep();
+        assert_eq!(clock.now() - start, Duration::from_millis(120));
+        limiter.sleep();
+        assert_eq!(clock.now() - start, Duration::from_millis(200));
+
+        // Late by several periods, the missed ticks are skipped instead of bursting.
+        clock.advance(Duration::from_millis(350));
+        limiter.sleep();
+        assert_eq!(clock.now() - start, Duration::from_millis(550));
+        limiter.sleep();
+        assert_eq!(clock.now() - start, Duration::from_millis(600));
+
+        let stats = limiter.stats();
+        assert_eq!(stats.skipped, 2);
+        assert_eq!(stats.jitter_max, Duration::from_millis(250));
+        assert_eq!(stats.jitter_p50, Duration::ZERO);
+        assert_eq!(stats.jitter_p90, Duration::from_millis(250));
+
+        // After a reset the schedule starts from now.
+        clock.advance(Duration::from_secs(10));
+        limiter.reset();
+        limiter.sleep();
+        assert_eq!(clock.now() - start, Duration::from_millis(10_700));
+        assert_eq!(limiter.stats().skipped, 2);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&values, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&values, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&values, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
     }
 }
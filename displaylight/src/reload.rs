@@ -0,0 +1,146 @@
+//! Watching the config files for changes, such that they can be applied without restarting.
+//!
+//! The files are polled, which works the same on all platforms and on network filesystems. Only
+//! changed content results in a reload, touching a file or saving it unchanged doesn't. On a
+//! change all layers of the [`ConfigLoader`] are loaded again.
+use crate::layers::ConfigLoader;
+use crate::Config;
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The state of a watched file.
+struct Watched {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    content: Option<String>,
+}
+
+impl Watched {
+    fn new(path: &Path) -> Self {
+        Watched {
+            path: path.to_path_buf(),
+            modified: modified(path),
+            content: std::fs::read_to_string(path).ok(),
+        }
+    }
+
+    /// Whether the content changed since the previous call.
+    fn changed(&mut self) -> bool {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        let content = std::fs::read_to_string(&self.path).ok();
+        if content == self.content {
+            return false;
+        }
+        self.content = content;
+        true
+    }
+}
+
+/// Watches the files of a config loader, returning the new config when their content changed.
+pub struct ConfigWatcher {
+    loader: ConfigLoader,
+    files: Vec<Watched>,
+    previous_poll: Option<Instant>,
+    /// The minimum interval between checking the files.
+    pub interval: Duration,
+}
+
+impl ConfigWatcher {
+    /// The default interval between checking the files.
+    pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Watch the files of this loader, their current content is considered to be loaded already.
+    /// Files that don't exist yet are watched as well.
+    pub fn new(loader: ConfigLoader) -> Self {
+        ConfigWatcher {
+            files: loader.files().into_iter().map(Watched::new).collect(),
+            loader,
+            previous_poll: None,
+            interval: ConfigWatcher::POLL_INTERVAL,
+        }
+    }
+
+    /// A description of the files being watched, for logging.
+    pub fn description(&self) -> String {
+        let paths: Vec<String> = self
+            .files
+            .iter()
+            .map(|f| format!("'{}'", f.path.display()))
+            .collect();
+        paths.join(", ")
+    }
+
+    /// Check the files if the interval passed since the previous check. Returns the loaded and
+    /// validated config if the content of any of them changed, or why it failed to load. None if
+    /// nothing changed.
+    pub fn poll(&mut self) -> Option<Result<Config, Box<dyn Error>>> {
+        let now = Instant::now();
+        if let Some(previous_poll) = self.previous_poll {
+            if now.duration_since(previous_poll) < self.interval {
+                return None;
+            }
+        }
+        self.previous_poll = Some(now);
+
+        // Check all of them, such that each change is only reported once.
+        let mut changed = false;
+        for file in self.files.iter_mut() {
+            changed |= file.changed();
+        }
+        if !changed {
+            return None;
+        }
+        Some(self.loader.load())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_watcher() {
+        let path = std::env::temp_dir().join("test_config_watcher.yaml");
+        // Only the fields that differ from the defaults are needed.
+        let content = "limiting_factor: 0.5\n".to_owned();
+        std::fs::write(&path, &content).unwrap();
+        let mut watcher = ConfigWatcher::new(ConfigLoader::new().file(&path));
+        watcher.interval = Duration::ZERO;
+        assert!(watcher.poll().is_none());
+
+        // Make sure the modification time differs, not all filesystems have a fine resolution.
+        let write = |content: &str| {
+            let before = modified(&path);
+            while modified(&path) == before {
+                std::thread::sleep(Duration::from_millis(10));
+                std::fs::write(&path, content).unwrap();
+            }
+        };
+
+        // Unchanged content doesn't reload.
+        write(&content);
+        assert!(watcher.poll().is_none());
+
+        write(&content.replace("limiting_factor: 0.5", "limiting_factor: 0.25"));
+        let config = watcher.poll().expect("Changed").expect("Should be valid.");
+        assert_eq!(config.limiting_factor, 0.25);
+        assert_eq!(config.rate, 60.0);
+        assert!(watcher.poll().is_none());
+
+        // Invalid values fail validation, invalid yaml fails parsing.
+        write(&content.replace("limiting_factor: 0.5", "limiting_factor: 2.0"));
+        assert!(watcher.poll().expect("Changed").is_err());
+        write("rate: [");
+        assert!(watcher.poll().expect("Changed").is_err());
+    }
+}
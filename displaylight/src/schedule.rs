@@ -0,0 +1,358 @@
+//! Following the time of day with the limiting factor, color temperature and profile.
+//!
+//! Keyframes set any of these at a time of day, like:
+//! ```yaml
+//! schedule:
+//!   keyframes:
+//!     - {time: "08:00", limiting_factor: 1.0, color_temperature: 6600, profile: ~}
+//!     - {time: "20:00", limiting_factor: 0.6, color_temperature: 3000, profile: evening}
+//!     - {time: "23:30", limiting_factor: 0.1, color_temperature: 1900}
+//! ```
+//! The limiting factor and color temperature are interpolated linearly between the keyframes that
+//! set them, wrapping around midnight, such that they change smoothly. The profile is switched to
+//! at the time of the keyframe that sets it, `~` switches to using no profile. Keyframes
+//! only affect the fields they set.
+//!
+//! The time of day is that of the local time zone, following daylight saving time. Setting
+//! `utc_offset` uses a fixed number of hours ahead of UTC instead, which is not adjusted for
+//! daylight saving time.
+use crate::clock::{Clock, SystemClock};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of seconds in a day.
+pub const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// A time of day, written as `HH:MM` or `HH:MM:SS`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default, Copy, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    /// Seconds since midnight.
+    pub seconds: u32,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("'{}' is not a time like 'HH:MM' or 'HH:MM:SS'", value);
+        let parts = value
+            .split(':')
+            .map(|p| p.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| invalid())?;
+        let (hours, minutes, seconds) = match parts[..] {
+            [h, m] => (h, m, 0),
+            [h, m, s] => (h, m, s),
+            _ => return Err(invalid()),
+        };
+        if hours > 23 || minutes > 59 || seconds > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay {
+            seconds: hours * 3600 + minutes * 60 + seconds,
+        })
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> String {
+        t.to_string()
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = self.seconds;
+        if s % 60 == 0 {
+            write!(f, "{:02}:{:02}", s / 3600, s / 60 % 60)
+        } else {
+            write!(f, "{:02}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+        }
+    }
+}
+
+/// Distinguishes a field that is set to null from a missing one, the latter stays None.
+fn deserialize_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// The values set at a time of day, unset fields are interpolated from the other keyframes.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
+pub struct Keyframe {
+    /// The time of day of this keyframe.
+    pub time: TimeOfDay,
+
+    /// The limiting factor, this replaces [`crate::Config::limiting_factor`].
+    #[serde(default)]
+    pub limiting_factor: Option<f32>,
+
+    /// The color temperature of white in Kelvin, 6600 is neutral, see
+    /// [`crate::color::temperature_gains`].
+    #[serde(default)]
+    pub color_temperature: Option<f32>,
+
+    /// The profile to switch to, the inner None switches to using no profile.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub profile: Option<Option<String>>,
+}
+
+/// Time of day keyframes, see the [module](self).
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
+pub struct ScheduleConfig {
+    /// Fixed hours the time of day is ahead of UTC, like 1.0 for Central European Time. It is not
+    /// adjusted for daylight saving time. None uses the local time zone.
+    #[serde(default)]
+    pub utc_offset: Option<f32>,
+
+    /// The keyframes, in any order.
+    #[serde(default)]
+    pub keyframes: Vec<Keyframe>,
+}
+
+/// The values the schedule sets at a time of day.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct Scheduled {
+    pub limiting_factor: Option<f32>,
+    pub color_temperature: Option<f32>,
+    /// The profile of the latest keyframe that sets one.
+    pub profile: Option<Option<String>>,
+}
+
+impl ScheduleConfig {
+    /// The values at the provided time of day.
+    pub fn at(&self, time: TimeOfDay) -> Scheduled {
+        Scheduled {
+            limiting_factor: self.interpolate(time, |k| k.limiting_factor),
+            color_temperature: self.interpolate(time, |k| k.color_temperature),
+            profile: self
+                .surrounding(time, |k| k.profile.clone())
+                .map(|((_, profile), _)| profile),
+        }
+    }
+
+    /// Interpolate linearly between the keyframes before and after the time that set the field.
+    fn interpolate(
+        &self,
+        time: TimeOfDay,
+        field: impl Fn(&Keyframe) -> Option<f32>,
+    ) -> Option<f32> {
+        let ((before_time, before), (after_time, after)) = self.surrounding(time, field)?;
+        let since = |from: u32, to: u32| (to + SECONDS_PER_DAY - from) % SECONDS_PER_DAY;
+        let span = since(before_time, after_time);
+        if span == 0 {
+            return Some(before);
+        }
+        let fraction = since(before_time, time.seconds) as f32 / span as f32;
+        Some(before + (after - before) * fraction)
+    }
+
+    /// The latest keyframe at or before the time and the first one after it that set the field,
+    /// wrapping around midnight. None if no keyframe sets it.
+    #[allow(clippy::type_complexity)]
+    fn surrounding<T: Clone>(
+        &self,
+        time: TimeOfDay,
+        field: impl Fn(&Keyframe) -> Option<T>,
+    ) -> Option<((u32, T), (u32, T))> {
+        let mut set: Vec<(u32, T)> = self
+            .keyframes
+            .iter()
+            .filter_map(|k| field(k).map(|v| (k.time.seconds, v)))
+            .collect();
+        set.sort_by_key(|(t, _)| *t);
+        let after = set.iter().position(|(t, _)| *t > time.seconds);
+        let before = match after {
+            Some(0) | None => set.last()?.clone(),
+            Some(i) => set[i - 1].clone(),
+        };
+        let after = set[after.unwrap_or(0)].clone();
+        Some((before, after))
+    }
+}
+
+struct State {
+    config: ScheduleConfig,
+    clock: Box<dyn Clock>,
+    /// The scheduled profile last returned by [`Schedule::profile_change`].
+    profile: Option<Option<String>>,
+}
+
+impl State {
+    fn time_of_day(&self) -> TimeOfDay {
+        let now = self.clock.system_time();
+        let since_epoch = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let offset = match self.config.utc_offset {
+            Some(hours) => hours as f64 * 3600.0,
+            None => local_utc_offset(now) as f64,
+        };
+        let local = since_epoch + offset;
+        TimeOfDay {
+            seconds: local.rem_euclid(SECONDS_PER_DAY as f64) as u32 % SECONDS_PER_DAY,
+        }
+    }
+}
+
+/// The seconds the local time zone is ahead of UTC at the provided time, including daylight saving
+/// time.
+fn local_utc_offset(time: SystemTime) -> i32 {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .offset()
+        .local_minus_utc()
+}
+
+/// Evaluates the schedule at the current time of day. Clones share the config, the clock and the
+/// profile tracking, such that the profile switching and the brightness filters follow one clock.
+#[derive(Clone)]
+pub struct Schedule {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::new(Default::default())
+    }
+}
+
+impl Schedule {
+    /// Create the schedule using the system clock.
+    pub fn new(config: ScheduleConfig) -> Self {
+        Schedule::with_clock(config, Box::new(SystemClock))
+    }
+
+    /// Create the schedule using the provided clock.
+    pub fn with_clock(config: ScheduleConfig, clock: Box<dyn Clock>) -> Self {
+        Schedule {
+            state: Arc::new(Mutex::new(State {
+                config,
+                clock,
+                profile: None,
+            })),
+        }
+    }
+
+    /// The config of this schedule.
+    pub fn config(&self) -> ScheduleConfig {
+        self.state.lock().unwrap().config.clone()
+    }
+
+    /// Replace the config, the clock and the profile last switched to are kept.
+    pub fn set_config(&self, config: ScheduleConfig) {
+        self.state.lock().unwrap().config = config;
+    }
+
+    /// Replace the clock, like with a [`crate::clock::ManualClock`] in tests.
+    pub fn set_clock(&self, clock: Box<dyn Clock>) {
+        self.state.lock().unwrap().clock = clock;
+    }
+
+    /// The current local time of day.
+    pub fn time_of_day(&self) -> TimeOfDay {
+        self.state.lock().unwrap().time_of_day()
+    }
+
+    /// The values at the current time of day.
+    pub fn now(&self) -> Scheduled {
+        let state = self.state.lock().unwrap();
+        state.config.at(state.time_of_day())
+    }
+
+    /// Returns the profile to switch to if the scheduled profile differs from the one returned
+    /// previously, the first call returns the scheduled profile if any. The outer None means no
+    /// switch, the inner None means no profile.
+    pub fn profile_change(&self) -> Option<Option<String>> {
+        let mut state = self.state.lock().unwrap();
+        let profile = state.config.at(state.time_of_day()).profile?;
+        if state.profile.as_ref() == Some(&profile) {
+            return None;
+        }
+        state.profile = Some(profile.clone());
+        Some(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::Duration;
+
+    #[test]
+    fn test_schedule() {
+        let config: ScheduleConfig = serde_yaml::from_str(
+            "utc_offset: 2.0
+keyframes:
+  - {time: '20:00', limiting_factor: 0.6, color_temperature: 3000, profile: evening}
+  - {time: '08:00', limiting_factor: 1.0, color_temperature: 6600, profile: ~}
+  - {time: '23:30', limiting_factor: 0.1}",
+        )
+        .expect("Should parse.");
+        assert_eq!(config.keyframes[1].profile, Some(None));
+        assert_eq!(config.keyframes[2].profile, None);
+        assert_eq!(config.keyframes[2].time.to_string(), "23:30");
+        assert!(serde_yaml::from_str::<TimeOfDay>("'24:00'").is_err());
+        assert!(serde_yaml::from_str::<TimeOfDay>("'8'").is_err());
+
+        // Start at 06:00 UTC, 08:00 local.
+        let clock = ManualClock::at(UNIX_EPOCH + Duration::from_secs(6 * 3600));
+        let schedule = Schedule::with_clock(config.clone(), Box::new(clock.clone()));
+        assert_eq!(schedule.time_of_day().to_string(), "08:00");
+        let now = schedule.now();
+        assert_eq!(now.limiting_factor, Some(1.0));
+        assert_eq!(now.color_temperature, Some(6600.0));
+        assert_eq!(schedule.profile_change(), Some(None));
+        assert_eq!(schedule.profile_change(), None);
+
+        // Halfway to the evening keyframe.
+        clock.advance(Duration::from_secs(6 * 3600));
+        let now = schedule.now();
+        assert!((now.limiting_factor.unwrap() - 0.8).abs() < 1e-5);
+        assert!((now.color_temperature.unwrap() - 4800.0).abs() < 1e-2);
+        assert_eq!(schedule.profile_change(), None);
+
+        clock.advance(Duration::from_secs(6 * 3600));
+        assert_eq!(schedule.profile_change(), Some(Some("evening".to_owned())));
+
+        // After midnight the night keyframe fades back to the morning, the temperature only has
+        // the evening and morning keyframes.
+        clock.advance(Duration::from_secs(6 * 3600));
+        assert_eq!(schedule.time_of_day().to_string(), "02:00");
+        let now = schedule.now();
+        assert!((now.limiting_factor.unwrap() - 0.1 - 0.9 * 2.5 / 8.5).abs() < 1e-5);
+        assert!((now.color_temperature.unwrap() - (3000.0 + 3600.0 * 0.5)).abs() < 1e-2);
+        assert_eq!(schedule.profile_change(), None);
+
+        // A new config keeps the clock and the profile tracking, in all clones.
+        let shared = schedule.clone();
+        shared.set_config(ScheduleConfig {
+            utc_offset: Some(0.0),
+            ..config.clone()
+        });
+        assert_eq!(schedule.time_of_day().to_string(), "00:00");
+        assert_eq!(schedule.profile_change(), None);
+
+        // Without an offset the local time zone is used.
+        shared.set_config(ScheduleConfig {
+            utc_offset: None,
+            ..config
+        });
+        let local = chrono::DateTime::<chrono::Local>::from(clock.system_time());
+        assert_eq!(
+            schedule.time_of_day().to_string(),
+            local.format("%H:%M").to_string()
+        );
+    }
+}
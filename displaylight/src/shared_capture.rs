@@ -0,0 +1,612 @@
+//! Screen capture shared by the sources of multiple outputs, see [`crate::outputs`].
+//!
+//! Each display is captured in full at most once per frame, the sources crop the region of their
+//! capture specification from that image. [`SharedCapture::next_frame`] starts a new frame, after
+//! which the displays are captured again when the first source asks for them.
+//!
+//! A source can also span regions of multiple displays, like for one strip behind two monitors.
+//! The regions are placed side by side in one image, such that the zones along its edges follow
+//! the monitor they are in front of and the leds are composed into one canvas.
+use crate::capture::{self, Screen, SpanPart};
+use crate::events::{Event, Events};
+use crate::pipeline::{Acquired, Source};
+use crate::CaptureSpecification;
+use log::{debug, error, info, warn};
+use screen_capture::{Capture, ImageBGR, Resolution, BGR};
+
+use std::cell::{OnceCell, RefCell};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::rc::Rc;
+
+/// The result of capturing a display for the current frame.
+#[derive(Clone)]
+enum Grabbed {
+    Image(Rc<dyn ImageBGR>),
+    /// Capturing failed, with the number of consecutive failures.
+    Failed(usize),
+    /// Capturing failed too often, the grabber is set up again on the next frame.
+    Reset,
+}
+
+/// Captures one display in full.
+struct Grabber {
+    capture: Box<dyn Capture>,
+    display: u32,
+    /// The resolution the capture was prepared for.
+    prepared: Option<Resolution>,
+    consecutive_fails: usize,
+    /// The result for the current frame, None if it wasn't captured yet.
+    grabbed: Option<Grabbed>,
+}
+
+impl Grabber {
+    fn grab(&mut self) -> Result<Rc<dyn ImageBGR>, Box<dyn Error>> {
+        let resolution = self.capture.resolution();
+        if self.prepared != Some(resolution) {
+            self.capture.prepare_capture(
+                self.display,
+                0,
+                0,
+                resolution.width,
+                resolution.height,
+            )?;
+            self.prepared = Some(resolution);
+        }
+        self.capture.capture_image()?;
+        Ok(Rc::from(self.capture.image()?))
+    }
+}
+
+/// Function that sets up the capture of a display, see [`SharedCapture::with_opener`].
+pub type CaptureOpener = Box<dyn Fn(u32) -> Result<Box<dyn Capture>, Box<dyn Error>>>;
+
+#[derive(Default)]
+struct State {
+    grabbers: BTreeMap<u32, Grabber>,
+    /// Sets up the capture of a display, [`screen_capture::capture`] if None.
+    opener: Option<CaptureOpener>,
+}
+
+impl State {
+    /// The grabber of the display, set up if there is none yet. The bool is true if it was set up.
+    fn grabber(&mut self, display: u32) -> Result<(&mut Grabber, bool), Box<dyn Error>> {
+        let created = !self.grabbers.contains_key(&display);
+        if created {
+            let capture = match self.opener.as_ref() {
+                Some(opener) => opener(display)?,
+                None => screen_capture::capture()?,
+            };
+            info!("Shared screen capture set up for display {display}");
+            self.grabbers.insert(
+                display,
+                Grabber {
+                    capture,
+                    display,
+                    prepared: None,
+                    consecutive_fails: 0,
+                    grabbed: None,
+                },
+            );
+        }
+        Ok((self.grabbers.get_mut(&display).unwrap(), created))
+    }
+}
+
+/// Handle to the shared capture, each output's source holds a clone. The sources must run on the
+/// same thread.
+#[derive(Default, Clone)]
+pub struct SharedCapture {
+    state: Rc<RefCell<State>>,
+}
+
+impl SharedCapture {
+    /// The number of consecutive capture failures after which the grabber of a display is reset.
+    const MAX_CONSECUTIVE_FAILS: usize = 10;
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create a shared capture that sets up the capture of each display with the opener.
+    pub fn with_opener(opener: CaptureOpener) -> Self {
+        let shared = SharedCapture::new();
+        shared.state.borrow_mut().opener = Some(opener);
+        shared
+    }
+
+    /// Start a new frame, the displays are captured again when they are next requested.
+    pub fn next_frame(&self) {
+        for grabber in self.state.borrow_mut().grabbers.values_mut() {
+            grabber.grabbed = None;
+        }
+    }
+
+    /// Drop the grabbers, they are set up again when next requested.
+    pub fn reset(&self) {
+        self.state.borrow_mut().grabbers.clear();
+    }
+
+    /// The resolution as reported by the grabber of the display. The bool is true if the grabber
+    /// was set up by this call.
+    fn resolution(&self, display: u32) -> Result<(Resolution, bool), Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        let (grabber, created) = state.grabber(display)?;
+        Ok((grabber.capture.resolution(), created))
+    }
+
+    /// The image of the display for the current frame, captured if it wasn't yet.
+    fn image(&self, display: u32) -> Result<Grabbed, Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        let (grabber, _) = state.grabber(display)?;
+        if let Some(grabbed) = grabber.grabbed.as_ref() {
+            return Ok(grabbed.clone());
+        }
+        let grabbed = match grabber.grab() {
+            Ok(image) => {
+                grabber.consecutive_fails = 0;
+                Grabbed::Image(image)
+            }
+            Err(e) => {
+                grabber.consecutive_fails += 1;
+                debug!(
+                    "Capturing display {display} failed {} times: {e:?}",
+                    grabber.consecutive_fails
+                );
+                if grabber.consecutive_fails > Self::MAX_CONSECUTIVE_FAILS {
+                    warn!(
+                        "Got {} consecutive capture fails, resetting grabber of display {display}; {e:?}",
+                        grabber.consecutive_fails
+                    );
+                    state.grabbers.remove(&display);
+                    return Ok(Grabbed::Reset);
+                }
+                Grabbed::Failed(grabber.consecutive_fails)
+            }
+        };
+        grabber.grabbed = Some(grabbed.clone());
+        Ok(grabbed)
+    }
+}
+
+/// View on a region of a shared image.
+struct Crop {
+    image: Rc<dyn ImageBGR>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    /// The pixels of the region, only copied if they are requested as a whole.
+    data: OnceCell<Vec<BGR>>,
+}
+
+impl Crop {
+    /// Crop the region of the specification, limited to the image.
+    fn new(image: Rc<dyn ImageBGR>, spec: &CaptureSpecification) -> Self {
+        let x = spec.x.min(image.width());
+        let y = spec.y.min(image.height());
+        Crop {
+            x,
+            y,
+            width: spec.width.min(image.width() - x),
+            height: spec.height.min(image.height() - y),
+            image,
+            data: OnceCell::new(),
+        }
+    }
+}
+
+impl ImageBGR for Crop {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> BGR {
+        self.image.pixel(self.x + x, self.y + y)
+    }
+
+    fn data(&self) -> &[BGR] {
+        if self.width == self.image.width() && self.height == self.image.height() {
+            return self.image.data();
+        }
+        self.data.get_or_init(|| {
+            let mut data = Vec::with_capacity((self.width * self.height) as usize);
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    data.push(self.pixel(x, y));
+                }
+            }
+            data
+        })
+    }
+}
+
+/// Regions of multiple displays placed side by side in one image, from left to right. Each region
+/// is scaled to the height of the image, keeping its aspect ratio.
+struct Span {
+    /// The regions and the x offset of each in the image.
+    parts: Vec<(Crop, u32)>,
+    width: u32,
+    height: u32,
+    /// The pixels of the image, only composed if they are requested as a whole.
+    data: OnceCell<Vec<BGR>>,
+}
+
+impl Span {
+    fn new(crops: Vec<Crop>) -> Self {
+        let height = crops.iter().map(|c| c.height).max().unwrap_or(0);
+        let mut parts = Vec::with_capacity(crops.len());
+        let mut width = 0;
+        for crop in crops {
+            let part_width = (crop.width as u64 * height as u64 / crop.height.max(1) as u64) as u32;
+            parts.push((crop, width));
+            width += part_width;
+        }
+        Span {
+            parts,
+            width,
+            height,
+            data: OnceCell::new(),
+        }
+    }
+}
+
+impl ImageBGR for Span {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> BGR {
+        // The last part starting at or before x holds it.
+        let index = self.parts.partition_point(|(_, offset)| *offset <= x) - 1;
+        let (crop, offset) = &self.parts[index];
+        let scale = |v: u32, size: u32| {
+            ((v as u64 * crop.height as u64 / self.height as u64) as u32).min(size - 1)
+        };
+        crop.pixel(scale(x - offset, crop.width), scale(y, crop.height))
+    }
+
+    fn data(&self) -> &[BGR] {
+        self.data.get_or_init(|| {
+            let mut data = Vec::with_capacity((self.width * self.height) as usize);
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    data.push(self.pixel(x, y));
+                }
+            }
+            data
+        })
+    }
+}
+
+/// One region captured by the source, see [`SpanPart`].
+struct Part {
+    /// The display whose resolution the specifications are matched against.
+    display: u32,
+    specs: Vec<CaptureSpecification>,
+    /// The specification that matched the resolution.
+    spec: CaptureSpecification,
+    /// The resolution the specifications were matched for.
+    resolution: Option<Resolution>,
+}
+
+/// Source that crops the region of its capture specifications from the shared capture. Spanning
+/// multiple regions they are placed side by side in one image, see [`SpanPart`].
+pub struct SharedCaptureSource {
+    shared: SharedCapture,
+    /// Whether this source starts the frames of the shared capture, because it is the only user.
+    owns_capture: bool,
+    parts: Vec<Part>,
+
+    events: Events,
+}
+
+impl SharedCaptureSource {
+    /// Create a new source, the first capture specification to match the resolution of the
+    /// display is used. The capture setup, failures and resolution changes are emitted to the
+    /// events.
+    pub fn new(
+        shared: &SharedCapture,
+        specs: &[CaptureSpecification],
+        display: u32,
+        events: Events,
+    ) -> Self {
+        SharedCaptureSource::create(
+            shared.clone(),
+            false,
+            vec![(display, specs.to_vec())],
+            events,
+        )
+    }
+
+    /// Create a source spanning the regions of the parts, from left to right. Without a shared
+    /// capture the source uses one of its own.
+    pub fn spanning(shared: Option<&SharedCapture>, parts: &[SpanPart], events: Events) -> Self {
+        SharedCaptureSource::create(
+            shared.cloned().unwrap_or_default(),
+            shared.is_none(),
+            parts
+                .iter()
+                .map(|p| (p.display, p.capture.clone()))
+                .collect(),
+            events,
+        )
+    }
+
+    fn create(
+        shared: SharedCapture,
+        owns_capture: bool,
+        specs: Vec<(u32, Vec<CaptureSpecification>)>,
+        events: Events,
+    ) -> Self {
+        SharedCaptureSource {
+            shared,
+            owns_capture,
+            parts: specs
+                .into_iter()
+                .map(|(display, specs)| Part {
+                    display,
+                    specs,
+                    spec: Default::default(),
+                    resolution: None,
+                })
+                .collect(),
+            events,
+        }
+    }
+
+    /// Match the specifications again on the next frame.
+    fn invalidate(&mut self) {
+        for part in self.parts.iter_mut() {
+            part.resolution = None;
+        }
+    }
+}
+
+impl Source for SharedCaptureSource {
+    fn reset(&mut self) {
+        // Set up the capture again, the displays may have changed while suspended.
+        self.shared.reset();
+        self.invalidate();
+    }
+
+    fn acquire(&mut self) -> Acquired {
+        if self.owns_capture {
+            self.shared.next_frame();
+        }
+        // Each part is matched against the resolution of its own display.
+        for part in self.parts.iter_mut() {
+            let resolution = match self.shared.resolution(part.display) {
+                Ok((resolution, created)) => {
+                    if created {
+                        self.events.emit(&Event::CaptureSetup);
+                    }
+                    resolution
+                }
+                Err(e) => {
+                    error!("Setting up grabber failed: {e:?}");
+                    self.events
+                        .emit(&Event::CaptureSetupFailed(format!("{e:?}")));
+                    return Acquired::Unavailable;
+                }
+            };
+            if part.resolution == Some(resolution) {
+                continue;
+            }
+            let width = resolution.width;
+            let height = resolution.height;
+            let screen = Screen {
+                width,
+                height,
+                display: part.display,
+            };
+            let matched = capture::explain(&screen, &part.specs);
+            for reason in matched.reasons.iter() {
+                debug!("{reason}");
+            }
+            part.spec = matched.specification;
+            let spec = part.spec;
+            info!(
+                "Resolution of display {} is {width}x{height}, capturing {spec:?}",
+                part.display
+            );
+            self.events.emit(&Event::ResolutionChanged {
+                width,
+                height,
+                specification: spec,
+            });
+            part.resolution = Some(resolution);
+        }
+
+        let mut crops = Vec::with_capacity(self.parts.len());
+        for part in self.parts.iter() {
+            match self.shared.image(part.spec.display) {
+                Ok(Grabbed::Image(image)) => crops.push(Crop::new(image, &part.spec)),
+                Ok(Grabbed::Failed(count)) => {
+                    self.events.emit(&Event::CaptureFailed(count));
+                    return Acquired::Failed;
+                }
+                Ok(Grabbed::Reset) => {
+                    self.invalidate();
+                    self.events.emit(&Event::CaptureReset);
+                    return Acquired::Unavailable;
+                }
+                Err(e) => {
+                    error!("Setting up grabber failed: {e:?}");
+                    self.events
+                        .emit(&Event::CaptureSetupFailed(format!("{e:?}")));
+                    return Acquired::Unavailable;
+                }
+            }
+        }
+        if crops.len() == 1 {
+            return Acquired::Image(Box::new(crops.remove(0)));
+        }
+        Acquired::Image(Box::new(Span::new(crops)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screen_capture::raster_image::RasterImageBGR;
+
+    /// Capture of a display showing a fixed image.
+    struct FakeCapture(RasterImageBGR);
+
+    impl Capture for FakeCapture {
+        fn capture_image(&mut self) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn image(&mut self) -> Result<Box<dyn ImageBGR>, Box<dyn Error>> {
+            Ok(Box::new(self.0.clone()))
+        }
+
+        fn resolution(&mut self) -> Resolution {
+            Resolution {
+                width: self.0.width(),
+                height: self.0.height(),
+            }
+        }
+
+        fn prepare_capture(
+            &mut self,
+            _display: u32,
+            _x: u32,
+            _y: u32,
+            _width: u32,
+            _height: u32,
+        ) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+    }
+
+    /// Shared capture of a red 40x20 display 0 and a blue 30x10 display 1.
+    fn two_displays() -> SharedCapture {
+        SharedCapture::with_opener(Box::new(|display| {
+            let image = match display {
+                0 => RasterImageBGR::filled(40, 20, BGR { r: 255, g: 0, b: 0 }),
+                1 => RasterImageBGR::filled(30, 10, BGR { r: 0, g: 0, b: 255 }),
+                _ => return Err("No such display.".into()),
+            };
+            Ok(Box::new(FakeCapture(image)))
+        }))
+    }
+
+    fn acquire(source: &mut SharedCaptureSource) -> Box<dyn ImageBGR> {
+        match source.acquire() {
+            Acquired::Image(image) => image,
+            _ => panic!("Should capture."),
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        // The specifications are matched against the resolution of the source's display.
+        let shared = two_displays();
+        let specs: Vec<CaptureSpecification> = serde_yaml::from_str(
+            "[{match_display: 0, width: 1},
+              {match_display: 1, match_width: 30, display: 1, region: {width: 0.5}}]",
+        )
+        .unwrap();
+        let mut source = SharedCaptureSource::new(&shared, &specs, 1, Default::default());
+        shared.next_frame();
+        let image = acquire(&mut source);
+        assert_eq!((image.width(), image.height()), (15, 10));
+        assert_eq!(image.pixel(0, 0).b, 255);
+
+        // Without a match the display is captured in full.
+        let mut source = SharedCaptureSource::new(&shared, &[], 1, Default::default());
+        let image = acquire(&mut source);
+        assert_eq!((image.width(), image.height()), (30, 10));
+    }
+
+    #[test]
+    fn test_spanning_displays() {
+        // Each part is matched against and resolved to the resolution of its own display, the
+        // right half of display 0 is 20x20 and the left half of display 1 is 15x10.
+        let parts: Vec<SpanPart> = serde_yaml::from_str(
+            "[{display: 0, capture: [{match_width: 40, region: {x: 0.5}}]},
+              {display: 1, capture: [{match_width: 30, display: 1, region: {width: 0.5}}]}]",
+        )
+        .unwrap();
+        let mut source =
+            SharedCaptureSource::spanning(Some(&two_displays()), &parts, Default::default());
+        let image = acquire(&mut source);
+        // Scaled to the height of 20, the right part is 30 wide.
+        assert_eq!((image.width(), image.height()), (50, 20));
+        assert_eq!(image.pixel(19, 19).r, 255);
+        assert_eq!(image.pixel(20, 0).b, 255);
+        assert_eq!(image.pixel(49, 19).b, 255);
+
+        // Without specifications each part is its display in full.
+        let parts: Vec<SpanPart> = serde_yaml::from_str("[{display: 0}, {display: 1}]").unwrap();
+        let mut source = SharedCaptureSource::spanning(None, &parts, Default::default());
+        source.shared = two_displays();
+        let image = acquire(&mut source);
+        assert_eq!((image.width(), image.height()), (40 + 60, 20));
+    }
+
+    #[test]
+    fn test_crop() {
+        let mut img = RasterImageBGR::filled(40, 20, Default::default());
+        img.set_gradient(0, 40, 0, 20);
+        let image: Rc<dyn ImageBGR> = Rc::new(img.clone());
+        let spec = CaptureSpecification {
+            x: 30,
+            y: 5,
+            width: 20,
+            height: 10,
+            ..Default::default()
+        };
+        let crop = Crop::new(image.clone(), &spec);
+        assert_eq!((crop.width(), crop.height()), (10, 10));
+        assert_eq!(crop.pixel(0, 0), img.pixel(30, 5));
+        assert_eq!(crop.data().len(), 100);
+        assert_eq!(crop.data()[11], img.pixel(31, 6));
+
+        // The full image isn't copied.
+        let spec = capture::explain(
+            &Screen {
+                width: 40,
+                height: 20,
+                display: 0,
+            },
+            &[],
+        )
+        .specification;
+        let full = Crop::new(image, &spec);
+        assert_eq!(full.data().as_ptr(), full.image.data().as_ptr());
+    }
+
+    #[test]
+    fn test_span() {
+        // A 40x20 monitor on the left and a 20x10 one on the right, scaled to the same height.
+        let left = RasterImageBGR::filled(40, 20, BGR { r: 255, g: 0, b: 0 });
+        let mut right = RasterImageBGR::filled(20, 10, BGR { r: 0, g: 0, b: 255 });
+        right.set_pixel(19, 9, BGR { r: 0, g: 255, b: 0 });
+        let crop = |img: RasterImageBGR| {
+            let spec = CaptureSpecification {
+                width: img.width(),
+                height: img.height(),
+                ..Default::default()
+            };
+            Crop::new(Rc::new(img), &spec)
+        };
+        let span = Span::new(vec![crop(left), crop(right)]);
+        assert_eq!((span.width(), span.height()), (80, 20));
+        assert_eq!(span.pixel(39, 0).r, 255);
+        assert_eq!(span.pixel(40, 0).b, 255);
+        assert_eq!(span.pixel(79, 19).g, 255);
+        assert_eq!(span.data().len(), 80 * 20);
+    }
+}
@@ -0,0 +1,390 @@
+//! The default implementations of the [`crate::pipeline`] stages, as configured by [`Config`].
+use crate::border_detection::{self, RectangleChangeLimiter};
+use crate::brightness::AdaptiveBrightness;
+use crate::color::FloorConfig;
+use crate::compensation::CompensationMap;
+use crate::events::{Event, Events};
+use crate::pipeline::{
+    Acquired, BorderDetector, ColorFilter, Sink, Source, ZoneMapper, ZoneSampler,
+};
+use crate::rectangle::Rectangle;
+use crate::sampler::Sampler;
+use crate::schedule::Schedule;
+use crate::{zones, CaptureSpecification, Config};
+use lights::RGB;
+use log::{debug, error, info, warn};
+use screen_capture::{Capture, ImageBGR, Resolution};
+
+use std::error::Error;
+use std::time::Instant;
+
+/// Source that captures the screen, configured by the capture specifications.
+pub struct ScreenCaptureSource {
+    grabber: Option<Box<dyn Capture>>,
+    specs: Vec<CaptureSpecification>,
+    /// The display whose resolution the specifications are matched against.
+    display: u32,
+
+    /// The resolution is used for the capture setup and config retrieval, store the old value.
+    cached_resolution: Option<Resolution>,
+
+    consecutive_capture_fails: usize,
+
+    events: Events,
+}
+
+impl ScreenCaptureSource {
+    /// The number of consecutive capture failures after which the grabber is reset.
+    const MAX_CONSECUTIVE_FAILS: usize = 10;
+
+    /// Create a new source, the first capture specification to match the resolution is used. The
+    /// capture setup, failures and resolution changes are emitted to the events.
+    pub fn new(specs: &[CaptureSpecification], events: Events) -> Self {
+        ScreenCaptureSource {
+            grabber: None,
+            specs: specs.to_vec(),
+            display: 0,
+            cached_resolution: None,
+            consecutive_capture_fails: 0,
+            events,
+        }
+    }
+}
+
+impl ScreenCaptureSource {
+    /// Match the specifications against the resolution of this display, 0 by default.
+    pub fn set_display(&mut self, display: u32) {
+        self.display = display;
+        self.cached_resolution = None;
+    }
+}
+
+impl Source for ScreenCaptureSource {
+    fn reset(&mut self) {
+        // Prepare the capture again, the displays may have changed while suspended.
+        self.cached_resolution = None;
+        self.consecutive_capture_fails = 0;
+    }
+
+    fn acquire(&mut self) -> Acquired {
+        // If the grabber isn't setup yet, try to set it up.
+        if self.grabber.is_none() {
+            let grabber = screen_capture::capture();
+            // Ensure we also clear the cached resolution, such that we actually prepare the capture again.
+            self.cached_resolution = None;
+            match grabber {
+                Ok(g) => {
+                    info!("Screen capture set up");
+                    self.grabber = Some(g);
+                    self.events.emit(&Event::CaptureSetup);
+                }
+                Err(e) => {
+                    error!("Setting up grabber failed: {e:?}");
+                    self.events
+                        .emit(&Event::CaptureSetupFailed(format!("{e:?}")));
+                    return Acquired::Unavailable;
+                }
+            }
+        }
+        let grabber = self.grabber.as_mut().unwrap();
+
+        // First, check if the resolution of the desktop environment has changed, if so, act.
+        let current_resolution = grabber.resolution();
+        if self.cached_resolution.is_none()
+            || *self.cached_resolution.as_ref().unwrap() != current_resolution
+        {
+            let width = current_resolution.width;
+            let height = current_resolution.height;
+
+            // Resolution has changed, figure out the best match in our configurations and
+            // prepare the capture accordingly.
+            let screen = crate::capture::Screen {
+                width,
+                height,
+                display: self.display,
+            };
+            let matched = crate::capture::explain(&screen, &self.specs);
+            for reason in matched.reasons.iter() {
+                debug!("{reason}");
+            }
+            let spec = matched.specification;
+            info!("Resolution is {width}x{height}, capturing {spec:?}");
+            self.events.emit(&Event::ResolutionChanged {
+                width,
+                height,
+                specification: spec,
+            });
+
+            if let Err(e) =
+                grabber.prepare_capture(spec.display, spec.x, spec.y, spec.width, spec.height)
+            {
+                error!("Failed preparing capture {e:?}");
+                self.grabber = None;
+                self.events.emit(&Event::CaptureReset);
+                return Acquired::Unavailable;
+            };
+            // Store the current resolution.
+            self.cached_resolution = Some(current_resolution);
+        }
+
+        // Now, we are ready to try and get the image:
+        let res = grabber.capture_image();
+        if let Err(e) = res {
+            self.consecutive_capture_fails += 1;
+            self.events
+                .emit(&Event::CaptureFailed(self.consecutive_capture_fails));
+            debug!(
+                "Capturing image failed {} times: {e:?}",
+                self.consecutive_capture_fails
+            );
+            if self.consecutive_capture_fails > Self::MAX_CONSECUTIVE_FAILS {
+                warn!(
+                    "Got {} consecutive capture fails, resetting grabber; {e:?}",
+                    self.consecutive_capture_fails
+                );
+                self.grabber = None;
+                self.consecutive_capture_fails = 0;
+                self.events.emit(&Event::CaptureReset);
+                return Acquired::Unavailable;
+            }
+            // Getting the image failed... :( Lets wait a bit and try again.
+            // Lets keep the leds at the old color. May make failures less noticable, but uac on windows doesn't
+            // look ugly when we can't grab the image for a while.
+            return Acquired::Failed;
+        }
+
+        // Then, we can grab the actual image.
+        match grabber.image() {
+            Ok(img) => {
+                self.consecutive_capture_fails = 0;
+                Acquired::Image(img)
+            }
+            Err(e) => {
+                self.consecutive_capture_fails += 1;
+                self.events
+                    .emit(&Event::CaptureFailed(self.consecutive_capture_fails));
+                warn!(
+                    "Failed to retrieve {} images, error: {e:?}",
+                    self.consecutive_capture_fails
+                );
+                Acquired::Failed
+            }
+        }
+    }
+}
+
+/// Detects the black borders and rate limits the changes to avoid flickering.
+pub struct BlackBorderDetector {
+    /// If false, always use the full width and height of the image.
+    pub enabled: bool,
+    /// The number of bisections to perform on each side.
+    pub bisect_count: u32,
+    /// Only change the borders if the detected borders are rectangular.
+    pub rectangular_only: bool,
+
+    border_rate_limiter: RectangleChangeLimiter,
+    initialised: bool,
+    /// Whether the rate limited borders reached the detected borders.
+    settled: bool,
+}
+
+impl BlackBorderDetector {
+    /// Create a detector from the edge detection fields of the config.
+    pub fn new(config: &Config) -> Self {
+        BlackBorderDetector {
+            enabled: config.edge_detection_enable,
+            bisect_count: config.edge_detection_bisect_count,
+            rectangular_only: config.edge_detection_rectangular_only,
+            border_rate_limiter: RectangleChangeLimiter::new(
+                config.edge_horizontal_change_per_s,
+                config.edge_vertical_change_per_s,
+            ),
+            initialised: false,
+            settled: false,
+        }
+    }
+}
+
+impl BorderDetector for BlackBorderDetector {
+    fn reset(&mut self) {
+        self.border_rate_limiter.reset_time(&Instant::now());
+    }
+
+    fn unchanged(&mut self) -> bool {
+        if !(self.initialised && self.settled) {
+            return false;
+        }
+        // Keep the limiter's time current, such that the next change is still rate limited.
+        let current = self.border_rate_limiter.rectangle();
+        self.border_rate_limiter.update(&current, &Instant::now());
+        true
+    }
+
+    fn detect(&mut self, image: &dyn ImageBGR) -> Option<Rectangle> {
+        // Detect the black borders if we are configured to do so.
+        let borders = if self.enabled {
+            border_detection::find_borders(image, self.bisect_count, self.rectangular_only)
+        } else {
+            Some(Rectangle {
+                x_min: 0,
+                y_min: 0,
+                x_max: image.width() - 1,
+                y_max: image.height() - 1,
+            })
+        };
+
+        let now = Instant::now();
+        match borders {
+            Some(borders) => {
+                // First update, force the border rate change.
+                if !self.initialised {
+                    self.border_rate_limiter.set(&borders, &now);
+                    self.initialised = true;
+                }
+                self.border_rate_limiter.update(&borders, &now);
+                self.settled = self.border_rate_limiter.rectangle() == borders;
+            }
+            None => {
+                // Keep the limiter's time current, such that the next change is still rate limited.
+                let current = self.border_rate_limiter.rectangle();
+                self.border_rate_limiter.update(&current, &now);
+                self.settled = true;
+            }
+        }
+        if self.initialised {
+            Some(self.border_rate_limiter.rectangle())
+        } else {
+            None
+        }
+    }
+}
+
+/// Zones along the edges of the region of interest, see [`zones::Zones`].
+pub struct EdgeZones {
+    /// The number of leds along each side.
+    pub layout: zones::Layout,
+    /// The depth in pixels of the horizontal cells at the left and right.
+    pub horizontal_depth: u32,
+    /// The depth in pixels of the vertical cells at the top and bottom.
+    pub vertical_depth: u32,
+}
+
+impl ZoneMapper for EdgeZones {
+    fn zones(&mut self, borders: &Rectangle) -> Vec<Rectangle> {
+        zones::Zones::make_zones(
+            borders,
+            &self.layout,
+            self.horizontal_depth,
+            self.vertical_depth,
+        )
+    }
+}
+
+/// Samples the zones on a grid of points, see [`Sampler`].
+pub struct GridSampler {
+    /// The distance between sampled pixels in the zones.
+    pub sample_pixel_distance: u32,
+    /// Whether or not to diagonalize the points to be sampled.
+    pub diagonalize_points: bool,
+
+    sampler: Option<Sampler>,
+}
+
+impl GridSampler {
+    pub fn new(sample_pixel_distance: u32, diagonalize_points: bool) -> Self {
+        GridSampler {
+            sample_pixel_distance,
+            diagonalize_points,
+            sampler: None,
+        }
+    }
+}
+
+impl ZoneSampler for GridSampler {
+    fn prepare(&mut self, zones: &[Rectangle]) {
+        self.sampler = Some(Sampler::make_sampler(
+            zones,
+            self.sample_pixel_distance,
+            self.diagonalize_points,
+        ));
+    }
+
+    fn sample(&mut self, image: &dyn ImageBGR, leds: &mut [RGB]) {
+        if let Some(sampler) = self.sampler.as_ref() {
+            sampler.sample_into(image, leds);
+        }
+    }
+}
+
+/// Scales to the overall brightness, optionally adapted to the average picture level.
+pub struct Brightness {
+    /// The limiting factor for the overall led brightness.
+    pub limiting_factor: f32,
+    /// Optional adaptive brightness.
+    pub adaptive: Option<AdaptiveBrightness>,
+    /// Optional schedule, its limiting factor replaces the one above and its color temperature
+    /// tints the leds. This is a handle to the schedule of the pipeline, see
+    /// [`crate::pipeline::Pipeline::schedule`].
+    pub schedule: Option<Schedule>,
+}
+
+impl ColorFilter for Brightness {
+    fn reset(&mut self) {
+        if let Some(adaptive) = self.adaptive.as_mut() {
+            adaptive.reset();
+        }
+    }
+
+    fn apply(&mut self, leds: &mut [RGB]) {
+        let mut brightness = self.limiting_factor;
+        if let Some(schedule) = self.schedule.as_ref() {
+            let now = schedule.now();
+            brightness = now.limiting_factor.unwrap_or(brightness);
+            if let Some(kelvin) = now.color_temperature {
+                crate::color::tint(leds, crate::color::temperature_gains(kelvin));
+            }
+        }
+        if let Some(adaptive) = self.adaptive.as_mut() {
+            brightness *= adaptive.update(leds, &Instant::now());
+        }
+        crate::color::scale(leds, brightness);
+    }
+}
+
+/// Ensures the leds never drop below the floor.
+pub struct Floor(pub FloorConfig);
+
+impl ColorFilter for Floor {
+    fn apply(&mut self, leds: &mut [RGB]) {
+        self.0.apply(leds);
+    }
+}
+
+/// Compensates for the differences in brightness between the leds.
+pub struct Compensation(pub CompensationMap);
+
+impl ColorFilter for Compensation {
+    fn apply(&mut self, leds: &mut [RGB]) {
+        self.0.apply(leds);
+    }
+}
+
+impl Sink for lights::Lights {
+    fn write(&mut self, leds: &[RGB]) -> Result<(), Box<dyn Error>> {
+        self.set_leds(leds)
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.resync()
+    }
+}
+
+impl Sink for lights::Segments {
+    fn write(&mut self, leds: &[RGB]) -> Result<(), Box<dyn Error>> {
+        self.set_leds(leds)
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.resync()
+    }
+}
@@ -0,0 +1,755 @@
+//! Validation of the config, reporting every problem with the path of the field and the reason.
+//!
+//! Values that would panic or underflow while running are rejected up front, like a `rate` of
+//! zero, depths that exceed the captured region or capture offsets outside the matched resolution.
+use crate::capture::Region;
+use crate::exit::ExitBehavior;
+use crate::{CaptureSpecification, Config};
+use serde_yaml::Value;
+
+/// A problem with a single field.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Problem {
+    /// The path of the field, like `capture[1].x`.
+    pub path: String,
+    /// Why the value is invalid.
+    pub reason: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+/// All problems found in a config, this is the error returned by [`Config::validate`].
+#[derive(Debug, PartialEq, Clone)]
+pub struct Problems(pub Vec<Problem>);
+
+impl std::fmt::Display for Problems {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines: Vec<String> = self.0.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::error::Error for Problems {}
+
+/// Collects the problems while checking the fields.
+#[derive(Default)]
+struct Checker {
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    fn fail(&mut self, path: &str, reason: String) {
+        self.problems.push(Problem {
+            path: path.to_owned(),
+            reason,
+        });
+    }
+
+    fn positive(&mut self, path: &str, value: f32) {
+        if !(value.is_finite() && value > 0.0) {
+            self.fail(path, format!("must be larger than 0, got {}", value));
+        }
+    }
+
+    fn non_negative(&mut self, path: &str, value: f32) {
+        if !(value.is_finite() && value >= 0.0) {
+            self.fail(path, format!("must be 0 or larger, got {}", value));
+        }
+    }
+
+    fn fraction(&mut self, path: &str, value: f32) {
+        if !(0.0..=1.0).contains(&value) {
+            self.fail(path, format!("must be between 0.0 and 1.0, got {}", value));
+        }
+    }
+
+    fn at_least_one(&mut self, path: &str, value: u32) {
+        if value == 0 {
+            self.fail(path, "must be at least 1".to_owned());
+        }
+    }
+
+    /// Report the keys of the value that the known value doesn't have, recursing into mappings
+    /// and sequences that both have.
+    fn unknown_fields(&mut self, path: &str, value: &Value, known: &Value) {
+        match (value, known) {
+            (Value::Mapping(value), Value::Mapping(known)) => {
+                for (key, value) in value.iter() {
+                    let name = match key.as_str() {
+                        Some(name) => name.to_owned(),
+                        None => format!("{:?}", key),
+                    };
+                    let path = if path.is_empty() {
+                        name
+                    } else {
+                        format!("{}.{}", path, name)
+                    };
+                    match known.get(key) {
+                        Some(known) => self.unknown_fields(&path, value, known),
+                        None => self.fail(&path, "unknown field".to_owned()),
+                    }
+                }
+            }
+            (Value::Sequence(value), Value::Sequence(known)) => {
+                for (i, (value, known)) in value.iter().zip(known.iter()).enumerate() {
+                    self.unknown_fields(&format!("{}[{}]", path, i), value, known);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Check the leds can be laid out and the segments cover them, each with its own port.
+    fn segments(&mut self, config: &Config) {
+        if config.leds < 4 {
+            self.fail(
+                "leds",
+                format!("must be at least 4, one for each side, got {}", config.leds),
+            );
+        }
+        if config.segments.is_empty() {
+            if config.leds > lights::Lights::MAX_LEDS {
+                self.fail(
+                    "leds",
+                    format!(
+                        "must not exceed the {} leds of one microcontroller without segments, got {}",
+                        lights::Lights::MAX_LEDS,
+                        config.leds
+                    ),
+                );
+            }
+            return;
+        }
+        for (i, segment) in config.segments.iter().enumerate() {
+            if segment.port.is_empty() {
+                self.fail(
+                    &format!("segments[{}].port", i),
+                    "must be a port name or 'auto'".to_owned(),
+                );
+            } else if segment.port != Config::PORT_AUTO {
+                if let Some(first) = config.segments[..i]
+                    .iter()
+                    .position(|s| s.port == segment.port)
+                {
+                    self.fail(
+                        &format!("segments[{}].port", i),
+                        format!("'{}' is used by segments[{}] as well", segment.port, first),
+                    );
+                }
+            }
+            if !(1..=lights::Lights::MAX_LEDS).contains(&segment.leds) {
+                self.fail(
+                    &format!("segments[{}].leds", i),
+                    format!(
+                        "must be between 1 and {}, got {}",
+                        lights::Lights::MAX_LEDS,
+                        segment.leds
+                    ),
+                );
+            }
+        }
+        let total: usize = config.segments.iter().map(|s| s.leds).sum();
+        let layout = crate::zones::Layout::for_leds(config.leds);
+        if total != layout.leds() {
+            self.fail(
+                "segments",
+                format!(
+                    "must hold the {} leds of the layout in total, got {}",
+                    layout.leds(),
+                    total
+                ),
+            );
+        }
+    }
+
+    /// Check a capture specification, the region must fit in the matched resolution.
+    fn capture(&mut self, path: &str, spec: &CaptureSpecification, config: &Config) {
+        let axes = [
+            ("x", "width", spec.match_width, spec.x, spec.width),
+            ("y", "height", spec.match_height, spec.y, spec.height),
+        ];
+        // Whether the offset is outside the matched resolution along x and y.
+        let mut outside = [false; 2];
+        for (i, (offset_name, size_name, matched, offset, size)) in axes.into_iter().enumerate() {
+            if let Some(matched) = matched {
+                if offset >= matched {
+                    outside[i] = true;
+                    self.fail(
+                        &format!("{}.{}", path, offset_name),
+                        format!(
+                            "offset {} is outside the matched {} of {}",
+                            offset, size_name, matched
+                        ),
+                    );
+                } else if offset as u64 + size as u64 > matched as u64 {
+                    self.fail(
+                        &format!("{}.{}", path, size_name),
+                        format!(
+                            "{} + {} exceeds the matched {} of {}",
+                            offset_name, size_name, size_name, matched
+                        ),
+                    );
+                }
+            }
+        }
+
+        let ranges = [
+            ("width", spec.min_width, spec.max_width),
+            ("height", spec.min_height, spec.max_height),
+        ];
+        for (name, min, max) in ranges {
+            if let (Some(min), Some(max)) = (min, max) {
+                if min > max {
+                    self.fail(
+                        &format!("{}.min_{}", path, name),
+                        format!("must not exceed max_{} ({})", name, max),
+                    );
+                }
+            }
+        }
+        if let Some(ratio) = spec.match_aspect_ratio {
+            self.positive(&format!("{}.match_aspect_ratio", path), ratio);
+        }
+
+        // The zones on the left and right are horizontal_depth wide, those on the top and bottom
+        // vertical_depth high. They must fit in the region, as far as its size is known here. An
+        // offset outside the resolution is reported above, the size is unknown then.
+        let (width, height) = match spec.region {
+            Some(region) => {
+                self.region(path, spec, &region);
+                match (spec.match_width, spec.match_height) {
+                    (Some(width), Some(height)) => {
+                        let screen = crate::capture::Screen {
+                            width,
+                            height,
+                            display: config.display,
+                        };
+                        let resolved = spec.resolve(&screen);
+                        (Some(resolved.width), Some(resolved.height))
+                    }
+                    _ => (None, None),
+                }
+            }
+            None => (
+                (spec.width != 0)
+                    .then_some(spec.width)
+                    .or_else(|| spec.match_width.map(|w| w.saturating_sub(spec.x))),
+                (spec.height != 0)
+                    .then_some(spec.height)
+                    .or_else(|| spec.match_height.map(|h| h.saturating_sub(spec.y))),
+            ),
+        };
+        let width = width.filter(|_| !outside[0]);
+        let height = height.filter(|_| !outside[1]);
+        if let Some(width) = width.filter(|w| config.horizontal_depth > *w) {
+            self.fail(
+                "horizontal_depth",
+                format!(
+                    "{} exceeds the width {} captured by {}",
+                    config.horizontal_depth, width, path
+                ),
+            );
+        }
+        if let Some(height) = height.filter(|h| config.vertical_depth > *h) {
+            self.fail(
+                "vertical_depth",
+                format!(
+                    "{} exceeds the height {} captured by {}",
+                    config.vertical_depth, height, path
+                ),
+            );
+        }
+    }
+}
+
+impl Checker {
+    /// Check a fractional region, it replaces the region in pixels and must fit in the screen.
+    fn region(&mut self, path: &str, spec: &CaptureSpecification, region: &Region) {
+        if spec.x != 0 || spec.y != 0 || spec.width != 0 || spec.height != 0 {
+            self.fail(
+                &format!("{}.region", path),
+                "can't be combined with x, y, width or height".to_owned(),
+            );
+        }
+        let axes = [
+            ("x", "width", region.x, region.width),
+            ("y", "height", region.y, region.height),
+        ];
+        for (offset_name, size_name, offset, size) in axes {
+            let offset_path = format!("{}.region.{}", path, offset_name);
+            let size_path = format!("{}.region.{}", path, size_name);
+            self.fraction(&offset_path, offset);
+            self.fraction(&size_path, size);
+            if offset + size > 1.0 {
+                self.fail(
+                    &size_path,
+                    format!("{} + {} exceeds 1.0", offset_name, size_name),
+                );
+            }
+        }
+    }
+}
+
+/// Check the config and its profiles, returns all problems found.
+pub fn check(config: &Config) -> Vec<Problem> {
+    let mut problems = check_fields(config);
+    if let Some(profile) = config.profile.as_ref() {
+        if !config.profiles.contains_key(profile) {
+            problems.push(Problem {
+                path: "profile".to_owned(),
+                reason: format!("unknown profile '{}'", profile),
+            });
+        }
+    }
+
+    for (i, rule) in config.profile_rules.iter().enumerate() {
+        let path = format!("profile_rules[{}]", i);
+        if !config.profiles.contains_key(&rule.profile) {
+            problems.push(Problem {
+                path: format!("{}.profile", path),
+                reason: format!("unknown profile '{}'", rule.profile),
+            });
+        }
+        if rule.letterbox.is_none() && rule.resolution.is_none() {
+            problems.push(Problem {
+                path: path.clone(),
+                reason: "must have a letterbox or resolution condition".to_owned(),
+            });
+        }
+        let mut c = Checker::default();
+        c.non_negative(&format!("{}.stable_for", path), rule.stable_for);
+        if let Some(letterbox) = rule.letterbox {
+            c.non_negative(
+                &format!("{}.letterbox.ratio_margin", path),
+                letterbox.ratio_margin,
+            );
+        }
+        problems.append(&mut c.problems);
+    }
+
+    let keyframes = config.schedule.iter().flat_map(|s| s.keyframes.iter());
+    for (i, keyframe) in keyframes.enumerate() {
+        if let Some(Some(profile)) = keyframe.profile.as_ref() {
+            if !config.profiles.contains_key(profile) {
+                problems.push(Problem {
+                    path: format!("schedule.keyframes[{}].profile", i),
+                    reason: format!("unknown profile '{}'", profile),
+                });
+            }
+        }
+    }
+
+    // Only report the problems of the fields a profile or output sets, the others are reported
+    // above.
+    for (name, overrides) in config.profiles.iter() {
+        let applied = crate::profiles::apply(config, Some(name));
+        problems.append(&mut check_overrides(
+            &format!("profiles.{}", name),
+            applied,
+            overrides,
+        ));
+    }
+    for (i, overrides) in config.outputs.iter().enumerate() {
+        let applied = crate::outputs::apply(config, i);
+        problems.append(&mut check_overrides(
+            &format!("outputs[{}]", i),
+            applied,
+            overrides,
+        ));
+    }
+    problems.append(&mut check_outputs(config));
+    problems
+}
+
+/// Check the yaml value the config was parsed from for fields the config doesn't have, like
+/// misspelled ones, which are ignored while parsing. The fields of profiles and outputs are
+/// checked against the config they apply to.
+pub fn check_unknown(value: &Value, config: &Config) -> Vec<Problem> {
+    let Ok(mut known) = serde_yaml::to_value(config) else {
+        return vec![];
+    };
+    let profiles: serde_yaml::Mapping = config
+        .profiles
+        .keys()
+        .map(|name| {
+            let applied = crate::profiles::apply(config, Some(name))
+                .ok()
+                .and_then(|p| serde_yaml::to_value(p).ok())
+                .unwrap_or(Value::Null);
+            (Value::String(name.clone()), applied)
+        })
+        .collect();
+    let outputs: Vec<Value> = (0..config.outputs.len())
+        .map(|i| {
+            crate::outputs::apply(config, i)
+                .ok()
+                .and_then(|o| serde_yaml::to_value(o).ok())
+                .unwrap_or(Value::Null)
+        })
+        .collect();
+    if let Value::Mapping(known) = &mut known {
+        known.insert("profiles".into(), Value::Mapping(profiles));
+        known.insert("outputs".into(), Value::Sequence(outputs));
+    }
+
+    let mut c = Checker::default();
+    c.unknown_fields("", value, &known);
+    c.problems
+}
+
+/// The problems of the fields set by the overrides, prefixed with the path of the overrides.
+fn check_overrides(
+    path: &str,
+    applied: Result<Config, Box<dyn std::error::Error>>,
+    overrides: &serde_yaml::Value,
+) -> Vec<Problem> {
+    let applied = match applied {
+        Ok(applied) => applied,
+        Err(e) => {
+            return vec![Problem {
+                path: path.to_owned(),
+                reason: e.to_string(),
+            }]
+        }
+    };
+    let overridden = |p: &Problem| {
+        let field = p.path.split(['.', '[']).next().unwrap_or_default();
+        overrides.get(field).is_some()
+    };
+    check_fields(&applied)
+        .into_iter()
+        .filter(overridden)
+        .map(|problem| Problem {
+            path: format!("{}.{}", path, problem.path),
+            reason: problem.reason,
+        })
+        .collect()
+}
+
+/// Check the outputs can be served together, they share the capture and need a port each.
+fn check_outputs(config: &Config) -> Vec<Problem> {
+    let mut c = Checker::default();
+    if config.outputs.len() > 1 && config.threaded {
+        c.fail(
+            "threaded",
+            "multiple outputs share the capture and can't be threaded".to_owned(),
+        );
+    }
+    let ports: Vec<Option<String>> = (0..config.outputs.len())
+        .map(|i| crate::outputs::apply(config, i).ok().map(|o| o.port))
+        .collect();
+    for (i, port) in ports.iter().enumerate() {
+        let Some(port) = port.as_ref().filter(|p| *p != Config::PORT_AUTO) else {
+            continue;
+        };
+        if let Some(first) = ports[..i].iter().position(|p| p.as_ref() == Some(port)) {
+            c.fail(
+                &format!("outputs[{}].port", i),
+                format!("'{}' is used by outputs[{}] as well", port, first),
+            );
+        }
+    }
+    c.problems
+}
+
+/// Check the fields of the config, without its profiles.
+fn check_fields(config: &Config) -> Vec<Problem> {
+    let mut c = Checker::default();
+    c.positive("rate", config.rate);
+    if config.port.is_empty() {
+        c.fail("port", "must be a port name or 'auto'".to_owned());
+    }
+    c.segments(config);
+    c.at_least_one("vertical_depth", config.vertical_depth);
+    c.at_least_one("horizontal_depth", config.horizontal_depth);
+    c.at_least_one("sample_pixel_distance", config.sample_pixel_distance);
+    c.non_negative(
+        "edge_horizontal_change_per_s",
+        config.edge_horizontal_change_per_s,
+    );
+    c.non_negative(
+        "edge_vertical_change_per_s",
+        config.edge_vertical_change_per_s,
+    );
+    c.fraction("limiting_factor", config.limiting_factor);
+
+    c.positive("gamma.r", config.gamma.r);
+    c.positive("gamma.g", config.gamma.g);
+    c.positive("gamma.b", config.gamma.b);
+    if let Some(budget) = config.power_budget {
+        c.positive("power_budget.ma_per_channel", budget.ma_per_channel);
+        c.non_negative("power_budget.idle_ma_per_led", budget.idle_ma_per_led);
+        c.positive("power_budget.budget_ma", budget.budget_ma);
+    }
+    if let Some(adaptive) = config.adaptive_brightness {
+        c.fraction("adaptive_brightness.min", adaptive.min);
+        c.fraction("adaptive_brightness.max", adaptive.max);
+        if adaptive.min > adaptive.max {
+            c.fail(
+                "adaptive_brightness.min",
+                format!("must not exceed max ({})", adaptive.max),
+            );
+        }
+        c.non_negative(
+            "adaptive_brightness.rise_time_constant",
+            adaptive.rise_time_constant,
+        );
+        c.non_negative(
+            "adaptive_brightness.fall_time_constant",
+            adaptive.fall_time_constant,
+        );
+    }
+    if let Some(floor) = config.floor {
+        c.fraction("floor.level", floor.level);
+    }
+
+    c.at_least_one(
+        "unchanged_frame_pixel_stride",
+        config.unchanged_frame_pixel_stride,
+    );
+    c.non_negative("full_refresh_interval", config.full_refresh_interval);
+    c.non_negative("resume_gap", config.resume_gap);
+    c.non_negative("profile_rules_release", config.profile_rules_release);
+    if let ExitBehavior::Fade(duration) = config.exit {
+        c.non_negative("exit.fade", duration);
+    }
+
+    if let Some(schedule) = config.schedule.as_ref() {
+        if let Some(offset) = schedule.utc_offset {
+            if !(-24.0..=24.0).contains(&offset) {
+                c.fail(
+                    "schedule.utc_offset",
+                    format!("must be between -24 and 24, got {}", offset),
+                );
+            }
+        }
+        for (i, keyframe) in schedule.keyframes.iter().enumerate() {
+            let path = format!("schedule.keyframes[{}]", i);
+            if let Some(factor) = keyframe.limiting_factor {
+                c.fraction(&format!("{}.limiting_factor", path), factor);
+            }
+            if let Some(kelvin) = keyframe.color_temperature {
+                if !(1000.0..=40000.0).contains(&kelvin) {
+                    c.fail(
+                        &format!("{}.color_temperature", path),
+                        format!("must be between 1000 and 40000, got {}", kelvin),
+                    );
+                }
+            }
+            if let Some(first) = schedule.keyframes[..i]
+                .iter()
+                .position(|k| k.time == keyframe.time)
+            {
+                c.fail(
+                    &format!("{}.time", path),
+                    format!("{} is used by keyframes[{}] as well", keyframe.time, first),
+                );
+            }
+        }
+    }
+
+    for (i, spec) in config.capture.iter().enumerate() {
+        c.capture(&format!("capture[{}]", i), spec, config);
+    }
+    for (i, part) in config.span.iter().enumerate() {
+        for (j, spec) in part.capture.iter().enumerate() {
+            c.capture(&format!("span[{}].capture[{}]", i, j), spec, config);
+        }
+    }
+    c.problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example config, which is valid.
+    fn example() -> Config {
+        let content = std::fs::read_to_string("config/linux.yaml").expect("Should exist.");
+        serde_yaml::from_str(&content).expect("Should parse.")
+    }
+
+    fn paths(problems: Vec<Problem>) -> Vec<String> {
+        problems.into_iter().map(|p| p.path).collect()
+    }
+
+    #[test]
+    fn test_check_examples() {
+        for file in ["config/linux.yaml", "config/windows.yaml"] {
+            let merged = crate::layers::ConfigLoader::new()
+                .file(std::path::Path::new(file))
+                .merged()
+                .expect("Should load.");
+            let config: Config = serde_yaml::from_value(merged.clone()).expect("Should parse.");
+            assert_eq!(check(&config), vec![], "{}", file);
+            assert_eq!(check_unknown(&merged, &config), vec![], "{}", file);
+        }
+    }
+
+    #[test]
+    fn test_check_fields() {
+        let mut config = example();
+        config.rate = 0.0;
+        config.limiting_factor = f32::NAN;
+        config.vertical_depth = 600;
+        config.capture = vec![CaptureSpecification {
+            match_width: Some(1920),
+            match_height: Some(1080),
+            x: 1920,
+            y: 600,
+            ..Default::default()
+        }];
+        assert_eq!(
+            paths(check(&config)),
+            vec!["rate", "limiting_factor", "capture[0].x", "vertical_depth"]
+        );
+
+        let problems = Problems(check(&config));
+        assert!(problems
+            .to_string()
+            .starts_with("rate: must be larger than 0, got 0\n"));
+    }
+
+    #[test]
+    fn test_check_profiles() {
+        // Profiles only report the fields they set.
+        let mut config = example();
+        config.profile = Some("missing".to_owned());
+        config.profiles.insert(
+            "movie".to_owned(),
+            serde_yaml::from_str("{limiting_factor: 1.5, rate: 30}").unwrap(),
+        );
+        assert_eq!(
+            paths(check(&config)),
+            vec!["profile", "profiles.movie.limiting_factor"]
+        );
+    }
+
+    #[test]
+    fn test_check_outputs() {
+        // Outputs only report the fields they set, and they can't share a port.
+        let mut config = example();
+        config.outputs = serde_yaml::from_str(
+            "[{port: /dev/ttyACM1, vertical_depth: 0}, {port: /dev/ttyACM1}, {rate: 30}]",
+        )
+        .unwrap();
+        assert_eq!(
+            paths(check(&config)),
+            vec!["outputs[0].vertical_depth", "outputs[2]", "outputs[1].port"]
+        );
+    }
+
+    #[test]
+    fn test_check_segments() {
+        // Segments must cover the strip, each on its own port.
+        let mut config = example();
+        config.segments = serde_yaml::from_str(
+            "[{port: /dev/ttyACM1, leds: 100}, {port: /dev/ttyACM1, leds: 0}, {port: auto, leds: 100}]",
+        )
+        .unwrap();
+        assert_eq!(
+            paths(check(&config)),
+            vec!["segments[1].port", "segments[1].leds", "segments"]
+        );
+        config.segments[1].port = "/dev/ttyACM2".to_owned();
+        config.segments[1].leds = 28;
+        assert_eq!(check(&config), vec![]);
+
+        // The segments follow the number of leds, which can exceed one microcontroller.
+        config.leds = 300;
+        assert_eq!(paths(check(&config)), vec!["segments"]);
+        config.segments[1].leds = 100;
+        assert_eq!(check(&config), vec![]);
+        config.segments.clear();
+        assert_eq!(paths(check(&config)), vec!["leds"]);
+    }
+
+    #[test]
+    fn test_check_schedule() {
+        // Keyframes need known profiles, usable values and distinct times.
+        let mut config = example();
+        config.schedule = serde_yaml::from_str(
+            "keyframes: [{time: '08:00', limiting_factor: 1.5}, {time: '08:00', color_temperature: 500, profile: night}, {time: '20:00', profile: ~}]",
+        )
+        .unwrap();
+        assert_eq!(
+            paths(check(&config)),
+            vec![
+                "schedule.keyframes[0].limiting_factor",
+                "schedule.keyframes[1].color_temperature",
+                "schedule.keyframes[1].time",
+                "schedule.keyframes[1].profile"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_capture() {
+        // Fractional regions must fit and the ranges must be ordered.
+        let mut config = example();
+        config.capture = vec![CaptureSpecification {
+            min_width: Some(3000),
+            max_width: Some(2000),
+            x: 10,
+            region: Some(Region {
+                x: 0.5,
+                width: 0.75,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }];
+        assert_eq!(
+            paths(check(&config)),
+            vec![
+                "capture[0].min_width",
+                "capture[0].region",
+                "capture[0].region.width"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_unknown() {
+        // Misspelled fields are ignored while parsing, but reported by their path.
+        let path = std::env::temp_dir().join("validation_unknown.yaml");
+        std::fs::write(
+            &path,
+            "
+            leds_per_sid: 50
+            power_budget: {budget_ma: 2000, budgetma: 1000}
+            exit: {fade: 0.5}
+            capture: [{match_width: 1920, matchheight: 1080}, {region: {width: 0.5, heigth: 0.5}}]
+            segments: [{port: /dev/ttyACM0, leds: 114, led: 1}, {port: /dev/ttyACM1, leds: 114}]
+            profiles:
+              movie: {limiting_factor: 0.5, limting_factor: 0.3, exit: black}
+            outputs: [{port: /dev/ttyACM2}, {prot: /dev/ttyACM3}]
+            ",
+        )
+        .expect("Should write.");
+        let merged = crate::layers::ConfigLoader::new()
+            .file(&path)
+            .merged()
+            .expect("Should load.");
+        let config: Config = serde_yaml::from_value(merged.clone()).unwrap();
+        assert_eq!(
+            paths(check_unknown(&merged, &config)),
+            vec![
+                "power_budget.budgetma",
+                "capture[0].matchheight",
+                "capture[1].region.heigth",
+                "leds_per_sid",
+                "segments[0].led",
+                "profiles.movie.limting_factor",
+                "outputs[1].prot"
+            ]
+        );
+        assert_eq!(
+            check_unknown(&merged, &config)[0].to_string(),
+            "power_budget.budgetma: unknown field"
+        );
+    }
+}
@@ -3,90 +3,174 @@
 
 use crate::rectangle::Rectangle;
 
+/// The number of leds along each side of the region, the strip starts at the top left and runs
+/// down the left side, along the bottom, up the right side and back along the top.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Layout {
+    pub left: u32,
+    pub bottom: u32,
+    pub right: u32,
+    pub top: u32,
+}
+
+impl Layout {
+    /// The layout of the original strip of 228 leds, numbers from the C++ code.
+    pub const DEFAULT: Layout = Layout {
+        left: 42,
+        bottom: 72,
+        right: 42,
+        top: 72,
+    };
+
+    /// Divide the leds over the sides in the proportions of the [`Layout::DEFAULT`] strip, an odd
+    /// led goes to the top.
+    pub fn for_leds(leds: usize) -> Layout {
+        let default = Layout::DEFAULT;
+        let leds = leds as u32;
+        let vertical = ((leds as f64 * default.left as f64 / default.leds() as f64).round() as u32)
+            .min(leds / 2);
+        let horizontal = (leds - 2 * vertical) / 2;
+        Layout {
+            left: vertical,
+            bottom: horizontal,
+            right: vertical,
+            top: leds - 2 * vertical - horizontal,
+        }
+    }
+
+    /// The total number of leds.
+    pub fn leds(&self) -> usize {
+        (self.left + self.bottom + self.right + self.top) as usize
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::DEFAULT
+    }
+}
+
 pub struct Zones {}
 
 impl Zones {
-    // Numbers from the C++ code, they pertain to the physical led mapping.
-    const LEDS: u32 = 228;
-    const HORIZONTAL: u32 = 73;
-    const VERTICAL: u32 = 42;
-
-    /// Make the zones for the provided rectangle, horizontal depth and vertical depth.
+    /// Make the zones of the layout for the provided rectangle, horizontal depth and vertical
+    /// depth.
     pub fn make_zones(
         rectangle: &Rectangle,
+        layout: &Layout,
         horizontal_depth: u32,
         vertical_depth: u32,
     ) -> Vec<Rectangle> {
-        let mut res: Vec<Rectangle> = Vec::with_capacity(Zones::LEDS as usize);
+        let mut res: Vec<Rectangle> = Vec::with_capacity(layout.leds());
 
         let width = rectangle.x_max - rectangle.x_min;
         let height = rectangle.y_max - rectangle.y_min;
 
-        // Inclusive bounds:
-        // left side 0 - 41 (starts top)
-        // bottom side: 42 - 113 (starts left)
-        // right side: 114 - 155 (starts bottom)
-        // top side: 156 - 227 (starts right)
-        let vertical_step: u32 = height / Zones::VERTICAL;
-        let horizontal_step: u32 = width / Zones::HORIZONTAL;
-
-        // Ugly code follows, copied from the C++ side.
-        for led in 0..Zones::LEDS {
-            let (x_min, x_max, y_min, y_max);
-            if led < 42 {
-                let pos = led;
-                // left side.
-                x_min = rectangle.x_min;
-                x_max = rectangle.x_min + horizontal_depth;
-                y_min = rectangle.y_min + pos * vertical_step;
-                y_max = rectangle.y_min + (pos + 1) * vertical_step;
-                res.push(Rectangle {
-                    x_min,
-                    x_max,
-                    y_min,
-                    y_max,
-                });
-            } else if led < 114 {
-                // bottom
-                let pos = led - 42;
-                x_min = rectangle.x_min + pos * horizontal_step;
-                x_max = rectangle.x_min + (pos + 1) * horizontal_step;
-                y_min = rectangle.y_min + height - vertical_depth;
-                y_max = rectangle.y_min + height;
-                res.push(Rectangle {
-                    x_min,
-                    x_max,
-                    y_min,
-                    y_max,
-                });
-            } else if led < 156 {
-                // right side.
-                let pos = led - 114;
-                x_min = rectangle.x_min + width - horizontal_depth;
-                x_max = rectangle.x_min + width;
-                y_min = rectangle.y_min + height - (pos + 1) * vertical_step;
-                y_max = rectangle.y_min + height - (pos) * vertical_step;
-                res.push(Rectangle {
-                    x_min,
-                    x_max,
-                    y_min,
-                    y_max,
-                });
-            } else if led < Zones::LEDS + 1 {
-                // top side
-                let pos = led - 156;
-                x_min = rectangle.x_min + width - (pos + 1) * horizontal_step;
-                x_max = rectangle.x_min + width - (pos) * horizontal_step;
-                y_min = rectangle.y_min;
-                y_max = rectangle.y_min + vertical_depth;
-                res.push(Rectangle {
-                    x_min,
-                    x_max,
-                    y_min,
-                    y_max,
-                });
-            }
+        // The depths can't exceed the rectangle, like when the borders are small.
+        let horizontal_depth = horizontal_depth.min(width);
+        let vertical_depth = vertical_depth.min(height);
+
+        // Like the C++ code, the top and bottom are divided in one more step than they have leds.
+        let (x, y) = (rectangle.x_min, rectangle.y_min);
+
+        // Left side, starts top.
+        let step = height / layout.left.max(1);
+        for pos in 0..layout.left {
+            res.push(Rectangle {
+                x_min: x,
+                x_max: x + horizontal_depth,
+                y_min: y + pos * step,
+                y_max: y + (pos + 1) * step,
+            });
+        }
+
+        // Bottom, starts left.
+        let step = width / (layout.bottom + 1);
+        for pos in 0..layout.bottom {
+            res.push(Rectangle {
+                x_min: x + pos * step,
+                x_max: x + (pos + 1) * step,
+                y_min: y + height - vertical_depth,
+                y_max: y + height,
+            });
+        }
+
+        // Right side, starts bottom.
+        let step = height / layout.right.max(1);
+        for pos in 0..layout.right {
+            res.push(Rectangle {
+                x_min: x + width - horizontal_depth,
+                x_max: x + width,
+                y_min: y + height - (pos + 1) * step,
+                y_max: y + height - pos * step,
+            });
+        }
+
+        // Top side, starts right.
+        let step = width / (layout.top + 1);
+        for pos in 0..layout.top {
+            res.push(Rectangle {
+                x_min: x + width - (pos + 1) * step,
+                x_max: x + width - pos * step,
+                y_min: y,
+                y_max: y + vertical_depth,
+            });
         }
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_zones_small() {
+        // Borders smaller than the depths, the zones stay within the rectangle.
+        let rectangle = Rectangle {
+            x_min: 500,
+            y_min: 300,
+            x_max: 550,
+            y_max: 320,
+        };
+        let zones = Zones::make_zones(&rectangle, &Layout::DEFAULT, 200, 100);
+        assert_eq!(zones.len(), 228);
+        for zone in zones.iter() {
+            assert!(zone.x_min >= rectangle.x_min && zone.x_max <= rectangle.x_max);
+            assert!(zone.y_min >= rectangle.y_min && zone.y_max <= rectangle.y_max);
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(Layout::for_leds(228), Layout::DEFAULT);
+        for leds in [4, 5, 100, 151, 228, 300] {
+            assert_eq!(Layout::for_leds(leds).leds(), leds);
+        }
+        assert_eq!(
+            Layout::for_leds(151),
+            Layout {
+                left: 28,
+                bottom: 47,
+                right: 28,
+                top: 48
+            }
+        );
+
+        // The sides follow each other around the rectangle.
+        let rectangle = Rectangle {
+            x_min: 0,
+            y_min: 0,
+            x_max: 1000,
+            y_max: 500,
+        };
+        let zones = Zones::make_zones(&rectangle, &Layout::for_leds(151), 100, 50);
+        assert_eq!(zones.len(), 151);
+        assert_eq!(zones[0].y_min, 0);
+        assert_eq!(zones[27].x_min, 0);
+        assert_eq!(zones[28].y_max, 500);
+        assert_eq!(zones[75].x_max, 1000);
+        assert_eq!(zones[103].y_min, 0);
+        assert_eq!(zones[150].x_min, 1000 - 48 * (1000 / 49));
+    }
+}
@@ -1,38 +1,255 @@
 //! A module to control LED lights attached to a microcontroller.
 mod messages;
+mod power;
+mod segments;
+use log::{debug, info, trace, warn};
 use messages::{ColorData, Message, MsgType};
 
 use serialport::SerialPort;
 
 pub use messages::{Config, RGB};
+pub use power::PowerBudget;
+pub use segments::Segments;
+
+/// Function that opens the serial port, used to reconnect.
+pub type PortOpener = Box<dyn FnMut() -> Result<Box<dyn SerialPort>, Box<dyn Error>> + Send>;
+
+/// State of the connection to the microcontroller.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ConnectionState {
+    /// The port is open.
+    Connected,
+    /// The port is not open, the number of failed attempts to reopen it since the disconnect.
+    Disconnected { attempts: u32 },
+}
+
+/// Reopens the port with exponential backoff after a disconnect.
+struct Reconnect {
+    opener: PortOpener,
+    attempts: u32,
+    next_attempt: Instant,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
 
 /// Object to control led lights.
 pub struct Lights {
-    port: Box<dyn SerialPort>,
-    limit_factor: f32,
+    port: Option<Box<dyn SerialPort>>,
+    reconnect: Option<Reconnect>,
+    config: Config,
+    /// Whether a config was set, it is sent again after reconnecting.
+    config_set: bool,
+    power_budget: Option<PowerBudget>,
+    estimated_current: f32,
+
+    /// The pixels as last sent to the microcontroller, used to only send the chunks that changed.
+    sent: Vec<RGB>,
+    /// Interval at which all chunks are sent, regardless of whether they changed.
+    full_refresh_interval: Duration,
+    /// Time of the last full refresh.
+    last_full_refresh: Instant,
 }
 
 use std::error::Error;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 impl Lights {
-    /// Create a new Lights instance, attaching to the provided serial port.
-    pub fn new(port_name: &str) -> Result<Lights, Box<dyn Error>> {
+    /// The number of leds the microcontroller drives, used to estimate the current for [`Lights::fill`].
+    pub const MAX_LEDS: usize = 228;
+
+    /// The default interval at which all chunks are sent, see [`Lights::set_full_refresh_interval`].
+    pub const FULL_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// The default delay before the first reconnection attempt, doubled on each failed attempt.
+    pub const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+    /// The default maximum delay between reconnection attempts.
+    pub const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+    fn open_port(port_name: &str) -> Result<Box<dyn SerialPort>, Box<dyn Error>> {
         let port = serialport::new(port_name, 9600) // Baud rate is a dummy anyway.
             .timeout(Duration::from_millis(100))
             .open()
             .map_err(|ref e| format!("Port '{}' not available: {}", &port_name, e))?;
-        Ok(Lights {
+        Ok(port)
+    }
+
+    /// Create a new Lights instance, attaching to the provided serial port. Fails if the port is
+    /// not available, write errors are returned and don't reconnect.
+    pub fn new(port_name: &str) -> Result<Lights, Box<dyn Error>> {
+        Ok(Lights::from_port(Lights::open_port(port_name)?))
+    }
+
+    /// Create a new Lights instance that reconnects to the provided serial port, see
+    /// [`Lights::with_opener`]. This doesn't fail if the port is absent.
+    pub fn new_reconnecting(port_name: &str) -> Lights {
+        let port_name = port_name.to_owned();
+        Lights::with_opener(Box::new(move || Lights::open_port(&port_name)))
+    }
+
+    /// Create a new Lights instance that reconnects to the port matching the criteria, the port is
+    /// searched for again on each attempt, such that it is found if it got a different name.
+    pub fn new_reconnecting_matching(matcher: PortMatcher) -> Lights {
+        Lights::with_opener(Box::new(move || Lights::open_port(&find_port(&matcher)?)))
+    }
+
+    /// Create a new Lights instance that opens the port with the provided function. If writing
+    /// fails, like when the device is unplugged, the port is closed and reopened with exponential
+    /// backoff. Updates while disconnected are dropped and don't return errors, the write error
+    /// that caused the disconnect is returned. After reconnecting all leds are sent and the config
+    /// is set again.
+    pub fn with_opener(opener: PortOpener) -> Lights {
+        let mut lights = Lights::create(None);
+        lights.reconnect = Some(Reconnect {
+            opener,
+            attempts: 0,
+            next_attempt: Instant::now(),
+            initial_backoff: Lights::RECONNECT_INITIAL_BACKOFF,
+            max_backoff: Lights::RECONNECT_MAX_BACKOFF,
+        });
+        lights.ensure_connected();
+        lights
+    }
+
+    /// Create a new Lights instance using an already opened serial port.
+    pub fn from_port(port: Box<dyn SerialPort>) -> Lights {
+        Lights::create(Some(port))
+    }
+
+    fn create(port: Option<Box<dyn SerialPort>>) -> Lights {
+        Lights {
             port,
-            limit_factor: 1.0,
-        })
+            reconnect: None,
+            config: Default::default(),
+            config_set: false,
+            power_budget: None,
+            estimated_current: 0.0,
+            sent: vec![],
+            full_refresh_interval: Lights::FULL_REFRESH_INTERVAL,
+            last_full_refresh: Instant::now(),
+        }
+    }
+
+    /// The state of the connection.
+    pub fn connection_state(&self) -> ConnectionState {
+        match (self.port.as_ref(), self.reconnect.as_ref()) {
+            (Some(_), _) => ConnectionState::Connected,
+            (None, Some(reconnect)) => ConnectionState::Disconnected {
+                attempts: reconnect.attempts,
+            },
+            (None, None) => ConnectionState::Disconnected { attempts: 0 },
+        }
+    }
+
+    /// Set the backoff between reconnection attempts, the delay starts at `initial` and doubles
+    /// on each failed attempt up to `max`. Only used if created with [`Lights::with_opener`]. A
+    /// pending attempt is moved forward if it is further away than `initial`.
+    pub fn set_reconnect_backoff(&mut self, initial: Duration, max: Duration) {
+        if let Some(reconnect) = self.reconnect.as_mut() {
+            reconnect.initial_backoff = initial;
+            reconnect.max_backoff = max;
+            reconnect.next_attempt = reconnect.next_attempt.min(Instant::now() + initial);
+        }
+    }
+
+    /// Try to reopen the port if it is closed and an attempt is due, returns whether it is open.
+    fn ensure_connected(&mut self) -> bool {
+        if self.port.is_some() {
+            return true;
+        }
+        let reconnect = match self.reconnect.as_mut() {
+            Some(reconnect) => reconnect,
+            None => return false,
+        };
+        let now = Instant::now();
+        if now < reconnect.next_attempt {
+            return false;
+        }
+        match (reconnect.opener)() {
+            Ok(port) => {
+                info!(
+                    "Port connected after {} failed attempts",
+                    reconnect.attempts
+                );
+                reconnect.attempts = 0;
+                self.port = Some(port);
+            }
+            Err(e) => {
+                let backoff = reconnect
+                    .initial_backoff
+                    .saturating_mul(1u32 << reconnect.attempts.min(16))
+                    .min(reconnect.max_backoff);
+                reconnect.attempts += 1;
+                debug!(
+                    "Connecting attempt {} failed, retrying in {:?}: {}",
+                    reconnect.attempts, backoff, e
+                );
+                reconnect.next_attempt = now + backoff;
+                return false;
+            }
+        }
+
+        // Reconnected, the microcontroller may have lost its state.
+        self.sent.clear();
+        if self.config_set {
+            let config = self.config;
+            if self.set_config(&config).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Make the next [`Lights::set_leds`] send all leds and send the config again, if one was set.
+    /// Used when the microcontroller may have lost its state, like after the host was suspended.
+    pub fn resync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.sent.clear();
+        if self.config_set {
+            let config = self.config;
+            self.set_config(&config)?;
+        }
+        Ok(())
+    }
+
+    /// Write a message to the port, on failure the port is closed if it can be reopened.
+    fn write(&mut self, msg: &Message) -> Result<(), Box<dyn Error>> {
+        let port = self.port.as_mut().ok_or("Port is not connected")?;
+        if let Err(e) = port.write_all(&msg.as_bytes()) {
+            warn!("Writing to port failed: {}", e);
+            if let Some(reconnect) = self.reconnect.as_mut() {
+                self.port = None;
+                reconnect.attempts = 0;
+                reconnect.next_attempt = Instant::now();
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Set the interval at which [`Lights::set_leds`] sends all chunks instead of only the ones
+    /// that changed. This guards against lost state on the microcontroller, a zero interval always
+    /// sends all chunks.
+    pub fn set_full_refresh_interval(&mut self, interval: Duration) {
+        self.full_refresh_interval = interval;
     }
 
-    /// Set the brightness limiting factor (0.0 - 1.0), this reduces the overall brightness for any
-    /// values set through [`Lights::fill`] or [`Lights::set_leds`]. This can be helpful if the
-    /// power supply is inadequate.
-    pub fn set_limit_factor(&mut self, factor: f32) {
-        self.limit_factor = factor;
+    /// Set the power budget, any values set through [`Lights::fill`] or [`Lights::set_leds`] that
+    /// would exceed this budget are scaled down. Values within the budget are sent as is. This can
+    /// be helpful if the power supply is inadequate. `None` disables the limiting.
+    pub fn set_power_budget(&mut self, budget: Option<PowerBudget>) {
+        self.power_budget = budget;
+    }
+
+    /// The estimated current in mA of the last frame before limiting, zero without a power budget.
+    pub fn estimated_current(&self) -> f32 {
+        self.estimated_current
+    }
+
+    /// Scale the pixels down to the power budget if one is set.
+    fn limit(&mut self, pixels: &mut [RGB]) {
+        if let Some(budget) = self.power_budget.as_ref() {
+            self.estimated_current = budget.limit(pixels, &self.config);
+        }
     }
 
     /// Set the configuration on the microcontroller to the provided struct.
@@ -42,9 +259,16 @@ impl Lights {
             ..Default::default()
         };
         msg.payload.config = *config;
+        debug!("Setting config {:?}", config);
 
-        self.port.write_all(&msg.as_bytes())?;
-        Ok(())
+        // Keep the config, the gamma values are needed to estimate the current and it is sent
+        // again after reconnecting.
+        self.config = *config;
+        self.config_set = true;
+        if !self.ensure_connected() {
+            return Ok(());
+        }
+        self.write(&msg)
     }
 
     /// Fill the entire string of leds with the provided color.
@@ -55,43 +279,112 @@ impl Lights {
         };
         msg.payload.color.offset = 0;
         msg.payload.color.settings = ColorData::SETTINGS_SET_ALL | ColorData::SETTINGS_SHOW_AFTER;
+        // Limit as if all leds are set to this color, that is what the microcontroller will do.
+        let mut all = [RGB { r, g, b }; Lights::MAX_LEDS];
+        self.limit(&mut all);
         let mut colors: [RGB; ColorData::LEDS_PER_MESSAGE] = Default::default();
-        colors[0].r = (r as f32 * self.limit_factor) as u8;
-        colors[0].g = (g as f32 * self.limit_factor) as u8;
-        colors[0].b = (b as f32 * self.limit_factor) as u8;
+        colors[0] = all[0];
         msg.payload.color.color = colors;
 
-        self.port.write_all(&msg.as_bytes())?;
-        Ok(())
+        // All leds on the microcontroller change, the next update must send all chunks.
+        self.sent.clear();
+        if !self.ensure_connected() {
+            return Ok(());
+        }
+        self.write(&msg)
     }
 
     /// Set the leds to the provided pixel values.
+    ///
+    /// Only the chunks of leds (one message each) that changed since the previous call are sent,
+    /// the last chunk sent has the show flag set. If nothing changed, the final chunk is still sent
+    /// to show and keep the microcontroller from decaying the leds. All chunks are sent
+    /// periodically, see [`Lights::set_full_refresh_interval`].
     pub fn set_leds(&mut self, pixels: &[RGB]) -> Result<(), Box<dyn Error>> {
-        // chunk the pixels into LEDS_PER_MESSAGE.
-        let chunk_count =
-            (pixels.len() as f32 / ColorData::LEDS_PER_MESSAGE as f32).ceil() as usize;
-        for (i, chunk) in pixels.chunks(ColorData::LEDS_PER_MESSAGE).enumerate() {
-            let is_final = i + 1 == chunk_count;
-            let mut msg: Message = Message {
-                msg_type: MsgType::COLOR,
-                ..Default::default()
-            };
-            msg.payload.color.offset = (i * ColorData::LEDS_PER_MESSAGE) as u16;
-            // Only if it is the last chunk, write the data.
-            msg.payload.color.settings = if is_final {
-                ColorData::SETTINGS_SHOW_AFTER
-            } else {
-                0
-            };
-            let mut colors: [RGB; ColorData::LEDS_PER_MESSAGE] = Default::default();
-            for c in 0..std::cmp::min(pixels.len(), ColorData::LEDS_PER_MESSAGE) {
-                colors[c].r = (chunk[c].r as f32 * self.limit_factor) as u8;
-                colors[c].g = (chunk[c].g as f32 * self.limit_factor) as u8;
-                colors[c].b = (chunk[c].b as f32 * self.limit_factor) as u8;
-            }
-            msg.payload.color.color = colors;
-            self.port.write_all(&msg.as_bytes())?;
+        self.send_leds(pixels, true)
+    }
+
+    /// Send the leds like [`Lights::set_leds`], but don't show them until [`Lights::show`] is
+    /// called. If nothing changed nothing is sent. This allows multiple controllers to show in the
+    /// same frame, see [`Segments`].
+    pub fn stage_leds(&mut self, pixels: &[RGB]) -> Result<(), Box<dyn Error>> {
+        self.send_leds(pixels, false)
+    }
+
+    /// Show the leds sent with [`Lights::stage_leds`], this sends the final chunk again.
+    pub fn show(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.sent.is_empty() || !self.ensure_connected() {
+            return Ok(());
         }
+        let last = (self.sent.len() - 1) / ColorData::LEDS_PER_MESSAGE;
+        let msg = Lights::color_message(&self.sent, last, true);
+        self.write(&msg)
+    }
+
+    /// The message holding the chunk of the pixels at this index.
+    fn color_message(pixels: &[RGB], index: usize, show: bool) -> Message {
+        let start = index * ColorData::LEDS_PER_MESSAGE;
+        let end = std::cmp::min(start + ColorData::LEDS_PER_MESSAGE, pixels.len());
+        let mut msg: Message = Message {
+            msg_type: MsgType::COLOR,
+            ..Default::default()
+        };
+        msg.payload.color.offset = start as u16;
+        msg.payload.color.settings = if show {
+            ColorData::SETTINGS_SHOW_AFTER
+        } else {
+            0
+        };
+        let mut colors: [RGB; ColorData::LEDS_PER_MESSAGE] = Default::default();
+        colors[..end - start].copy_from_slice(&pixels[start..end]);
+        msg.payload.color.color = colors;
+        msg
+    }
+
+    fn send_leds(&mut self, pixels: &[RGB], show: bool) -> Result<(), Box<dyn Error>> {
+        let mut pixels = pixels.to_vec();
+        self.limit(&mut pixels);
+        if pixels.is_empty() || !self.ensure_connected() {
+            return Ok(());
+        }
+
+        let full_refresh = self.sent.len() != pixels.len()
+            || self.last_full_refresh.elapsed() >= self.full_refresh_interval;
+        if full_refresh {
+            self.last_full_refresh = Instant::now();
+        }
+
+        // Determine which chunks to send.
+        let mut to_send: Vec<usize> = pixels
+            .chunks(ColorData::LEDS_PER_MESSAGE)
+            .enumerate()
+            .filter(|(i, chunk)| {
+                let start = i * ColorData::LEDS_PER_MESSAGE;
+                full_refresh || self.sent[start..start + chunk.len()] != **chunk
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if to_send.is_empty() && show {
+            let chunk_count =
+                (pixels.len() as f32 / ColorData::LEDS_PER_MESSAGE as f32).ceil() as usize;
+            to_send.push(chunk_count - 1);
+        }
+
+        trace!(
+            "Sending {} chunks, full refresh: {}",
+            to_send.len(),
+            full_refresh
+        );
+
+        // Forget what was sent, if writing fails halfway we don't know the state.
+        self.sent.clear();
+        for (n, i) in to_send.iter().enumerate() {
+            // Only if it is the last chunk, show the data.
+            let is_final = n + 1 == to_send.len();
+            let msg = Lights::color_message(&pixels, *i, show && is_final);
+            self.write(&msg)?;
+        }
+        self.sent = pixels;
         Ok(())
     }
 }
@@ -100,3 +393,292 @@ impl Lights {
 pub fn available_ports() -> Result<Vec<serialport::SerialPortInfo>, serialport::Error> {
     serialport::available_ports()
 }
+
+/// Criteria to find the serial port of the microcontroller among the USB serial ports, unset
+/// fields match anything.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PortMatcher {
+    /// The USB vendor id.
+    pub vid: Option<u16>,
+    /// The USB product id.
+    pub pid: Option<u16>,
+    /// The USB serial number, must be equal.
+    pub serial_number: Option<String>,
+    /// The USB product string, must be contained in the device's product string.
+    pub product: Option<String>,
+}
+
+impl Default for PortMatcher {
+    /// Matches the vendor and product id the firmware enumerates with.
+    fn default() -> Self {
+        PortMatcher {
+            vid: Some(PortMatcher::FIRMWARE_VID),
+            pid: Some(PortMatcher::FIRMWARE_PID),
+            serial_number: None,
+            product: None,
+        }
+    }
+}
+
+impl PortMatcher {
+    /// The USB vendor id of the firmware.
+    pub const FIRMWARE_VID: u16 = 0x16c0;
+    /// The USB product id of the firmware.
+    pub const FIRMWARE_PID: u16 = 0x27dd;
+
+    /// Whether the port matches all criteria, only USB ports can match.
+    pub fn matches(&self, port: &serialport::SerialPortInfo) -> bool {
+        let usb = match &port.port_type {
+            serialport::SerialPortType::UsbPort(usb) => usb,
+            _ => return false,
+        };
+        self.vid.map(|v| v == usb.vid).unwrap_or(true)
+            && self.pid.map(|v| v == usb.pid).unwrap_or(true)
+            && self
+                .serial_number
+                .as_ref()
+                .map(|v| usb.serial_number.as_ref() == Some(v))
+                .unwrap_or(true)
+            && self
+                .product
+                .as_ref()
+                .map(|v| usb.product.as_ref().map(|p| p.contains(v.as_str())) == Some(true))
+                .unwrap_or(true)
+    }
+
+    /// Select the matching port from the provided ports. If multiple ports match, the one with the
+    /// lowest name is chosen, such that the choice doesn't depend on the enumeration order.
+    pub fn select(&self, ports: &[serialport::SerialPortInfo]) -> Option<String> {
+        ports
+            .iter()
+            .filter(|p| self.matches(p))
+            .map(|p| p.port_name.clone())
+            .min()
+    }
+}
+
+/// Find the name of the port matching the criteria among the [`available_ports`], see
+/// [`PortMatcher::select`].
+pub fn find_port(matcher: &PortMatcher) -> Result<String, Box<dyn Error>> {
+    let ports = available_ports()?;
+    let candidates = ports.iter().filter(|p| matcher.matches(p)).count();
+    let name = matcher
+        .select(&ports)
+        .ok_or_else(|| format!("No port matches {:?}", matcher))?;
+    if candidates > 1 {
+        info!(
+            "{} ports match, using the first by name: {}",
+            candidates, name
+        );
+    } else {
+        debug!("Found port {}", name);
+    }
+    Ok(name)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use messages::ReceivedMessage;
+    use serialport::TTYPort;
+    use std::io::Read;
+
+    /// Read all the color messages currently available, returning their offset and settings.
+    fn read_colors(port: &mut TTYPort) -> Vec<(u16, u8)> {
+        let mut res = vec![];
+        let mut buffer = [0u8; Message::LENGTH];
+        while port.bytes_to_read().expect("Should succeed.") as usize >= Message::LENGTH {
+            port.read_exact(&mut buffer).expect("Should succeed.");
+            if let Some(ReceivedMessage::ColorData(c)) = Message::from_bytes(&buffer) {
+                res.push((c.offset, c.settings));
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_changed_chunks() {
+        let (mut device, host) = TTYPort::pair().expect("Should be able to make a pty pair.");
+        let mut lights = Lights::from_port(Box::new(host));
+        lights.set_full_refresh_interval(Duration::from_secs(3600));
+        let show = ColorData::SETTINGS_SHOW_AFTER;
+
+        // First update sends everything.
+        let mut leds = [RGB::default(); 40];
+        lights.set_leds(&leds).expect("Should succeed.");
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_colors(&mut device), vec![(0, 0), (19, 0), (38, show)]);
+
+        // Nothing changed, only the final chunk is sent to show.
+        lights.set_leds(&leds).expect("Should succeed.");
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_colors(&mut device), vec![(38, show)]);
+
+        // Change the first chunk, only that is sent and it is shown.
+        leds[3].r = 10;
+        lights.set_leds(&leds).expect("Should succeed.");
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_colors(&mut device), vec![(0, show)]);
+
+        // After a resync everything is sent again.
+        lights.resync().expect("Should succeed.");
+        lights.set_leds(&leds).expect("Should succeed.");
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_colors(&mut device), vec![(0, 0), (19, 0), (38, show)]);
+
+        // A zero refresh interval always sends everything.
+        lights.set_full_refresh_interval(Duration::ZERO);
+        lights.set_leds(&leds).expect("Should succeed.");
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_colors(&mut device), vec![(0, 0), (19, 0), (38, show)]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let (mut first_device, first_host) = TTYPort::pair().expect("Should make a pty pair.");
+        let (mut second_device, second_host) = TTYPort::pair().expect("Should make a pty pair.");
+        let mut segments = Segments::new(vec![
+            (Lights::from_port(Box::new(first_host)), 20),
+            (Lights::from_port(Box::new(second_host)), 30),
+        ]);
+        segments.set_full_refresh_interval(Duration::from_secs(3600));
+        let show = ColorData::SETTINGS_SHOW_AFTER;
+
+        // Each controller gets its segment, the shows are sent after all segments.
+        let mut leds = [RGB::default(); 50];
+        segments.set_leds(&leds).expect("Should succeed.");
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            read_colors(&mut first_device),
+            vec![(0, 0), (19, 0), (19, show)]
+        );
+        assert_eq!(
+            read_colors(&mut second_device),
+            vec![(0, 0), (19, 0), (19, show)]
+        );
+
+        // Only the changed chunk of the second segment is sent, both still show.
+        leds[45].g = 10;
+        segments.set_leds(&leds).expect("Should succeed.");
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_colors(&mut first_device), vec![(19, show)]);
+        assert_eq!(read_colors(&mut second_device), vec![(19, 0), (19, show)]);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        use std::sync::{Arc, Mutex};
+        // The opener hands out the host side of the pty pair in the slot, if any.
+        let slot: Arc<Mutex<Option<TTYPort>>> = Default::default();
+        let opener_slot = slot.clone();
+        let mut lights = Lights::with_opener(Box::new(move || {
+            let port = opener_slot.lock().unwrap().take().ok_or("Absent")?;
+            Ok(Box::new(port) as Box<dyn SerialPort>)
+        }));
+        lights.set_reconnect_backoff(Duration::ZERO, Duration::ZERO);
+        let show = ColorData::SETTINGS_SHOW_AFTER;
+        let leds = [RGB::default(); 20];
+
+        // Port absent at startup, updates are dropped.
+        assert_eq!(
+            lights.connection_state(),
+            ConnectionState::Disconnected { attempts: 1 }
+        );
+        lights.set_leds(&leds).expect("Should succeed.");
+        assert_eq!(
+            lights.connection_state(),
+            ConnectionState::Disconnected { attempts: 2 }
+        );
+
+        // Device appears, frames flow.
+        let (mut device, host) = TTYPort::pair().expect("Should be able to make a pty pair.");
+        *slot.lock().unwrap() = Some(host);
+        lights.set_leds(&leds).expect("Should succeed.");
+        assert_eq!(lights.connection_state(), ConnectionState::Connected);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_colors(&mut device), vec![(0, 0), (19, show)]);
+
+        // Device disappears, the write error is returned once, then updates are dropped.
+        drop(device);
+        assert!(lights.set_leds(&leds).is_err());
+        assert_eq!(
+            lights.connection_state(),
+            ConnectionState::Disconnected { attempts: 0 }
+        );
+        lights.set_leds(&leds).expect("Should succeed.");
+
+        // Device comes back, everything is sent again.
+        let (mut device, host) = TTYPort::pair().expect("Should be able to make a pty pair.");
+        *slot.lock().unwrap() = Some(host);
+        lights.set_leds(&leds).expect("Should succeed.");
+        assert_eq!(lights.connection_state(), ConnectionState::Connected);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_colors(&mut device), vec![(0, 0), (19, show)]);
+    }
+
+    #[test]
+    fn test_port_matcher() {
+        use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+        let usb = |name: &str, vid: u16, serial: &str| SerialPortInfo {
+            port_name: name.to_owned(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid: PortMatcher::FIRMWARE_PID,
+                serial_number: Some(serial.to_owned()),
+                manufacturer: None,
+                product: Some("Serial port".to_owned()),
+            }),
+        };
+        let ports = vec![
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_owned(),
+                port_type: SerialPortType::PciPort,
+            },
+            usb("/dev/ttyACM2", PortMatcher::FIRMWARE_VID, "B"),
+            usb("/dev/ttyACM0", 0x1234, "C"),
+            usb("/dev/ttyACM1", PortMatcher::FIRMWARE_VID, "A"),
+        ];
+
+        // Multiple matches, the lowest name wins regardless of order.
+        let matcher = PortMatcher::default();
+        assert_eq!(matcher.select(&ports), Some("/dev/ttyACM1".to_owned()));
+        let reversed: Vec<_> = ports.iter().cloned().rev().collect();
+        assert_eq!(matcher.select(&reversed), Some("/dev/ttyACM1".to_owned()));
+
+        let matcher = PortMatcher {
+            serial_number: Some("B".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(matcher.select(&ports), Some("/dev/ttyACM2".to_owned()));
+
+        let matcher = PortMatcher {
+            vid: None,
+            pid: None,
+            product: Some("Serial".to_owned()),
+            serial_number: Some("C".to_owned()),
+        };
+        assert_eq!(matcher.select(&ports), Some("/dev/ttyACM0".to_owned()));
+
+        let matcher = PortMatcher {
+            product: Some("Other".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(matcher.select(&ports), None);
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let mut lights = Lights::with_opener(Box::new(|| Err("Absent".into())));
+        lights.set_reconnect_backoff(Duration::from_secs(3600), Duration::from_secs(3600));
+        assert_eq!(
+            lights.connection_state(),
+            ConnectionState::Disconnected { attempts: 1 }
+        );
+        // The next attempt is not due yet.
+        lights.fill(0, 0, 0).expect("Should succeed.");
+        assert_eq!(
+            lights.connection_state(),
+            ConnectionState::Disconnected { attempts: 1 }
+        );
+    }
+}
@@ -9,10 +9,13 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("Ports: {:#?}", lights::available_ports()?);
         return Ok(());
     }
-    let port = port.unwrap();
+    let mut port = port.unwrap();
+    if port == "auto" {
+        port = lights::find_port(&Default::default())?;
+        println!("Using port {port}");
+    }
 
     let mut control = lights::Lights::new(&port)?;
-    control.set_limit_factor(1.0);
     control.fill(0, 0, 0)?;
 
     let config = lights::Config {
@@ -249,6 +249,12 @@ impl Message {
 const _: () = [(); 1][(core::mem::size_of::<Message>() == Message::LENGTH) as usize ^ 1];
 
 #[cfg(test)]
+#[allow(
+    clippy::field_reassign_with_default,
+    clippy::assertions_on_constants,
+    clippy::needless_range_loop,
+    clippy::identity_op
+)]
 mod tests {
     use super::*;
 
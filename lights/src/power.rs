@@ -0,0 +1,147 @@
+//! Estimation of the current drawn by the led string and limiting it to a power budget.
+use crate::messages::{Config, RGB};
+
+/// Power budget for the led string, frames that exceed it are scaled down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerBudget {
+    /// Current in mA drawn by a single channel (r, g or b) at full intensity.
+    pub ma_per_channel: f32,
+
+    /// Current in mA drawn by each led regardless of its color.
+    pub idle_ma_per_led: f32,
+
+    /// The current in mA the power supply can deliver to the led string.
+    pub budget_ma: f32,
+}
+
+impl Default for PowerBudget {
+    /// Typical values for ws2811 leds on a 5V 4A power supply.
+    fn default() -> Self {
+        PowerBudget {
+            ma_per_channel: 20.0,
+            idle_ma_per_led: 1.0,
+            budget_ma: 4000.0,
+        }
+    }
+}
+
+/// The microcontroller applies gamma correction, so the duty cycle of a channel, and thus the
+/// current, is the gamma corrected value.
+fn duty(value: u8, gamma: f32) -> f32 {
+    (value as f32 / 255.0).powf(gamma)
+}
+
+/// Ensure that a gamma value from the config is usable.
+fn sane_gamma(gamma: f32) -> f32 {
+    if gamma.is_finite() && gamma > 0.0 {
+        gamma
+    } else {
+        1.0
+    }
+}
+
+/// Scale the gamma corrected duty cycle of the pixels, such that their current is multiplied by
+/// the scale, each channel value is multiplied by `scale^(1/gamma)`.
+pub(crate) fn scale(pixels: &mut [RGB], config: &Config, scale: f32) {
+    let factor_r = scale.powf(1.0 / sane_gamma(config.gamma_r));
+    let factor_g = scale.powf(1.0 / sane_gamma(config.gamma_g));
+    let factor_b = scale.powf(1.0 / sane_gamma(config.gamma_b));
+    for p in pixels.iter_mut() {
+        p.r = (p.r as f32 * factor_r) as u8;
+        p.g = (p.g as f32 * factor_g) as u8;
+        p.b = (p.b as f32 * factor_b) as u8;
+    }
+}
+
+impl PowerBudget {
+    /// Estimate the current in mA drawn by the channels only, excluding the idle current.
+    pub(crate) fn channel_current(&self, pixels: &[RGB], config: &Config) -> f32 {
+        let (gamma_r, gamma_g, gamma_b) = (
+            sane_gamma(config.gamma_r),
+            sane_gamma(config.gamma_g),
+            sane_gamma(config.gamma_b),
+        );
+        let total_duty: f32 = pixels
+            .iter()
+            .map(|p| duty(p.r, gamma_r) + duty(p.g, gamma_g) + duty(p.b, gamma_b))
+            .sum();
+        total_duty * self.ma_per_channel
+    }
+
+    /// Estimate the total current in mA drawn by the led string when showing these pixels.
+    pub fn estimate_current(&self, pixels: &[RGB], config: &Config) -> f32 {
+        self.channel_current(pixels, config) + self.idle_ma_per_led * pixels.len() as f32
+    }
+
+    /// Scale the pixels down such that the current stays within the budget. Pixels are untouched
+    /// if the estimated current is already within the budget. Returns the estimated current before
+    /// any scaling was applied.
+    ///
+    /// Scaling is done on the gamma corrected duty cycle, each channel value is multiplied by
+    /// `scale^(1/gamma)`. This reduces the current by `scale` while keeping the hue intact.
+    pub fn limit(&self, pixels: &mut [RGB], config: &Config) -> f32 {
+        let channel_current = self.channel_current(pixels, config);
+        let idle_current = self.idle_ma_per_led * pixels.len() as f32;
+        let estimate = channel_current + idle_current;
+        if estimate <= self.budget_ma || channel_current <= 0.0 {
+            return estimate;
+        }
+
+        let factor = ((self.budget_ma - idle_current) / channel_current).clamp(0.0, 1.0);
+        scale(pixels, config, factor);
+        estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: RGB = RGB {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+
+    #[test]
+    fn test_estimate() {
+        let budget = PowerBudget {
+            ma_per_channel: 20.0,
+            idle_ma_per_led: 1.0,
+            budget_ma: 1000.0,
+        };
+        let config = Config::default();
+        let leds = [WHITE; 10];
+        assert_eq!(budget.estimate_current(&leds, &config), 10.0 * 61.0);
+        let leds = [RGB::default(); 10];
+        assert_eq!(budget.estimate_current(&leds, &config), 10.0);
+    }
+
+    #[test]
+    fn test_limit() {
+        let budget = PowerBudget {
+            ma_per_channel: 20.0,
+            idle_ma_per_led: 1.0,
+            budget_ma: 1000.0,
+        };
+        let config = Config::default();
+
+        // Dim scenes are not touched.
+        let dim = RGB {
+            r: 50,
+            g: 40,
+            b: 30,
+        };
+        let mut leds = [dim; 100];
+        budget.limit(&mut leds, &config);
+        assert!(leds.iter().all(|p| *p == dim));
+
+        // Full white is scaled down to fit in the budget.
+        let mut leds = [WHITE; 100];
+        let before = budget.limit(&mut leds, &config);
+        assert_eq!(before, 100.0 * 61.0);
+        let after = budget.estimate_current(&leds, &config);
+        assert!(after <= budget.budget_ma);
+        assert!(after > budget.budget_ma * 0.95);
+    }
+}
@@ -0,0 +1,178 @@
+//! One logical strip split over multiple controllers, each driving a contiguous segment of it.
+use crate::{Config, Lights, PowerBudget, RGB};
+
+use std::error::Error;
+use std::ops::Range;
+use std::time::Duration;
+
+/// Drives a strip whose leds are split over multiple controllers, in order. The leds of all
+/// segments are sent before any of them is shown, such that the segments update in the same frame.
+pub struct Segments {
+    segments: Vec<(Lights, usize)>,
+    power_budget: Option<PowerBudget>,
+}
+
+impl Segments {
+    /// Create the strip from the controllers and the number of leds each drives, in the order of
+    /// the strip.
+    pub fn new(segments: Vec<(Lights, usize)>) -> Segments {
+        Segments {
+            segments,
+            power_budget: None,
+        }
+    }
+
+    /// The controllers and the number of leds each drives.
+    pub fn segments_mut(&mut self) -> impl Iterator<Item = (&mut Lights, usize)> {
+        self.segments.iter_mut().map(|(l, n)| (l, *n))
+    }
+
+    /// Set the leds of the strip, each controller gets its segment. A failing controller doesn't
+    /// stop the others, the first error is returned.
+    pub fn set_leds(&mut self, pixels: &[RGB]) -> Result<(), Box<dyn Error>> {
+        let mut limited;
+        let mut pixels = pixels;
+        if self.power_budget.is_some() {
+            limited = pixels.to_vec();
+            self.limit(&mut limited);
+            pixels = &limited;
+        }
+        if let [(lights, _)] = self.segments.as_mut_slice() {
+            return lights.set_leds(pixels);
+        }
+        let mut res = Ok(());
+        let mut start = 0;
+        for (lights, count) in self.segments.iter_mut() {
+            let end = (start + *count).min(pixels.len());
+            let staged = lights.stage_leds(&pixels[start.min(end)..end]);
+            if res.is_ok() {
+                res = staged;
+            }
+            start = end;
+        }
+        for (lights, _) in self.segments.iter_mut() {
+            let shown = lights.show();
+            if res.is_ok() {
+                res = shown;
+            }
+        }
+        res
+    }
+
+    /// Resynchronise all controllers, see [`Lights::resync`].
+    pub fn resync(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut res = Ok(());
+        for (lights, _) in self.segments.iter_mut() {
+            let synced = lights.resync();
+            if res.is_ok() {
+                res = synced;
+            }
+        }
+        res
+    }
+
+    /// Set the config of each controller, see [`Lights::set_config`]. A failing controller doesn't
+    /// stop the others, the first error is returned.
+    pub fn set_config(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        let mut res = Ok(());
+        for (lights, _) in self.segments.iter_mut() {
+            let set = lights.set_config(config);
+            if res.is_ok() {
+                res = set;
+            }
+        }
+        res
+    }
+
+    /// Set the power budget of the strip, see [`Lights::set_power_budget`]. The strip shares the
+    /// power supply, so the current is estimated over all segments and if it exceeds the budget
+    /// all of them are scaled down by the same factor.
+    pub fn set_power_budget(&mut self, budget: Option<PowerBudget>) {
+        self.power_budget = budget;
+        for (lights, _) in self.segments.iter_mut() {
+            lights.set_power_budget(None);
+        }
+    }
+
+    /// Scale the pixels down to the power budget if one is set, each segment with the gamma of its
+    /// controller.
+    fn limit(&self, pixels: &mut [RGB]) {
+        let Some(budget) = self.power_budget.as_ref() else {
+            return;
+        };
+        let channel_current: f32 = self
+            .ranges(pixels.len())
+            .map(|(lights, range)| budget.channel_current(&pixels[range], &lights.config))
+            .sum();
+        let idle_current = budget.idle_ma_per_led * pixels.len() as f32;
+        if channel_current + idle_current <= budget.budget_ma || channel_current <= 0.0 {
+            return;
+        }
+        let factor = ((budget.budget_ma - idle_current) / channel_current).clamp(0.0, 1.0);
+        for (lights, range) in self.ranges(pixels.len()) {
+            crate::power::scale(&mut pixels[range], &lights.config, factor);
+        }
+    }
+
+    /// The controllers and the range of the pixels each drives.
+    fn ranges(&self, len: usize) -> impl Iterator<Item = (&Lights, Range<usize>)> {
+        let mut start = 0;
+        self.segments.iter().map(move |(lights, count)| {
+            let end = (start + count).min(len);
+            let range = start.min(end)..end;
+            start = end;
+            (lights, range)
+        })
+    }
+
+    /// Set the full refresh interval of each controller, see [`Lights::set_full_refresh_interval`].
+    pub fn set_full_refresh_interval(&mut self, interval: Duration) {
+        for (lights, _) in self.segments.iter_mut() {
+            lights.set_full_refresh_interval(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_budget() {
+        const WHITE: RGB = RGB {
+            r: 255,
+            g: 255,
+            b: 255,
+        };
+        let mut segments = Segments::new(vec![
+            (Lights::new_reconnecting("/nonexistent/first"), 100),
+            (Lights::new_reconnecting("/nonexistent/second"), 100),
+        ]);
+        let budget = PowerBudget {
+            ma_per_channel: 20.0,
+            idle_ma_per_led: 1.0,
+            budget_ma: 6500.0,
+        };
+        segments.set_power_budget(Some(budget));
+
+        // One bright segment is within the budget of the whole strip, it isn't dimmed.
+        let mut pixels = vec![WHITE; 100];
+        pixels.extend([RGB::default(); 100]);
+        let original = pixels.clone();
+        segments.limit(&mut pixels);
+        assert_eq!(pixels, original);
+
+        // Over the budget, both segments are scaled down by the same factor.
+        let mut pixels = vec![WHITE; 200];
+        segments.limit(&mut pixels);
+        assert_eq!(pixels[0], pixels[199]);
+        assert!(pixels[0].r < 255);
+        let config = Config::default();
+        assert!(budget.estimate_current(&pixels, &config) <= budget.budget_ma);
+
+        segments.set_power_budget(None);
+        let mut pixels = vec![WHITE; 200];
+        segments.limit(&mut pixels);
+        assert!(pixels.iter().all(|p| *p == WHITE));
+    }
+}